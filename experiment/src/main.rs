@@ -1,5 +1,3 @@
-#![feature(hash_set_entry)]
-#![feature(dir_entry_ext2)]
 use std::fs::{read_dir, Metadata};
 use std::os::unix::fs::MetadataExt;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
@@ -9,7 +7,6 @@ use std::sync::Arc;
 use std::io;
 use std::borrow::Borrow;
 use std::ops::Deref;
-use std::os::unix::fs::DirEntryExt2;
 
 type ObjectList = Vec<Arc<ObjectPath>>;
 
@@ -46,7 +43,7 @@ impl Inventory {
                 Ok(entry) => {
                     let metadata = entry.metadata()?;
                     if metadata.is_dir() {
-                        let dirname = self.cache_name(entry.file_name_ref());
+                        let dirname = self.cache_name(&entry.file_name());
                         path.push(&dirname);
                         self.load_dir_recursive_intern(
                             ObjectPath::subobject(dir.clone(), dirname),
@@ -55,7 +52,7 @@ impl Inventory {
                         path.pop();
                     } else {
                         if metadata.blocks() > 64 {
-                            let name = self.cache_name(entry.file_name_ref());
+                            let name = self.cache_name(&entry.file_name());
                             self.entries
                                 .entry(metadata.dev())
                                 .or_default()
@@ -79,13 +76,20 @@ impl Inventory {
     }
 
     pub fn cache_name(&mut self, name: &OsStr) -> CachedName {
-        self.cached_names
-            .get_or_insert_with(name, |name| CachedName(Arc::new(OsString::from(name))))
-            .clone()
+        match self.cached_names.get(name) {
+            Some(cached) => cached.clone(),
+            None => {
+                let cached = CachedName(Arc::new(OsString::from(name)));
+                self.cached_names.insert(cached.clone());
+                cached
+            }
+        }
     }
 
-    pub fn garbage_collect() {
-        /* PLANNED: remove all entries with refcount == 1 (drain_filter)*/
+    /// Removes all cached names which are not used by any ObjectPath anymore, these have
+    /// only the reference from the cache itself left.
+    pub fn garbage_collect(&mut self) {
+        self.cached_names.retain(|name| Arc::strong_count(&name.0) > 1);
     }
 }
 
@@ -215,6 +219,23 @@ fn directory_path_subobject() {
     );
 }
 
+#[test]
+fn cached_names_garbage_collect() {
+    let mut inventory = Inventory::new();
+    let root = Arc::new(ObjectPath::new("."));
+    let foo = ObjectPath::subobject(root.clone(), inventory.cache_name(OsStr::new("foo")));
+    let bar = ObjectPath::subobject(root, inventory.cache_name(OsStr::new("bar")));
+    assert_eq!(inventory.cached_names.len(), 2);
+
+    drop(foo);
+    inventory.garbage_collect();
+    assert_eq!(inventory.cached_names.len(), 1);
+
+    drop(bar);
+    inventory.garbage_collect();
+    assert!(inventory.cached_names.is_empty());
+}
+
 fn main() {
     eprintln!("Hello, world!");
 
@@ -234,4 +255,11 @@ fn main() {
     eprintln!("loaded entries: {}", sum);
 
     eprintln!("strings in cache: {}", inventory.cached_names.len());
+
+    inventory.entries.clear();
+    inventory.garbage_collect();
+    eprintln!(
+        "strings in cache after clear: {}",
+        inventory.cached_names.len()
+    );
 }
@@ -0,0 +1,125 @@
+//! Client library of the rmrfd. Deleting a directory tree with it takes about as long as
+//! renaming it, the daemon deletes it in background. See the README for the protocol.
+#![warn(missing_docs)]
+#![warn(rustdoc::missing_crate_level_docs)]
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{self, Path, PathBuf};
+
+use librmrfd::protocol::{Request, Response};
+use librmrfd::RmrfdError;
+pub use librmrfd::protocol::DEFAULT_SOCKET;
+
+/// Returns the control socket of the daemon, RMRFD_SOCKET or DEFAULT_SOCKET.
+pub fn socket() -> PathBuf {
+    env::var_os("RMRFD_SOCKET").map_or_else(|| PathBuf::from(DEFAULT_SOCKET), PathBuf::from)
+}
+
+/// Removes 'path' with the daemon listening on socket(), see rmrf_at().
+pub fn rmrf(path: &Path, sync: i8) -> io::Result<u64> {
+    rmrf_at(&socket(), path, sync)
+}
+
+/// Removes 'path' from the filesystem by moving it into a directory reserved by the daemon
+/// listening on 'socket', the daemon deletes it from there. With 'sync' -1 this returns as
+/// soon as 'path' is moved, with 0 once the size to be freed is known and with 1 to 100 when
+/// that percentage of it is freed. Returns the number of 1k blocks freed or to be freed, 0
+/// with -1.
+pub fn rmrf_at(socket: &Path, path: &Path, sync: i8) -> io::Result<u64> {
+    if !(-1..=100).contains(&sync) {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let path = path::absolute(path)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let stream = UnixStream::connect(socket)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let reserved = request(&mut reader, &mut writer, Request::Path(path.clone()))?;
+    // when this fails closing the session lets the daemon drop the reserved directory
+    fs::rename(&path, Path::new(&reserved).join(name))?;
+    let blocks = request(&mut reader, &mut writer, Request::Sync(sync))?;
+    blocks
+        .to_str()
+        .and_then(|blocks| blocks.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid number of blocks"))
+}
+
+/// Sends 'request' and returns the value of the response.
+fn request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    request: Request,
+) -> io::Result<OsString> {
+    request.write(writer)?;
+    match Response::read(reader) {
+        Ok(Response::Ok(value)) => Ok(value),
+        Ok(Response::Err(errno)) => Err(io::Error::from_raw_os_error(errno)),
+        Err(RmrfdError::Io(err)) => Err(err),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn spool() {
+        let root = std::env::temp_dir().join(format!("librmrf-spool-{}", std::process::id()));
+        fs::create_dir_all(root.join(".rmrf/tmp.1")).unwrap();
+        fs::create_dir_all(root.join("victim")).unwrap();
+        let socket = root.join("sock");
+
+        // answers like the daemon, without deleting anything
+        let listener = UnixListener::bind(&socket).unwrap();
+        let daemon = {
+            let root = root.clone();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                assert_eq!(
+                    Request::read(&mut reader).unwrap(),
+                    Some(Request::Path(root.join("victim")))
+                );
+                Response::Ok(root.join(".rmrf/tmp.1/").into_os_string())
+                    .write(&mut writer)
+                    .unwrap();
+                assert_eq!(Request::read(&mut reader).unwrap(), Some(Request::Sync(0)));
+                Response::Ok(OsString::from("42"))
+                    .write(&mut writer)
+                    .unwrap();
+
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                Request::read(&mut reader).unwrap();
+                Response::Err(libc::EXDEV).write(&mut &stream).unwrap();
+            })
+        };
+
+        assert_eq!(rmrf_at(&socket, &root.join("victim"), 0).unwrap(), 42);
+        assert!(root.join(".rmrf/tmp.1/victim").is_dir());
+        assert!(!root.join("victim").exists());
+
+        let err = rmrf_at(&socket, &root.join(".rmrf"), -1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        assert!(root.join(".rmrf").exists());
+        assert_eq!(
+            rmrf_at(&socket, &root, 101).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        daemon.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
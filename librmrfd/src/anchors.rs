@@ -0,0 +1,188 @@
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use dirinventory::openat::Dir;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::platform::{metadata_types, Mount};
+
+/// The rmrf directories held open as trusted starting points. Everything below them is
+/// opened relative to the parent directory handle with O_NOFOLLOW, a symlink planted in a
+/// world writable spool can never redirect an operation to somewhere else. Neither can a
+/// filesystem or bind mount which appears below an anchor, nothing is opened on another mount
+/// than the anchor's own.
+#[derive(Debug)]
+pub(crate) struct Anchors(Vec<Anchor>);
+
+/// A single trusted directory.
+#[derive(Debug)]
+struct Anchor {
+    path:  PathBuf,
+    dir:   Dir,
+    mount: Mount,
+    ino:   metadata_types::ino_t,
+}
+
+impl Anchors {
+    /// Opens the directories 'paths', they are trusted and may contain symlinks.
+    pub(crate) fn new<'a>(paths: impl Iterator<Item = &'a Path>) -> io::Result<Anchors> {
+        paths
+            .map(|path| {
+                let dir = Dir::open(path)?;
+                Ok(Anchor {
+                    path: path.to_path_buf(),
+                    mount: Mount::of(&dir)?,
+                    ino: dir.self_metadata()?.ino().unwrap_or(0),
+                    dir,
+                })
+            })
+            .collect::<io::Result<_>>()
+            .map(Anchors)
+    }
+
+    /// Canonicalizes the directory 'path' and returns it spelled below the anchor it is in,
+    /// None when it is not below any. Which anchor that is is decided by ancestor() on the
+    /// open directory, not by comparing path prefixes.
+    pub(crate) fn resolve(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        let canonical = fs::canonicalize(path)?;
+        let anchor = match self.ancestor(&Dir::open(&canonical)?)? {
+            Some(anchor) => anchor,
+            None => return Ok(None),
+        };
+        // Anchors may be spelled as /proc/self/fd/N, a bind mount of the anchor has another
+        // canonical path and is refused here.
+        Ok(canonical
+            .strip_prefix(fs::canonicalize(&anchor.path)?)
+            .ok()
+            .map(|rest| anchor.path.join(rest)))
+    }
+
+    /// Returns the anchor 'dir' is at or below. The ancestry is checked on the handles,
+    /// walking up from 'dir' through '..' until the device and inode of an anchor are met or
+    /// the root is reached. This can't be fooled by symlinks or differently spelled paths.
+    fn ancestor(&self, dir: &Dir) -> io::Result<Option<&Anchor>> {
+        let mut dir = dir.try_clone()?;
+        let mut metadata = dir.self_metadata()?;
+        loop {
+            if let Some(anchor) = self.0.iter().find(|anchor| {
+                metadata.dev() == Some(anchor.mount.dev) && metadata.ino() == Some(anchor.ino)
+            }) {
+                return Ok(Some(anchor));
+            }
+            let parent = dir.sub_dir(c"..")?;
+            let parent_metadata = parent.self_metadata()?;
+            // the root is its own parent
+            if parent_metadata.dev() == metadata.dev() && parent_metadata.ino() == metadata.ino()
+            {
+                return Ok(None);
+            }
+            (dir, metadata) = (parent, parent_metadata);
+        }
+    }
+
+    /// Returns the mount of the anchor 'path' is below, by its spelling.
+    pub(crate) fn mount_of(&self, path: &Path) -> Option<Mount> {
+        self.0
+            .iter()
+            .filter(|anchor| path.starts_with(&anchor.path))
+            .max_by_key(|anchor| anchor.path.as_os_str().len())
+            .map(|anchor| anchor.mount)
+    }
+
+    /// Returns the path of an anchor on the device 'dev'.
+    pub(crate) fn path_on(&self, dev: metadata_types::dev_t) -> Option<&Path> {
+        self.0
+            .iter()
+            .find(|anchor| anchor.mount.dev == dev)
+            .map(|anchor| anchor.path.as_path())
+    }
+
+    /// Opens the directory 'path' which must be below or at an anchor. Fails when any
+    /// component below the anchor is a symlink or not a directory, and with EXDEV when it
+    /// is on another mount than the anchor. 'buf' is used for the names passed to the
+    /// kernel, opening does not allocate once it is large enough.
+    pub(crate) fn open_dir(&self, path: &Path, buf: &mut Vec<u8>) -> io::Result<Dir> {
+        let (anchor, rest) = self
+            .0
+            .iter()
+            .filter_map(|anchor| path.strip_prefix(&anchor.path).ok().map(|rest| (anchor, rest)))
+            .max_by_key(|(_, rest)| std::cmp::Reverse(rest.as_os_str().len()))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::PermissionDenied))?;
+
+        rest.components()
+            .try_fold(anchor.dir.try_clone()?, |dir, component| match component {
+                Component::Normal(name) => {
+                    let dir = dir.sub_dir(cstr(buf, name)?)?;
+                    if Mount::of(&dir)? != anchor.mount {
+                        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+                    }
+                    Ok(dir)
+                }
+                _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+            })
+    }
+
+    /// Opens the parent directory of 'path' like open_dir(), returns it together with the
+    /// name of 'path' in it.
+    pub(crate) fn open_parent<'a>(
+        &self,
+        path: &'a Path,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(Dir, &'a OsStr)> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => Ok((self.open_dir(parent, buf)?, name)),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+/// Returns 'name' as nul terminated string stored in 'buf'. Unlike passing the name to openat
+/// directly this reuses the allocation of 'buf'.
+pub(crate) fn cstr<'a>(buf: &'a mut Vec<u8>, name: &OsStr) -> io::Result<&'a CStr> {
+    buf.clear();
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    CStr::from_bytes_with_nul(buf).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn nofollow() {
+        crate::tests::init_env_logging();
+
+        let root = std::env::temp_dir().join(format!("rmrfd-anchors-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub/dir")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("link")).unwrap();
+
+        let anchors = Anchors::new([root.as_path()].into_iter()).unwrap();
+        let mut buf = Vec::new();
+        assert!(anchors.open_dir(&root, &mut buf).is_ok());
+        assert!(anchors.open_dir(&root.join("sub/dir"), &mut buf).is_ok());
+        assert!(anchors.open_dir(&root.join("link"), &mut buf).is_err());
+        assert!(anchors.open_dir(&root.join("link/dir"), &mut buf).is_err());
+        assert!(anchors.open_dir(&root.join("sub/../sub"), &mut buf).is_err());
+        assert!(anchors.open_dir(Path::new("/"), &mut buf).is_err());
+
+        let path = root.join("sub/dir");
+        let (dir, name) = anchors.open_parent(&path, &mut buf).unwrap();
+        assert_eq!(name, "dir");
+        assert!(dir.metadata(name).unwrap().is_dir());
+
+        assert_eq!(
+            anchors.resolve(&root.join("link/dir/..")).unwrap(),
+            Some(root.join("sub"))
+        );
+        assert_eq!(anchors.resolve(&root.join("sub/../..")).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! Lock-free counters and gauges for the statistics. Values read while other threads update
+//! them are only approximately consistent with each other. The current value of a Gauge is
+//! sequentially consistent, it may be used to coordinate threads.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A monotonic counter.
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    /// Adds 'n' to the counter.
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the current count.
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns the average increase per second over 'elapsed'.
+    pub(crate) fn rate(&self, elapsed: Duration) -> f64 {
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.get() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// A value going up and down, tracks the maximum it had and the average over all changes.
+#[derive(Debug)]
+pub(crate) struct Gauge {
+    value:   AtomicU64,
+    max:     AtomicU64,
+    sum:     AtomicU64,
+    samples: AtomicU64,
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Gauge {
+            value:   AtomicU64::new(0),
+            max:     AtomicU64::new(0),
+            sum:     AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Gauge {
+    /// Increases the gauge by 'n'.
+    pub(crate) fn add(&self, n: u64) {
+        let value = self.value.fetch_add(n, Ordering::SeqCst) + n;
+        self.max.fetch_max(value, Ordering::Relaxed);
+        self.sample(value);
+    }
+
+    /// Decreases the gauge by 'n'.
+    pub(crate) fn sub(&self, n: u64) {
+        let value = self.value.fetch_sub(n, Ordering::SeqCst) - n;
+        self.sample(value);
+    }
+
+    fn sample(&self, value: u64) {
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub(crate) fn get(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    /// Returns the largest value the gauge had.
+    pub(crate) fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Returns the average over all values the gauge had after a change.
+    pub(crate) fn avg(&self) -> f64 {
+        match self.samples.load(Ordering::Relaxed) {
+            0 => 0.0,
+            samples => self.sum.load(Ordering::Relaxed) as f64 / samples as f64,
+        }
+    }
+}
+
+/// Number of seconds a Rate averages over.
+pub(crate) const RATE_WINDOW: u64 = 10;
+
+/// Rolling per second rate over the last RATE_WINDOW completed seconds. Keeps one bucket per
+/// second in a ring, a bucket is reset when its second comes around again. Additions racing
+/// with that reset may get lost.
+#[derive(Debug, Default)]
+pub(crate) struct Rate([(AtomicU64, AtomicU64); RATE_WINDOW as usize]);
+
+impl Rate {
+    /// Adds 'n' in second 'now', counted from an arbitrary start.
+    pub(crate) fn add(&self, n: u64, now: u64) {
+        let (second, count) = &self.0[(now % RATE_WINDOW) as usize];
+        let last = second.load(Ordering::Relaxed);
+        if last != now
+            && second
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            count.store(0, Ordering::Relaxed);
+        }
+        count.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the average per second over the seconds before 'now'. Within the first
+    /// RATE_WINDOW seconds only the seconds since the start are taken into account.
+    pub(crate) fn get(&self, now: u64) -> f64 {
+        let window = now.min(RATE_WINDOW);
+        if window == 0 {
+            return 0.0;
+        }
+        let sum: u64 = self
+            .0
+            .iter()
+            .filter(|(second, _)| {
+                let second = second.load(Ordering::Relaxed);
+                second < now && second >= now - window
+            })
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum();
+        sum as f64 / window as f64
+    }
+}
+
+/// Number of buckets of a Histogram.
+pub(crate) const HISTOGRAM_BUCKETS: usize = 24;
+
+/// Histogram of durations with logarithmic buckets. Bucket 'i' counts durations below 2^i
+/// microseconds which are not in a lower bucket, the last bucket takes everything slower.
+#[derive(Debug, Default)]
+pub(crate) struct Histogram([AtomicU64; HISTOGRAM_BUCKETS]);
+
+impl Histogram {
+    /// Accounts a single 'duration'.
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.0[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counts of all buckets.
+    pub(crate) fn get(&self) -> [u64; HISTOGRAM_BUCKETS] {
+        std::array::from_fn(|bucket| self.0[bucket].load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter() {
+        let counter = Counter::default();
+        counter.add(3);
+        counter.add(7);
+        assert_eq!(counter.get(), 10);
+        assert_eq!(counter.rate(Duration::from_secs(2)), 5.0);
+        assert_eq!(counter.rate(Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn gauge() {
+        let gauge = Gauge::default();
+        gauge.add(4);
+        gauge.sub(2);
+        gauge.add(4);
+        gauge.sub(6);
+        assert_eq!(gauge.get(), 0);
+        assert_eq!(gauge.max(), 6);
+        assert_eq!(gauge.avg(), 3.0);
+    }
+
+    #[test]
+    fn rate() {
+        let rate = Rate::default();
+        assert_eq!(rate.get(0), 0.0);
+        rate.add(10, 0);
+        rate.add(20, 1);
+        // the current second is not complete yet
+        rate.add(100, 2);
+        assert_eq!(rate.get(2), 15.0);
+
+        // seconds dropping out of the window and reused buckets
+        rate.add(30, 12);
+        assert_eq!(rate.get(13), 3.0);
+        assert_eq!(rate.get(100), 0.0);
+    }
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::ZERO);
+        histogram.record(Duration::from_micros(1));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_secs(3600));
+        let buckets = histogram.get();
+        assert_eq!(buckets[0], 1);
+        assert_eq!(buckets[1], 1);
+        assert_eq!(buckets[2], 1);
+        assert_eq!(buckets[HISTOGRAM_BUCKETS - 1], 1);
+    }
+}
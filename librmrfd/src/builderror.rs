@@ -0,0 +1,56 @@
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::pathdisplay::PathEscape;
+
+/// Errors returned by RmrfdBuilder::start() when the configuration is unusable or the
+/// daemon can't be started.
+#[derive(Debug, Error)]
+pub enum BuildError {
+    /// No rmrf directory was registered with add_dir().
+    #[error("no rmrf directory registered")]
+    NoDirs,
+    /// The named thread pool is configured with zero threads.
+    #[error("{0} threads set to zero")]
+    NoThreads(&'static str),
+    /// The stat batch size is zero.
+    #[error("stat batch size set to zero")]
+    NoBatch,
+    /// The inventory backlog can't even hold one entry per gather thread.
+    #[error("inventory backlog {backlog} is smaller than the number of gather threads {threads}")]
+    BacklogTooSmall {
+        /// The configured backlog.
+        backlog: usize,
+        /// The number of gather threads.
+        threads: usize,
+    },
+    /// A variable read by RmrfdBuilder::from_env() or from_vars() holds an unparsable value.
+    #[error("invalid value {value:?} for {var}")]
+    InvalidEnv {
+        /// Name of the variable.
+        var:   &'static str,
+        /// The offending value.
+        value: String,
+    },
+    /// The rmrf directory is on a read-only filesystem, nothing could be deleted there.
+    #[error("rmrf directory {:?} is on a read-only filesystem", .0.escaped())]
+    ReadOnly(PathBuf),
+    /// Another rmrfd process is already working on this rmrf directory.
+    #[error("rmrf directory {:?} is locked by another process", .0.escaped())]
+    Locked(PathBuf),
+    /// Starting the threads or checking a directory failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<BuildError> for io::Error {
+    fn from(err: BuildError) -> Self {
+        match err {
+            BuildError::Io(err) => err,
+            BuildError::ReadOnly(_) => io::Error::new(io::ErrorKind::ReadOnlyFilesystem, err),
+            _ => io::Error::new(io::ErrorKind::InvalidInput, err),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Linux capabilities. The daemon usually runs as root to delete the files of all users but
+//! needs only a few of root's capabilities for that. Which ones are held is detected at start,
+//! optionally all others are dropped, see RmrfdBuilder::with_drop_capabilities(). Deletions
+//! which fail for a lack of a capability are skipped and reported with
+//! DeleteErrorKind::Capability.
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The capabilities rmrfd makes use of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Handing new per user and reserved directories over to their users.
+    Chown,
+    /// Deleting in directories without write permission, for example in per user directories.
+    DacOverride,
+    /// Listing and stat()ing directories regardless of their permissions.
+    DacReadSearch,
+    /// Deleting the files of other users in sticky directories.
+    Fowner,
+    /// Negative nice values and the realtime io class, see RmrfdBuilder::with_nice().
+    SysNice,
+}
+
+const CAPABILITIES: [Capability; 5] = [
+    Capability::Chown,
+    Capability::DacOverride,
+    Capability::DacReadSearch,
+    Capability::Fowner,
+    Capability::SysNice,
+];
+
+impl Capability {
+    /// The bit of the capability in the kernel's sets.
+    fn bit(self) -> u32 {
+        match self {
+            Capability::Chown => 1 << 0,
+            Capability::DacOverride => 1 << 1,
+            Capability::DacReadSearch => 1 << 2,
+            Capability::Fowner => 1 << 3,
+            Capability::SysNice => 1 << 23,
+        }
+    }
+
+    /// What does not work without the capability.
+    fn missing(self) -> &'static str {
+        match self {
+            Capability::Chown => "directories can't be handed over to users",
+            Capability::DacOverride => "files in directories not writable are skipped",
+            Capability::DacReadSearch => "unreadable directories can't be scanned",
+            Capability::Fowner => "files of other users in sticky directories are skipped",
+            Capability::SysNice => "threads can't be given a higher priority",
+        }
+    }
+}
+
+/// A set of Capability.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Capabilities(u32);
+
+impl Capabilities {
+    /// Returns the set with 'capability' added.
+    pub(crate) fn with(self, capability: Capability) -> Capabilities {
+        Capabilities(self.0 | capability.bit())
+    }
+
+    /// Returns 'true' when 'capability' is in the set.
+    pub(crate) fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+}
+
+/// Marks HELD as not detected yet.
+const UNKNOWN: u64 = u64::MAX;
+
+/// The capabilities held by the process as of the last detect().
+static HELD: AtomicU64 = AtomicU64::new(UNKNOWN);
+
+/// Detects the effective capabilities of the calling thread, logs the missing ones and
+/// remembers them for lacks(). Called by RmrfdBuilder::start() after restrict().
+pub(crate) fn detect() -> io::Result<Capabilities> {
+    let held = current()?.0;
+    for capability in CAPABILITIES {
+        if held.contains(capability) {
+            debug!("capability {:?} held", capability);
+        } else {
+            info!("capability {:?} not held, {}", capability, capability.missing());
+        }
+    }
+    HELD.store(held.0 as u64, Ordering::Relaxed);
+    Ok(held)
+}
+
+/// Returns 'true' when 'capability' is known not to be held.
+pub(crate) fn lacks(capability: Capability) -> bool {
+    match HELD.load(Ordering::Relaxed) {
+        UNKNOWN => false,
+        held => !Capabilities(held as u32).contains(capability),
+    }
+}
+
+/// Returns the capability a deletion failing with 'error' probably lacked. EACCES is what
+/// CAP_DAC_OVERRIDE overrides, EPERM in a sticky directory what CAP_FOWNER does. EPERM on
+/// immutable or append-only files is no capability rmrfd would use, see RmrfdError::delete_in().
+pub(crate) fn missing(error: &io::Error) -> Option<Capability> {
+    let capability = match error.raw_os_error() {
+        Some(libc::EACCES) => Capability::DacOverride,
+        Some(libc::EPERM) => Capability::Fowner,
+        _ => return None,
+    };
+    lacks(capability).then_some(capability)
+}
+
+/// Drops all capabilities but the ones in 'keep' from the permitted and effective sets of the
+/// calling thread, threads started later inherit that. Dropped capabilities can't be gained
+/// back. Returns the capabilities kept, 'Unsupported' where there are no capabilities.
+pub(crate) fn restrict(keep: Capabilities) -> io::Result<Capabilities> {
+    let (effective, permitted) = current()?;
+    let kept = Capabilities(effective.0 & keep.0);
+    set(kept, Capabilities(permitted.0 & keep.0))?;
+    Ok(kept)
+}
+
+/// Header of the capget() and capset() syscalls.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid:     libc::c_int,
+}
+
+/// Data of the capget() and capset() syscalls, two of them hold 64 capabilities.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective:   u32,
+    permitted:   u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// Returns the effective and permitted capabilities of the calling thread.
+#[cfg(target_os = "linux")]
+fn current() -> io::Result<(Capabilities, Capabilities)> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid:     0,
+    };
+    let mut data = [CapData::default(); 2];
+    // SAFETY: version 3 reads two data structs, pid 0 is the calling thread
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((Capabilities(data[0].effective), Capabilities(data[0].permitted)))
+}
+
+/// Sets the 'effective' and 'permitted' capabilities of the calling thread, all others and
+/// the inheritable ones are cleared.
+#[cfg(target_os = "linux")]
+fn set(effective: Capabilities, permitted: Capabilities) -> io::Result<()> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid:     0,
+    };
+    let data = [
+        CapData {
+            effective:   effective.0,
+            permitted:   permitted.0,
+            inheritable: 0,
+        },
+        CapData::default(),
+    ];
+    // SAFETY: version 3 reads two data structs, pid 0 is the calling thread
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current() -> io::Result<(Capabilities, Capabilities)> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set(_effective: Capabilities, _permitted: Capabilities) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restrict_thread() {
+        crate::tests::init_env_logging();
+
+        // capabilities are per thread, don't drop them in the test runner's threads
+        std::thread::spawn(|| {
+            let (held, _) = current().unwrap();
+            let keep = Capabilities::default().with(Capability::Fowner);
+            let kept = restrict(keep).unwrap();
+            assert_eq!(kept.contains(Capability::Fowner), held.contains(Capability::Fowner));
+            assert!(!kept.contains(Capability::Chown));
+            assert_eq!(current().unwrap().0, kept);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! The report of a completed job, for automation verifying what a deletion did. It is kept by
+//! the job, see JobHandle::report(), written to the event log and, with
+//! RmrfdBuilder::with_report_dir(), as a file of its own.
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::events::{push_path, push_str};
+use crate::pathdisplay::PathEscape;
+use crate::{ErrorSummary, JobState, SpaceReport};
+
+/// How many skipped entries a report lists at most, ErrorSummary::errors counts all.
+pub(crate) const SKIPPED_MAX: usize = 100;
+
+/// What a job did, made when it is done, cancelled or aborted. The deleted files and bytes
+/// are the ones below the directory of the job, jobs nested in it are included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobReport {
+    /// The directory the job deleted.
+    pub path:          PathBuf,
+    /// The user the job is attributed to.
+    pub uid:           Option<libc::uid_t>,
+    /// How the job ended.
+    pub state:         JobState,
+    /// From the submission to the end.
+    pub duration:      Duration,
+    /// Files deleted.
+    pub deleted_files: u64,
+    /// Bytes of the deleted files.
+    pub deleted_bytes: u64,
+    /// Directories removed by the sweep.
+    pub removed_dirs:  u64,
+    /// Files below the min_blockcount threshold, they were left to the sweep.
+    pub small_files:   u64,
+    /// Bytes of 'small_files'.
+    pub small_bytes:   u64,
+    /// The space freed on the filesystem, only for jobs which are done.
+    pub space:         Option<SpaceReport>,
+    /// The failed operations.
+    pub errors:        ErrorSummary,
+    /// The first SKIPPED_MAX entries left in place because something failed.
+    pub skipped:       Vec<Skipped>,
+}
+
+/// Callback invoked with the JobReport of every job which is done, cancelled or aborted. It is
+/// called by the thread ending the job and must return quickly.
+pub(crate) type ReportCallback = dyn Fn(&JobReport) + Send + Sync;
+
+/// An entry a job left in place, see JobReport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    /// The entry.
+    pub path:   PathBuf,
+    /// The error which made it stay.
+    pub reason: String,
+}
+
+impl JobReport {
+    /// Returns the report as single line JSON object, the same as in the event log without
+    /// the 'time' field.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"event\":\"report\"");
+        self.push_json(&mut json);
+        json.push('}');
+        json
+    }
+
+    /// Appends the fields of the report to the JSON object 'json'.
+    pub(crate) fn push_json(&self, json: &mut String) {
+        json.push_str(",\"path\":");
+        push_path(json, &self.path);
+        if let Some(uid) = self.uid {
+            let _ = write!(json, ",\"uid\":{}", uid);
+        }
+        json.push_str(",\"state\":");
+        push_str(json, &format!("{:?}", self.state).to_lowercase());
+        let _ = write!(
+            json,
+            ",\"duration\":{:.3},\"deleted_files\":{},\"deleted_bytes\":{},\"removed_dirs\":{}",
+            self.duration.as_secs_f64(),
+            self.deleted_files,
+            self.deleted_bytes,
+            self.removed_dirs
+        );
+        let _ = write!(
+            json,
+            ",\"small_files\":{},\"small_bytes\":{}",
+            self.small_files, self.small_bytes
+        );
+        if let Some(space) = self.space {
+            let _ = write!(json, ",\"freed_bytes\":{},\"fs_type\":", space.freed_bytes);
+            push_str(json, &format!("{:?}", space.fs_type).to_lowercase());
+        }
+        let _ = write!(
+            json,
+            ",\"operations\":{},\"errors\":{}",
+            self.errors.operations, self.errors.errors
+        );
+        if let Some(last) = &self.errors.last {
+            json.push_str(",\"last_error\":");
+            push_str(json, last);
+        }
+        json.push_str(",\"skipped\":[");
+        for (n, skipped) in self.skipped.iter().enumerate() {
+            json.push_str(if n == 0 { "{\"path\":" } else { ",{\"path\":" });
+            push_path(json, &skipped.path);
+            json.push_str(",\"reason\":");
+            push_str(json, &skipped.reason);
+            json.push('}');
+        }
+        json.push(']');
+    }
+}
+
+/// Writes 'json' to a new file in 'dir'. It appears under its final name complete, the
+/// names sort by the time the reports were written.
+pub(crate) fn write_report(dir: &Path, json: &str) -> io::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let name = format!(
+        "job.{:016x}.{:x}.json",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let partial = dir.join(format!(".{}.tmp", name));
+    let path = dir.join(name);
+    fs::write(&partial, json)?;
+    fs::rename(&partial, &path)?;
+    debug!("job report {:?}", path.escaped());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_json() {
+        let report = JobReport {
+            path:          PathBuf::from("/tmp/rmrf/job"),
+            uid:           Some(1000),
+            state:         JobState::Aborted,
+            duration:      Duration::from_millis(1500),
+            deleted_files: 3,
+            deleted_bytes: 12288,
+            removed_dirs:  0,
+            small_files:   1,
+            small_bytes:   100,
+            space:         None,
+            errors:        ErrorSummary {
+                operations: 5,
+                errors:     2,
+                last:       Some(String::from("busy")),
+            },
+            skipped:       vec![
+                Skipped {
+                    path:   PathBuf::from("/tmp/rmrf/job/a"),
+                    reason: String::from("gone"),
+                },
+                Skipped {
+                    path:   PathBuf::from("/tmp/rmrf/job/\"b\""),
+                    reason: String::from("busy"),
+                },
+            ],
+        };
+        let json = report.to_json();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-report-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = write_report(&dir, &json).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(value["state"], "aborted");
+        assert_eq!(value["duration"], 1.5);
+        assert_eq!(value["errors"], 2);
+        assert_eq!(value["small_bytes"], 100);
+        assert_eq!(value["skipped"][1]["path"], "/tmp/rmrf/job/\"b\"");
+        assert_eq!(value["skipped"][1]["reason"], "busy");
+        assert!(value.get("freed_bytes").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
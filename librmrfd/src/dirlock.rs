@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::pathdisplay::PathEscape;
+
+/// Locks held by this process, instances within one process share them.
+static LOCKS: Mutex<BTreeMap<(u64, u64), Weak<DirLock>>> = Mutex::new(BTreeMap::new());
+
+/// An exclusive flock() on a rmrf directory, prevents two daemons from processing the same
+/// directory. The lock is released when the last instance in this process drops it.
+#[derive(Debug)]
+pub(crate) struct DirLock(fs::File);
+
+impl DirLock {
+    /// Locks the directory 'path'. Fails with 'WouldBlock' when another process holds the
+    /// lock.
+    pub(crate) fn lock(path: &Path) -> io::Result<Arc<DirLock>> {
+        let dir = fs::File::open(path)?;
+        let metadata = dir.metadata()?;
+        let key = (metadata.dev(), metadata.ino());
+
+        let mut locks = LOCKS.lock();
+        if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+            return Ok(lock);
+        }
+
+        // SAFETY: flock on a valid fd owned by 'dir'
+        if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!("locked {:?}", path.escaped());
+        let lock = Arc::new(DirLock(dir));
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        locks.insert(key, Arc::downgrade(&lock));
+        Ok(lock)
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // closing the file releases the lock
+        debug!("unlocking fd {}", self.0.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        crate::tests::init_env_logging();
+
+        let path = std::env::temp_dir().join(format!("rmrfd-dirlock-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+
+        // a separate open file description acts like another process
+        let other = fs::File::open(&path).unwrap();
+        // SAFETY: flock on a valid fd
+        assert_eq!(
+            unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        assert_eq!(
+            DirLock::lock(&path).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        drop(other);
+
+        let lock = DirLock::lock(&path).unwrap();
+        assert!(Arc::ptr_eq(&lock, &DirLock::lock(&path).unwrap()));
+
+        fs::remove_dir(&path).unwrap();
+    }
+}
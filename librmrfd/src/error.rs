@@ -0,0 +1,229 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use dirinventory::{DynError, ObjectPath};
+use dirinventory::openat::{AsPath, Dir};
+use thiserror::Error;
+
+use crate::{BuildError, Capability};
+use crate::caps;
+use crate::pathdisplay::PathEscape;
+use crate::platform::is_immutable;
+
+/// The errors of the rmrfd library.
+#[derive(Debug, Error)]
+pub enum RmrfdError {
+    /// The configuration is unusable, see RmrfdBuilder::start().
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    /// Listing a directory or stat()ing an entry failed.
+    #[error("gathering {:?}: {source}", .path.escaped())]
+    Gather {
+        /// The entry that failed.
+        path:   PathBuf,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// Deleting an object failed.
+    #[error("deleting {:?}: {source} ({kind:?})", .path.escaped())]
+    Delete {
+        /// The object that failed.
+        path:   PathBuf,
+        /// What kind of failure this is.
+        kind:   DeleteErrorKind,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// The object at the path is not the one scanned anymore, it was replaced by another
+    /// file or a symlink. It is left alone.
+    #[error("{:?} was replaced since it was scanned, not deleted", .0.escaped())]
+    Replaced(PathBuf),
+    /// A directory is on another filesystem or bind mount than its job, something was mounted
+    /// there. It is not descended into.
+    #[error("{:?} is a mountpoint, not descending", .0.escaped())]
+    Mountpoint(PathBuf),
+    /// A path is a layer of a mounted overlay or contains one, it must not be changed
+    /// while mounted. It is left alone.
+    #[error("{:?} is a layer of a mounted overlay, not deleting", .0.escaped())]
+    OverlayLayer(PathBuf),
+    /// New entries kept appearing in a directory while it was swept, someone is still
+    /// writing there. It is left in place.
+    #[error("{:?} still not empty after {rescans} rescans", .path.escaped())]
+    NotEmpty {
+        /// The directory.
+        path:    PathBuf,
+        /// How often it was swept again.
+        rescans: u32,
+    },
+    /// A silly renamed file is still open on an NFS client, the server won't let it go. It
+    /// and the directories above it are left in place.
+    #[error("{:?} is still open on an NFS client, left in place", .0.escaped())]
+    Lingering(PathBuf),
+    /// A path passed in can't be used, for example because it contains '..'.
+    #[error("invalid path {:?}", .0.escaped())]
+    InvalidPath(PathBuf),
+    /// A path passed in is not below any of the registered rmrf directories.
+    #[error("{:?} is not below a rmrf directory", .0.escaped())]
+    NotBelowRmrfDir(PathBuf),
+    /// There is no rmrf directory on the filesystem of a path passed in at the same directory
+    /// level or above it, see Rmrfd::reserve().
+    #[error("no rmrf directory for {:?}", .0.escaped())]
+    NoRmrfDir(PathBuf),
+    /// The submission is not allowed for the user, or the per user directory has the wrong
+    /// owner or mode.
+    #[error("permission denied for {:?}", .0.escaped())]
+    PermissionDenied(PathBuf),
+    /// A client is not authorized for the action it requested.
+    #[error("not authorized for {0}")]
+    NotAuthorized(String),
+    /// A client sent something the daemon does not understand.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// Any other IO error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl RmrfdError {
+    /// Creates a Gather error from an error reported by the gatherer for 'path'.
+    pub(crate) fn gather(path: &ObjectPath, error: DynError) -> Self {
+        RmrfdError::Gather {
+            path:   path.to_pathbuf(),
+            source: error
+                .downcast::<io::Error>()
+                .map_or_else(|error| io::Error::other(error.to_string()), |error| *error),
+        }
+    }
+
+    /// Returns the path the error is about, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            RmrfdError::Gather { path, .. }
+            | RmrfdError::Delete { path, .. }
+            | RmrfdError::Replaced(path)
+            | RmrfdError::Mountpoint(path)
+            | RmrfdError::OverlayLayer(path)
+            | RmrfdError::NotEmpty { path, .. }
+            | RmrfdError::Lingering(path)
+            | RmrfdError::InvalidPath(path)
+            | RmrfdError::NotBelowRmrfDir(path)
+            | RmrfdError::NoRmrfDir(path)
+            | RmrfdError::PermissionDenied(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Returns the errno best describing the error, for example to report it to a client.
+    pub fn errno(&self) -> i32 {
+        match self {
+            RmrfdError::Gather { source, .. }
+            | RmrfdError::Delete { source, .. }
+            | RmrfdError::Io(source) => source.raw_os_error().unwrap_or(libc::EIO),
+            RmrfdError::Replaced(_) => libc::ESTALE,
+            RmrfdError::Mountpoint(_) | RmrfdError::NoRmrfDir(_) => libc::EXDEV,
+            RmrfdError::NotEmpty { .. } => libc::ENOTEMPTY,
+            RmrfdError::Lingering(_) | RmrfdError::OverlayLayer(_) => libc::EBUSY,
+            RmrfdError::InvalidPath(_) | RmrfdError::NotBelowRmrfDir(_) => libc::EINVAL,
+            RmrfdError::PermissionDenied(_) => libc::EACCES,
+            RmrfdError::NotAuthorized(_) => libc::EPERM,
+            RmrfdError::Protocol(_) => libc::EPROTO,
+            RmrfdError::Build(_) => libc::EINVAL,
+        }
+    }
+
+    /// Creates a Delete error for 'path', classifying the errno of 'error'. Permission errors
+    /// are attributed to a capability when the daemon does not hold it.
+    pub fn delete(path: PathBuf, error: io::Error) -> Self {
+        let kind = caps::missing(&error)
+            .map_or_else(|| DeleteErrorKind::from(&error), DeleteErrorKind::Capability);
+        RmrfdError::Delete {
+            path,
+            kind,
+            source: error,
+        }
+    }
+
+    /// Like delete() for the entry 'name' of 'dir' at 'path'. EPERM on an immutable or
+    /// append-only entry or 'dir' is reported as such, looked up relative to 'dir'.
+    pub(crate) fn delete_in<P: AsPath + Copy>(
+        dir: &Dir,
+        name: P,
+        path: PathBuf,
+        error: io::Error,
+    ) -> Self {
+        if error.raw_os_error() == Some(libc::EPERM)
+            && [is_immutable(dir, name), is_immutable(dir, ".")]
+                .into_iter()
+                .any(|immutable| immutable.unwrap_or(false))
+        {
+            return RmrfdError::Delete {
+                path,
+                kind: DeleteErrorKind::Immutable,
+                source: error,
+            };
+        }
+        RmrfdError::delete(path, error)
+    }
+}
+
+/// Classification of deletion failures, determines how the daemon reacts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteErrorKind {
+    /// The object vanished already, nothing to do.
+    Gone,
+    /// Not allowed to delete the object, skipped.
+    Permission,
+    /// Not allowed to delete the object because the daemon lacks the capability, skipped.
+    Capability(Capability),
+    /// The object or its directory is flagged immutable or append-only, skipped. rmrfd
+    /// never clears these flags.
+    Immutable,
+    /// The object or filesystem is busy, may be retried later.
+    Busy,
+    /// The filesystem is read-only, no more deletions there.
+    ReadOnly,
+    /// The NFS file handle went stale, the path needs to be looked up again.
+    Stale,
+    /// Anything else.
+    Other,
+}
+
+impl From<&io::Error> for DeleteErrorKind {
+    fn from(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::ENOENT) => DeleteErrorKind::Gone,
+            Some(libc::EACCES) | Some(libc::EPERM) => DeleteErrorKind::Permission,
+            Some(libc::EBUSY) | Some(libc::ENOTEMPTY) => DeleteErrorKind::Busy,
+            Some(libc::EROFS) => DeleteErrorKind::ReadOnly,
+            Some(libc::ESTALE) => DeleteErrorKind::Stale,
+            _ => DeleteErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let err = RmrfdError::delete(
+            PathBuf::from("/tmp/foo"),
+            io::Error::from_raw_os_error(libc::EROFS),
+        );
+        assert!(matches!(
+            err,
+            RmrfdError::Delete {
+                kind: DeleteErrorKind::ReadOnly,
+                ..
+            }
+        ));
+
+        let err = RmrfdError::gather(
+            &ObjectPath::new("/tmp/foo"),
+            Box::new(io::Error::from_raw_os_error(libc::EACCES)),
+        );
+        assert!(err.to_string().starts_with("gathering \"/tmp/foo\": "));
+        assert_eq!(err.errno(), libc::EACCES);
+    }
+}
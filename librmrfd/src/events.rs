@@ -0,0 +1,409 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use crossbeam_channel::{bounded, Sender, TrySendError};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{JobReport, JobState, PauseReason, RmrfdError, SpaceReport};
+use crate::completion::{write_report, ReportCallback};
+use crate::platform::metadata_types;
+use crate::pathdisplay::PathEscape;
+
+/// Something worth telling external automation about.
+#[derive(Debug)]
+pub(crate) enum Event<'a> {
+    /// A job changed its state.
+    Job {
+        path:  &'a Path,
+        uid:   Option<libc::uid_t>,
+        state: JobState,
+        /// Set when the job is done.
+        space: Option<SpaceReport>,
+    },
+    /// A directory was completely removed.
+    Dir { path: &'a Path },
+    /// Gathering or deleting something failed.
+    Error { error: &'a RmrfdError },
+    /// The deletion on a device was paused, noticed at 'path'.
+    Paused {
+        dev:    metadata_types::dev_t,
+        reason: PauseReason,
+        path:   &'a Path,
+    },
+    /// The deletion on a device was resumed.
+    Resumed { dev: metadata_types::dev_t },
+    /// A job is done, cancelled or aborted.
+    Report { report: &'a JobReport },
+}
+
+/// How many lines may wait for the event log writer, more are dropped with a warning.
+const BACKLOG: usize = 1024;
+
+/// Writes one JSON object per line for every event, see RmrfdBuilder::with_event_log(). The
+/// reports of jobs are written to files of their own in the report directory as well, see
+/// RmrfdBuilder::with_report_dir(), and passed to the callback registered with
+/// RmrfdBuilder::with_report_callback(). Does nothing when none of them is set.
+#[derive(Default)]
+pub(crate) struct EventLog {
+    writer:     Option<EventWriter>,
+    report_dir: Option<PathBuf>,
+    on_report:  Option<Arc<ReportCallback>>,
+}
+
+/// The thread writing the event log, a slow log never holds up the deletion. Dropping it
+/// writes the lines still waiting and stops the thread.
+struct EventWriter {
+    sender: Sender<Option<String>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for EventWriter {
+    fn drop(&mut self) {
+        let _ = self.sender.send(None);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventLog")
+            .field("enabled", &self.writer.is_some())
+            .field("report_dir", &self.report_dir)
+            .field("on_report", &self.on_report.is_some())
+            .finish()
+    }
+}
+
+impl EventLog {
+    /// Creates an EventLog writing to 'writer' from a thread of its own. Every line is
+    /// written and flushed at once, readers tailing the log never see partial objects. Write
+    /// errors are only logged.
+    pub(crate) fn new(mut writer: Box<dyn Write + Send>) -> io::Result<EventLog> {
+        let (sender, receiver) = bounded::<Option<String>>(BACKLOG);
+        let thread = thread::Builder::new()
+            .name(String::from("events"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Ok(Some(line)) = receiver.recv() {
+                    if let Err(err) = writer
+                        .write_all(line.as_bytes())
+                        .and_then(|_| writer.flush())
+                    {
+                        warn!("writing event log: {}", err);
+                    }
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(EventLog {
+            writer:     Some(EventWriter {
+                sender,
+                thread: Some(thread),
+            }),
+            report_dir: None,
+            on_report:  None,
+        })
+    }
+
+    /// Writes the job reports to files in 'dir' as well.
+    pub(crate) fn with_report_dir(mut self, dir: Option<PathBuf>) -> EventLog {
+        self.report_dir = dir;
+        self
+    }
+
+    /// Calls 'callback' with the job reports as well.
+    pub(crate) fn with_report_callback(
+        mut self,
+        callback: Option<Arc<ReportCallback>>,
+    ) -> EventLog {
+        self.on_report = callback;
+        self
+    }
+
+    /// Writes 'event'. Lines are queued for the event log, when its backlog is full they
+    /// are dropped with a warning.
+    pub(crate) fn emit(&self, event: Event) {
+        let report_dir = match event {
+            Event::Report { report } => {
+                if let Some(on_report) = &self.on_report {
+                    on_report(report);
+                }
+                self.report_dir.as_ref()
+            }
+            _ => None,
+        };
+        if self.writer.is_none() && report_dir.is_none() {
+            return;
+        }
+        let line = to_json(&event, SystemTime::now());
+        if let Some(dir) = report_dir {
+            if let Err(err) = write_report(dir, &line) {
+                warn!("writing job report to {:?}: {}", dir.escaped(), err);
+            }
+        }
+        if let Some(writer) = &self.writer {
+            if let Err(TrySendError::Full(_)) = writer.sender.try_send(Some(line)) {
+                warn!("event log backlog full, event dropped");
+            }
+        }
+    }
+}
+
+/// Formats 'event' as a single line JSON object.
+fn to_json(event: &Event, time: SystemTime) -> String {
+    let time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut json = format!("{{\"time\":{:.3}", time);
+    match event {
+        Event::Job {
+            path,
+            uid,
+            state,
+            space,
+        } => {
+            json.push_str(",\"event\":\"job\",\"path\":");
+            push_path(&mut json, path);
+            if let Some(uid) = uid {
+                let _ = write!(json, ",\"uid\":{}", uid);
+            }
+            json.push_str(",\"state\":");
+            push_str(&mut json, &format!("{:?}", state).to_lowercase());
+            if let Some(space) = space {
+                let _ = write!(
+                    json,
+                    ",\"deleted_bytes\":{},\"freed_bytes\":{}",
+                    space.deleted_bytes, space.freed_bytes
+                );
+                json.push_str(",\"fs_type\":");
+                push_str(&mut json, &format!("{:?}", space.fs_type).to_lowercase());
+            }
+        }
+        Event::Dir { path } => {
+            json.push_str(",\"event\":\"dir\",\"path\":");
+            push_path(&mut json, path);
+        }
+        Event::Error { error } => {
+            json.push_str(",\"event\":\"error\"");
+            if let Some(path) = error.path() {
+                json.push_str(",\"path\":");
+                push_path(&mut json, path);
+            }
+            json.push_str(",\"message\":");
+            push_str(&mut json, &error.to_string());
+        }
+        Event::Paused { dev, reason, path } => {
+            let _ = write!(json, ",\"event\":\"paused\",\"dev\":{},\"reason\":", dev);
+            push_str(&mut json, &format!("{:?}", reason).to_lowercase());
+            json.push_str(",\"path\":");
+            push_path(&mut json, path);
+        }
+        Event::Resumed { dev } => {
+            let _ = write!(json, ",\"event\":\"resumed\",\"dev\":{}", dev);
+        }
+        Event::Report { report } => {
+            json.push_str(",\"event\":\"report\"");
+            report.push_json(&mut json);
+        }
+    }
+    json.push_str("}\n");
+    json
+}
+
+/// Appends 'path' escaped like in the logs as quoted JSON string, the path in an event and in
+/// a log line about the same file are the same string.
+pub(crate) fn push_path(json: &mut String, path: &Path) {
+    push_str(json, &path.escaped().to_string());
+}
+
+/// Appends 's' as quoted JSON string.
+pub(crate) fn push_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::FsType;
+
+    /// Collects everything written in memory.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        crate::tests::init_env_logging();
+
+        let buffer = Buffer::default();
+        let events = EventLog::new(Box::new(buffer.clone())).unwrap();
+        events.emit(Event::Job {
+            path:  Path::new("/tmp/rmrf/\"quoted\"\n"),
+            uid:   Some(1000),
+            state: JobState::Sweeping,
+            space: None,
+        });
+        events.emit(Event::Job {
+            path:  Path::new("/tmp/rmrf/done"),
+            uid:   None,
+            state: JobState::Done,
+            space: Some(SpaceReport {
+                deleted_bytes: 4096,
+                freed_bytes:   0,
+                fs_type:       FsType::Overlay,
+            }),
+        });
+        events.emit(Event::Error {
+            error: &RmrfdError::delete(
+                "/tmp/rmrf/foo".into(),
+                io::Error::from_raw_os_error(libc::EACCES),
+            ),
+        });
+        EventLog::default().emit(Event::Dir {
+            path: Path::new("/tmp/rmrf/foo"),
+        });
+        events.emit(Event::Paused {
+            dev:    42,
+            reason: PauseReason::ReadOnly,
+            path:   Path::new("/tmp/rmrf/foo"),
+        });
+        drop(events);
+
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["event"], "job");
+        assert_eq!(lines[0]["path"], "/tmp/rmrf/\"quoted\"\\n");
+        assert_eq!(lines[0]["uid"], 1000);
+        assert_eq!(lines[0]["state"], "sweeping");
+        assert_eq!(lines[1]["state"], "done");
+        assert_eq!(lines[1]["deleted_bytes"], 4096);
+        assert_eq!(lines[1]["freed_bytes"], 0);
+        assert_eq!(lines[1]["fs_type"], "overlay");
+        assert_eq!(lines[2]["event"], "error");
+        assert_eq!(lines[2]["path"], "/tmp/rmrf/foo");
+        assert_eq!(lines[3]["event"], "paused");
+        assert_eq!(lines[3]["dev"], 42);
+        assert_eq!(lines[3]["reason"], "readonly");
+    }
+
+    #[test]
+    fn reports() {
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-reports-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let buffer = Buffer::default();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let events = EventLog::new(Box::new(buffer.clone()))
+            .unwrap()
+            .with_report_dir(Some(dir.clone()))
+            .with_report_callback(Some({
+                let states = states.clone();
+                Arc::new(move |report: &JobReport| states.lock().push(report.state))
+            }));
+        let report = JobReport {
+            path:          PathBuf::from("/tmp/rmrf/job"),
+            uid:           None,
+            state:         JobState::Done,
+            duration:      std::time::Duration::from_secs(2),
+            deleted_files: 1,
+            deleted_bytes: 4096,
+            removed_dirs:  1,
+            small_files:   0,
+            small_bytes:   0,
+            space:         Some(SpaceReport {
+                deleted_bytes: 4096,
+                freed_bytes:   4096,
+                fs_type:       FsType::Other,
+            }),
+            errors:        Default::default(),
+            skipped:       Vec::new(),
+        };
+        events.emit(Event::Report { report: &report });
+        events.emit(Event::Dir {
+            path: Path::new("/tmp/rmrf/job"),
+        });
+        drop(events);
+
+        assert_eq!(*states.lock(), [JobState::Done]);
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        let line: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(line["event"], "report");
+        assert_eq!(line["freed_bytes"], 4096);
+        assert_eq!(line["fs_type"], "other");
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let file = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert_eq!(file, output.lines().next().unwrap().to_string() + "\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Blocks writing while the test holds the gate.
+    struct Gated(Arc<Mutex<()>>, Buffer);
+
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _gate = self.0.lock();
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backlog() {
+        crate::tests::init_env_logging();
+
+        let (gate, buffer) = (Arc::new(Mutex::new(())), Buffer::default());
+        let events = EventLog::new(Box::new(Gated(gate.clone(), buffer.clone()))).unwrap();
+        let closed = gate.lock();
+        for dev in 0..BACKLOG as u64 + 10 {
+            events.emit(Event::Resumed { dev });
+        }
+        drop(closed);
+        drop(events);
+
+        // the writer may have taken one line off the backlog before it blocked
+        let lines = String::from_utf8(buffer.0.lock().clone()).unwrap().lines().count();
+        assert!((BACKLOG..=BACKLOG + 1).contains(&lines));
+    }
+}
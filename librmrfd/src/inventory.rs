@@ -1,77 +1,509 @@
 use std::sync::Arc;
 use std::io;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::CStr;
+use std::path::PathBuf;
 use std::thread;
+use std::time::Instant;
 
 use dirinventory::{openat, InventoryEntryMessage, ObjectPath};
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use openat::{metadata_types, Metadata};
+use crossbeam_channel::{unbounded, Receiver, Select, Sender};
+use parking_lot::Mutex;
+use openat::{Dir, Metadata};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use crate::RmrfdError;
+use crate::anchors::{cstr, Anchors};
+use crate::events::{Event, EventLog};
+use crate::job::{JobHandle, Jobs};
+use crate::notify::{DeleteCallback, DeletedFile};
+use crate::nfs;
 use crate::objectlist::ObjectList;
+use crate::pause::{PauseReason, Pauses};
+use crate::pathdisplay::{ObjectPathDisplay, PathEscape};
+use crate::platform::{blocks_to_bytes, metadata_types};
+use crate::profile::{self, Syscall};
+use crate::rmrfd::DirsQueue;
+use crate::statistics::Stats;
+use crate::statpool::StatPool;
+use crate::sweep::sweep;
+use crate::threadprio::ThreadPriority;
+use crate::trace::{pass_span, phase_span, Span};
+use crate::watchdog::{self, Item};
 
 /// Stores all paths generated by the inventory gather pass.  The Inventory stores paths in
 /// sub maps per device id, each sorted by size and inode.
 #[derive(Debug)]
 pub struct Inventory {
-    // output: Receiver<InventoryMessage>,
+    control: Vec<Sender<InventoryControl>>,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+    jobs:    Arc<Jobs>,
+    stats:   Arc<Stats>,
+    pauses:  Arc<Pauses>,
+}
+
+/// Control messages to the inventory threads, these are selected together with the data
+/// channels.
+#[derive(Debug)]
+pub(crate) enum InventoryControl {
+    /// Terminate the thread.
+    Shutdown,
+    /// Change the early delete threshold, see RmrfdBuilder::with_early_delete_percent().
+    EarlyDeletePercent(metadata_types::blkcnt_t),
 }
 
 impl Inventory {
-    /// Create a new Inventory.
-    pub fn new(
+    /// Create a new Inventory with 'threads' threads. Gatherer output channels and stat pool
+    /// channels are distributed round robin over the threads, each thread selects on all of
+    /// its channels plus a control channel. The threads run with the given 'priority',
+    /// 'on_deleted' is called for every deleted file. Files are only really deleted when
+    /// 'armed', always relative to a handle opened below one of the 'anchors'. Errors and
+    /// paused devices are written to 'events'. A pass ends the 'jobs' 'dirs_queue' gathered
+    /// completely, they must be created for as many threads as there are 'channels', at
+    /// most 'threads'.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        threads: usize,
         channels: Vec<Arc<Receiver<InventoryEntryMessage>>>,
+        stat_channels: Vec<Receiver<Vec<InventoryEntryMessage>>>,
+        stat_pool: Arc<StatPool>,
         early_delete_percent: metadata_types::blkcnt_t,
+        priority: ThreadPriority,
+        on_deleted: Option<Arc<DeleteCallback>>,
+        armed: bool,
+        events: Arc<EventLog>,
+        anchors: Arc<Anchors>,
+        jobs: Arc<Jobs>,
+        dirs_queue: Arc<DirsQueue>,
     ) -> io::Result<Arc<Inventory>> {
-        (0..channels.len()).try_for_each(|n| -> io::Result<()> {
-            let receiver = channels[n].clone();
+        let threads = std::cmp::min(threads, channels.len());
+        let pauses = Pauses::new(events);
+        let stats = Stats::new();
+        let mut control = Vec::with_capacity(threads);
+        let mut handles = Vec::with_capacity(threads);
+
+        (0..threads).try_for_each(|n| -> io::Result<()> {
+            let receivers: Vec<_> = channels.iter().skip(n).step_by(threads).cloned().collect();
+            let stat_receivers: Vec<_> = stat_channels
+                .iter()
+                .skip(n)
+                .step_by(threads)
+                .cloned()
+                .collect();
+            let (control_sender, control_receiver) = unbounded();
+            control.push(control_sender);
+            let stat_pool = stat_pool.clone();
+            let dirs_queue = dirs_queue.clone();
+            let jobs = jobs.clone();
+            let stats = stats.clone();
+            let on_deleted = on_deleted.clone();
+            let mut deleter = Deleter::new(anchors.clone(), pauses.clone(), armed);
             let mut inventory_map = InventoryMap::new();
+            let mut backlog = VecDeque::new();
+            let mut dones = 0;
+            let mut epoch = None;
+            let mut early_delete_percent = early_delete_percent;
 
             let mut max_blkcnt_sofar: metadata_types::blkcnt_t = 0;
+            let mut pass: Option<Span> = None;
+            let mut entries: u64 = 0;
+
+            handles.push(
+                thread::Builder::new()
+                    .name(format!("inventory/{}", n))
+                    .spawn(move || {
+                        debug!("thread started: {}", thread::current().name().unwrap());
+                        priority.apply();
+                        stat_pool.watchdog().register();
+                        let mut select = Select::new();
+                        select.recv(&control_receiver);
+                        receivers.iter().for_each(|receiver| {
+                            select.recv(receiver);
+                        });
+                        stat_receivers.iter().for_each(|receiver| {
+                            select.recv(receiver);
+                        });
+
+                        loop {
+                            use crate::inventory::InventoryEntryMessage::*;
+                            let message = match backlog.pop_front() {
+                                Some(message) => message,
+                                None => {
+                                    let operation = select.select();
+                                    match operation.index() {
+                                        0 => {
+                                            match operation.recv(&control_receiver) {
+                                                Ok(InventoryControl::EarlyDeletePercent(p)) => {
+                                                    debug!("early_delete_percent: {}", p);
+                                                    early_delete_percent = p;
+                                                    continue;
+                                                }
+                                                Ok(InventoryControl::Shutdown) | Result::Err(_) => {
+                                                    debug!("shutdown");
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                        index if index <= receivers.len() => {
+                                            match operation.recv(&receivers[index - 1]) {
+                                                Ok(message) => message,
+                                                Result::Err(_) => break,
+                                            }
+                                        }
+                                        index => {
+                                            match operation.recv(
+                                                &stat_receivers[index - 1 - receivers.len()],
+                                            ) {
+                                                Ok(batch) => {
+                                                    backlog.extend(batch);
+                                                    continue;
+                                                }
+                                                Result::Err(_) => break,
+                                            }
+                                        }
+                                    }
+                                }
+                            };
+                            pass.get_or_insert_with(|| pass_span(n));
+                            match message {
+                                Metadata { path, metadata, .. } => {
+                                    trace!("got metadata for: {:?}", path.display());
+                                    entries += 1;
+
+                                    if jobs.is_cancelled(&path) {
+                                        trace!("cancelled {:?}", path.display());
+                                        continue;
+                                    }
 
-            thread::Builder::new()
-                .name(format!("inventory/{}", n))
-                .spawn(move || {
-                    debug!("thread started: {}", thread::current().name().unwrap());
-                    loop {
-                        use crate::inventory::InventoryEntryMessage::*;
-                        match receiver.recv().unwrap(/*TODO: thread exit */) {
-                            Metadata { path, metadata, .. } => {
-                                trace!("got metadata for: {:?}", path);
-
-                                let early_done = if metadata.nlink().unwrap_or(0) == 1 {
-                                    let blkcnt = metadata.blocks().unwrap_or(0);
-                                    if blkcnt >= max_blkcnt_sofar * early_delete_percent / 100 {
-                                        max_blkcnt_sofar = std::cmp::max(blkcnt, max_blkcnt_sofar);
-                                        // TODO: REALLY DELETE
-                                        trace!("early delete {:?}", path);
-                                        true
+                                    let early_done = if metadata.nlink().unwrap_or(0) == 1 {
+                                        let blkcnt = metadata.blocks().unwrap_or(0);
+                                        if blkcnt >= max_blkcnt_sofar * early_delete_percent / 100
+                                        {
+                                            max_blkcnt_sofar =
+                                                std::cmp::max(blkcnt, max_blkcnt_sofar);
+                                            trace!("early delete {:?}", path.display());
+                                            let dev = metadata.dev().unwrap_or(0);
+                                            let ino = metadata.ino().unwrap_or(0);
+                                            if deleter.delete_file(&path, dev, ino, &stats, &jobs)
+                                            {
+                                                let bytes = blocks_to_bytes(blkcnt);
+                                                jobs.deleted(&path, 1, bytes);
+                                                stats.deleted(dev, 1, bytes);
+                                                if let Some(on_deleted) = &on_deleted {
+                                                    on_deleted(&DeletedFile {
+                                                        path:   &path,
+                                                        blocks: blkcnt,
+                                                        dev,
+                                                        ino,
+                                                    });
+                                                }
+                                            }
+                                            true
+                                        } else {
+                                            false
+                                        }
                                     } else {
                                         false
-                                    }
-                                } else {
-                                    false
-                                };
-
-                                if !early_done {
-                                    inventory_map.insert_with_metadata(path, &metadata).ok(); //TODO: pass error up
-                                };
-                            }
-                            EndOfDirectory { .. } | Entry { .. } => { /* ignored, unused */ }
-                            Err { path, error } => { /*TODO: pass error up */ }
-                            Done => {
-                                inventory_map.fastrmrf_files();
-                                // TODO: slowrmrf (while receiver.is_empty())
-                                // TODO: signal done
+                                    };
+
+                                    if !early_done {
+                                        //TODO: pass error up
+                                        if let Ok(true) =
+                                            inventory_map.insert_with_metadata(path, &metadata)
+                                        {
+                                            stats.queued(1);
+                                        }
+                                    };
+                                }
+                                EndOfDirectory { .. } | Entry { .. } => { /* ignored, unused */ }
+                                Err { path, error, .. } => {
+                                    let error = RmrfdError::gather(&path, error);
+                                    warn!("{}", error);
+                                    jobs.events().emit(Event::Error { error: &error });
+                                    jobs.failed(&error);
+                                    stats.error();
+                                }
+                                // Every gatherer channel sends a 'Done', wait for all of them
+                                Done if dones + 1 < receivers.len() => {
+                                    dones += 1;
+                                }
+                                // Jobs gathered completely by now have all their entries in
+                                // the stat pool or the stat channels, they end with this pass.
+                                Done if epoch.is_none() => {
+                                    epoch = Some(dirs_queue.epoch());
+                                    backlog.push_front(Done);
+                                }
+                                Done if !stat_pool.is_idle()
+                                    || stat_receivers.iter().any(|r| !r.is_empty()) =>
+                                {
+                                    // The gatherer is done but stat results are still pending,
+                                    // process them first and then look at 'Done' again.
+                                    stat_pool.wait_idle();
+                                    stat_receivers.iter().for_each(|receiver| {
+                                        backlog.extend(receiver.try_iter().flatten())
+                                    });
+                                    backlog.push_back(Done);
+                                }
+                                Done => {
+                                    dones = 0;
+                                    let pass = pass.take().unwrap_or_else(|| pass_span(n));
+                                    pass.record("entries", std::mem::take(&mut entries));
+                                    inventory_map.fastrmrf_files(
+                                        &jobs,
+                                        &stats,
+                                        on_deleted.as_deref(),
+                                        &mut deleter,
+                                        &pass,
+                                    );
+                                    // slowrmrf, the last thread done sweeps
+                                    let epoch = epoch.take().unwrap_or_default();
+                                    let sweeping = jobs.thread_done(armed, epoch);
+                                    sweep_jobs(sweeping, &jobs, &stats, &deleter);
+                                }
                             }
                         }
-                    }
-                })
-                .map(|_| Ok(()))?
+                    })?,
+            );
+            Ok(())
         })?;
 
-        Ok(Arc::new(Inventory {}))
+        Ok(Arc::new(Inventory {
+            control,
+            threads: Mutex::new(handles),
+            jobs,
+            stats,
+            pauses,
+        }))
+    }
+
+    /// Changes the early delete threshold in all inventory threads. Each thread applies it
+    /// between two messages.
+    pub(crate) fn set_early_delete_percent(&self, percent: metadata_types::blkcnt_t) {
+        self.control.iter().for_each(|control| {
+            let _ = control.send(InventoryControl::EarlyDeletePercent(percent));
+        });
+    }
+
+    /// Returns the registry of deletion jobs.
+    pub(crate) fn jobs(&self) -> &Arc<Jobs> {
+        &self.jobs
+    }
+
+    /// Returns the deletion and error counters.
+    pub(crate) fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Returns the registry of paused devices.
+    pub(crate) fn pauses(&self) -> &Arc<Pauses> {
+        &self.pauses
+    }
+
+    /// Tells all inventory threads to terminate and waits for them. Threads waiting for a
+    /// paused device give up.
+    pub(crate) fn shutdown(&self) {
+        self.pauses.shutdown();
+        self.control.iter().for_each(|control| {
+            let _ = control.send(InventoryControl::Shutdown);
+        });
+        self.threads.lock().drain(..).for_each(|handle| {
+            let _ = handle.join();
+        });
+    }
+}
+
+/// Deletes the files of one inventory thread. The path and name buffers are reused for every
+/// file, once they grew to the longest path deleting a file does not allocate anymore. When
+/// the disk is full memory is often tight as well.
+#[derive(Debug)]
+struct Deleter {
+    anchors:     Arc<Anchors>,
+    pauses:      Arc<Pauses>,
+    armed:       bool,
+    path:        PathBuf,
+    name:        Vec<u8>,
+    /// The parent directory opened last, files next to each other share it.
+    parent:      Option<Dir>,
+    /// The path of 'parent', a buffer like 'path'.
+    parent_path: PathBuf,
+}
+
+impl Deleter {
+    /// Creates a Deleter opening everything below the 'anchors', files are only really
+    /// deleted when 'armed'. It waits while the device of a file is paused in 'pauses'.
+    fn new(anchors: Arc<Anchors>, pauses: Arc<Pauses>, armed: bool) -> Deleter {
+        Deleter {
+            anchors,
+            pauses,
+            armed,
+            path: PathBuf::with_capacity(libc::PATH_MAX as usize),
+            name: Vec::with_capacity(libc::PATH_MAX as usize),
+            parent: None,
+            parent_path: PathBuf::with_capacity(libc::PATH_MAX as usize),
+        }
+    }
+
+    /// Opens the parent directory of the file in the path buffer below the anchors, reuses
+    /// the one opened last when it is the same. Returns it with the name of the file.
+    fn open_parent(&mut self) -> io::Result<(&Dir, &CStr)> {
+        let Deleter {
+            anchors,
+            path,
+            name: buf,
+            parent: cached,
+            parent_path,
+            ..
+        } = self;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        };
+        if cached.is_none() || parent_path != parent {
+            *cached = None;
+            let dir = anchors.open_dir(parent, buf)?;
+            parent_path.clear();
+            parent_path.push(parent);
+            *cached = Some(dir);
+        }
+        let dir = cached.as_ref().expect("opened above");
+        Ok((dir, cstr(buf, name)?))
+    }
+
+    /// Closes the parent directory kept open, at the end of a pass.
+    fn close_parent(&mut self) {
+        self.parent = None;
+    }
+
+    /// Deletes the file 'path' with the inode 'ino' on 'device' when armed, otherwise only
+    /// pretends to. Returns 'true' when the file is gone now. Failures are logged and counted,
+    /// the file is not retried, except when its NFS file handle went stale or its device
+    /// went read-only or out of space, then the device is paused until it is resumed. When the
+    /// path
+    /// does not refer to the scanned inode anymore it is skipped and reported as
+    /// RmrfdError::Replaced. Silly renamed NFS files are left to the sweep. The file is
+    /// unlinked relative to its parent directory opened below the anchors, symlinks on the
+    /// way are never followed. Failures count against the error budget of the 'jobs' the file
+    /// belongs to.
+    fn delete_file(
+        &mut self,
+        path: &Arc<ObjectPath>,
+        device: metadata_types::dev_t,
+        ino: metadata_types::ino_t,
+        stats: &Stats,
+        jobs: &Jobs,
+    ) -> bool {
+        if !self.armed {
+            return true;
+        }
+        path.write_pathbuf(&mut self.path);
+        if self.path.file_name().is_some_and(nfs::is_silly_renamed) {
+            trace!("silly renamed, left to the sweep: {:?}", self.path.escaped());
+            return false;
+        }
+        let mut retries = 0;
+        let error = loop {
+            match self.pauses.wait(device).and_then(|()| self.unlink(path, device, ino, stats)) {
+                Ok(true) => return true,
+                Ok(false) => break RmrfdError::Replaced(self.path.clone()),
+                Err(err) if nfs::is_stale(&err) && retries < nfs::STALE_RETRIES => {
+                    retries += 1;
+                    self.close_parent();
+                    debug!("stale file handle, retry {}: {:?}", retries, self.path.escaped());
+                }
+                Err(err) => match PauseReason::of(&err) {
+                    Some(reason) => self.pauses.pause(device, reason, &self.path),
+                    None => break self.delete_error(err),
+                },
+            }
+        };
+        warn!("{}", error);
+        jobs.events().emit(Event::Error { error: &error });
+        jobs.failed(&error);
+        stats.error();
+        false
+    }
+
+    /// Unlinks the file 'path' which is in the path buffer already, when it is still the
+    /// inode 'ino' on 'device'. Returns 'false' when it is not.
+    fn unlink(
+        &mut self,
+        path: &Arc<ObjectPath>,
+        device: metadata_types::dev_t,
+        ino: metadata_types::ino_t,
+        stats: &Stats,
+    ) -> io::Result<bool> {
+        let (dir, name) = self.open_parent()?;
+        let metadata = dir.metadata(name)?;
+        if metadata.dev() != Some(device) || metadata.ino() != Some(ino) {
+            return Ok(false);
+        }
+        watchdog::busy(Item::Object(path.clone()));
+        let start = Instant::now();
+        let result = dir.remove_file(name);
+        let elapsed = start.elapsed();
+        watchdog::idle();
+        stats.unlinked(device, elapsed);
+        profile::record(Syscall::Unlink, elapsed);
+        result.map(|()| true)
+    }
+
+    /// Creates the Delete error for the file in the path buffer, relative to its parent
+    /// directory when unlinking got as far as opening it.
+    fn delete_error(&self, error: io::Error) -> RmrfdError {
+        let path = self.path.clone();
+        match (&self.parent, self.path.file_name()) {
+            (Some(dir), Some(name)) if self.path.parent() == Some(&self.parent_path) => {
+                RmrfdError::delete_in(dir, name, path, error)
+            }
+            _ => RmrfdError::delete(path, error),
+        }
+    }
+
+    /// Returns the number of links of the file 'path', looked up like delete_file() does.
+    fn nlink(&mut self, path: &ObjectPath) -> Option<metadata_types::nlink_t> {
+        path.write_pathbuf(&mut self.path);
+        let (dir, name) = self.open_parent().ok()?;
+        dir.metadata(name).ok()?.nlink()
+    }
+}
+
+/// Phase two of the jobs in 'sweeping', removes everything the inventory left over. The
+/// jobs of different users are swept by turns according to their shares.
+fn sweep_jobs(sweeping: Vec<JobHandle>, jobs: &Jobs, stats: &Stats, deleter: &Deleter) {
+    let Deleter {
+        anchors, pauses, ..
+    } = deleter;
+    let sweeping = jobs
+        .shares()
+        .interleave(sweeping.into_iter().map(|job| (job.uid(), job)).collect());
+    for job in sweeping {
+        debug!("slowrmrf {:?}", job.path().escaped());
+        let events = jobs.events();
+        let on_unlink = |dev, latency| stats.unlinked(dev, latency);
+        let on_error = |error: &RmrfdError, removed| job.failed(error, removed);
+        let remove_root = !job.keep_root();
+        let mount = job.mount();
+        match sweep(anchors, pauses, job.path(), mount, remove_root, events, &on_unlink, &on_error)
+        {
+            Ok(totals) => {
+                phase_span(job.span(), "sweep", totals.dev, Some(job.path()))
+                    .record("files", totals.files)
+                    .record("bytes", totals.bytes);
+                job.deleted(totals.files, totals.bytes);
+                stats.deleted(totals.dev, totals.files, totals.bytes);
+                stats.dirs_deleted(totals.dev, totals.dirs);
+                job.dirs_removed(totals.dirs);
+                stats.errors(totals.errors);
+            }
+            Err(err) => {
+                let error = RmrfdError::delete(job.path().to_path_buf(), err);
+                warn!("{}", error);
+                jobs.events().emit(Event::Error { error: &error });
+                job.failed(&error, 0);
+                stats.error();
+            }
+        }
+        job.finish();
     }
 }
 
@@ -87,31 +519,87 @@ impl InventoryMap {
         }
     }
 
-    fn fastrmrf_files(&mut self) {
+    fn fastrmrf_files(
+        &mut self,
+        jobs: &Jobs,
+        stats: &Stats,
+        on_deleted: Option<&DeleteCallback>,
+        deleter: &mut Deleter,
+        pass: &Span,
+    ) {
         // PLANNED: one thread per device
         for device in self.devices() {
             debug!("start fastrmrf for dev {}", device);
+            let span = phase_span(pass, "fastrmrf", device, None);
+            let (mut files, mut freed) = (0, 0);
+            let buckets = self.map.get_mut(&device).unwrap();
+            let mut keys: Vec<ObjectKey> = buckets.keys().rev().copied().collect();
+            // largest first, by turns of the users when several have jobs running
+            if jobs.users() > 1 {
+                keys = jobs.shares().interleave(
+                    keys.into_iter()
+                        .map(|key| {
+                            let uid = buckets[&key].first().and_then(|path| jobs.uid_of(path));
+                            (uid, key)
+                        })
+                        .collect(),
+                );
+            }
             // delete all elements where all hardlinks are collected
-            self.map
-                .get_mut(&device)
-                .unwrap()
-                .iter_mut()
-                .rev()
-                .filter(|(_, object_list)| {
-                    object_list
-                        .first()
-                        .and_then(|f| f.metadata().ok())
-                        .and_then(|m| m.nlink())
-                        == Some(object_list.len() as metadata_types::nlink_t)
-                })
-                .for_each(|(_, object_list)| {
-                    object_list.ditch(|object| {
-                        // TODO: REALLY DELETE
-                        trace!("fast delete {:?}", object);
-                        // PLANNED: accounting, sum up memory freed
-                        true
-                    });
+            keys.into_iter().for_each(|key| {
+                let Some(object_list) = buckets.get_mut(&key) else {
+                    return;
+                };
+                let links = object_list.len();
+                stats.dequeued(links as u64);
+                let first = object_list.first().cloned();
+                if first.as_deref().and_then(|first| deleter.nlink(first))
+                    != Some(links as metadata_types::nlink_t)
+                {
+                    // Links outside of the pass, dropped from the inventory, the sweep
+                    // deletes the ones inside a job.
+                    if let Some(first) = first {
+                        trace!("links left to the sweep: {:?}", first.display());
+                    }
+                    object_list.ditch(|_| true);
+                    return;
+                }
+                let mut deleted = 0;
+                object_list.ditch(|object| {
+                    if jobs.is_cancelled(object) {
+                        trace!("cancelled {:?}", object.display());
+                        return true;
+                    }
+                    trace!("fast delete {:?}", object.display());
+                    if !deleter.delete_file(object, device, key.ino, stats, jobs) {
+                        return true;
+                    }
+                    jobs.deleted(object, 1, 0);
+                    deleted += 1;
+                    if let Some(on_deleted) = on_deleted {
+                        on_deleted(&DeletedFile {
+                            path:   object,
+                            blocks: key.blocks,
+                            dev:    device,
+                            ino:    key.ino,
+                        });
+                    }
+                    true
                 });
+                // the space is only freed when the last link is gone
+                let bytes = match first {
+                    Some(first) if deleted == links => {
+                        let bytes = blocks_to_bytes(key.blocks);
+                        jobs.deleted(&first, 0, bytes);
+                        bytes
+                    }
+                    _ => 0,
+                };
+                stats.deleted(device, deleted as u64, bytes);
+                files += deleted as u64;
+                freed += bytes;
+            });
+            span.record("files", files).record("bytes", freed);
 
             // prune all unused objectmaps with empty objectlists
             self.map
@@ -119,6 +607,7 @@ impl InventoryMap {
                 .unwrap()
                 .retain(|_, objectlist| !objectlist.is_empty());
         }
+        deleter.close_parent();
     }
 
     /// Returns a HashSet of all known device identifiers.
@@ -133,11 +622,12 @@ impl InventoryMap {
     }
 
     // Insert the given path, using the supplied metadata to determine where the path will be stored.
+    // Returns 'true' when the path was not already in the inventory.
     pub fn insert_with_metadata(
         &mut self,
         path: Arc<ObjectPath>,
         metadata: &Metadata,
-    ) -> io::Result<()> {
+    ) -> io::Result<bool> {
         let key = ObjectKey::try_from(metadata)
             .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
 
@@ -148,15 +638,14 @@ impl InventoryMap {
                     .dev()
                     .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?,
             )
-            .or_insert(BTreeMap::new());
+            .or_default();
 
         // and get/create the objectlist, insert path
-        map.entry(key).or_insert_with(ObjectList::new).insert(path);
-
-        Ok(())
+        Ok(map.entry(key).or_default().insert(path))
     }
 
     /// Remove the given path under the supplied metadata from the inventory.
+    #[cfg(test)]
     pub fn remove_with_metadata(
         &mut self,
         path: Arc<ObjectPath>,
@@ -180,6 +669,7 @@ impl InventoryMap {
     }
 
     /// Checks if the given path/metadata exists in the inventory.
+    #[cfg(test)]
     pub fn contains_with_metadata(&self, path: Arc<ObjectPath>, metadata: &Metadata) -> bool {
         ObjectKey::try_from(metadata)
             .and_then(|key| Some(self.map[&metadata.dev()?].get(&key)?.contains(path)))
@@ -188,13 +678,15 @@ impl InventoryMap {
 
     /// Insert existing path (on filesystem) into the inventory. Retrives the metadata from the
     /// path.  Will result in an error when the metadata of the given path can't be retrieved.
+    #[cfg(test)]
     pub fn insert(&mut self, path: Arc<ObjectPath>) -> io::Result<()> {
         let metadata = path.metadata()?;
-        self.insert_with_metadata(path, &metadata)
+        self.insert_with_metadata(path, &metadata).map(|_| ())
     }
 
     /// Remove existing path (on filesystem) from the inventory. Retrives the metadata from the
     /// path.  Will result in an error when the metadata of the given path can't be retrieved.
+    #[cfg(test)]
     pub fn remove(&mut self, path: Arc<ObjectPath>) -> io::Result<()> {
         let metadata = path.metadata()?;
         self.remove_with_metadata(path, &metadata)
@@ -203,6 +695,7 @@ impl InventoryMap {
     /// Check if an existing path (on filesystem) exists in the inventory. Retrives the
     /// metadata from the path.  Will return false when the metadata of the given path can't be
     /// retrieved.
+    #[cfg(test)]
     pub fn contains(&self, path: Arc<ObjectPath>) -> bool {
         path.metadata()
             .map(|metadata| self.contains_with_metadata(path, &metadata))
@@ -211,7 +704,7 @@ impl InventoryMap {
 }
 
 /// Objects are looked up by size and inode number combined here.
-#[derive(Debug, Eq)]
+#[derive(Debug, Clone, Copy, Eq)]
 pub struct ObjectKey {
     blocks: metadata_types::blkcnt_t,
     ino:    metadata_types::ino_t,
@@ -261,7 +754,7 @@ impl PartialEq for ObjectKey {
 
 #[cfg(test)]
 mod tests {
-    use crossbeam_channel::bounded;
+    use std::fs;
 
     use super::*;
 
@@ -270,26 +763,157 @@ mod tests {
         crate::tests::init_env_logging();
 
         let mut inventory_map = InventoryMap::new();
-        inventory_map.insert(ObjectPath::new("Cargo.toml"));
+        inventory_map.insert(ObjectPath::new("Cargo.toml")).unwrap();
         assert!(inventory_map.contains(ObjectPath::new("Cargo.toml")));
         assert!(!inventory_map.contains(ObjectPath::new("src/lib.rs")));
     }
 
+    #[test]
+    fn delete_replaced() {
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-replaced-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"scanned").unwrap();
+        fs::write(dir.join("other"), b"replacement").unwrap();
+        let path = ObjectPath::new(dir.join("file"));
+        let metadata = path.metadata().unwrap();
+        let (dev, ino) = (metadata.dev().unwrap(), metadata.ino().unwrap());
+
+        fs::rename(dir.join("other"), dir.join("file")).unwrap();
+        let stats = Stats::new();
+        let jobs = Jobs::new(1, Arc::default());
+        let anchors = Anchors::new([dir.as_path()].into_iter()).unwrap();
+        let mut deleter = Deleter::new(Arc::new(anchors), Pauses::new(Arc::default()), true);
+        assert!(!deleter.delete_file(&path, dev, ino, &stats, &jobs));
+        assert!(dir.join("file").exists());
+
+        let ino = path.metadata().unwrap().ino().unwrap();
+        assert!(deleter.delete_file(&path, dev, ino, &stats, &jobs));
+        assert!(!dir.join("file").exists());
+        assert_eq!(stats.snapshot((0, 0)).errors, 1);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete_siblings() {
+        use std::os::unix::io::AsRawFd;
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-siblings-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let stats = Stats::new();
+        let jobs = Jobs::new(1, Arc::default());
+        let anchors = Anchors::new([dir.as_path()].into_iter()).unwrap();
+        let mut deleter = Deleter::new(Arc::new(anchors), Pauses::new(Arc::default()), true);
+        let mut delete = |name: &str| {
+            fs::write(dir.join(name), b"file").unwrap();
+            let path = ObjectPath::new(dir.join(name));
+            let metadata = path.metadata().unwrap();
+            let (dev, ino) = (metadata.dev().unwrap(), metadata.ino().unwrap());
+            assert!(deleter.delete_file(&path, dev, ino, &stats, &jobs));
+            let fd = deleter.parent.as_ref().unwrap().as_raw_fd();
+            (deleter.parent_path.clone(), fd)
+        };
+
+        let (parent, fd) = delete("a");
+        assert_eq!(parent, dir);
+        assert_eq!(delete("b"), (parent, fd));
+        assert_eq!(delete("sub/c").0, dir.join("sub"));
+        deleter.close_parent();
+        assert!(deleter.parent.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fastrmrf_shares() {
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-shares-{}", std::process::id()));
+        let jobs = Jobs::new(1, Arc::default());
+        jobs.shares().set([(1000, 2)]);
+        let mut inventory_map = InventoryMap::new();
+        for (user, uid) in [("a", 1000), ("b", 1001)] {
+            fs::create_dir_all(dir.join(user)).unwrap();
+            let job_dir = ObjectPath::new(dir.join(user));
+            jobs.submit(&job_dir, Some(uid), false, Default::default(), 0, 0);
+            // distinct sizes, the largest file of 'a' comes first
+            for n in 0..4 {
+                let path = dir.join(user).join(n.to_string());
+                let size = (n + 1) * 8192 + if user == "a" { 4096 } else { 0 };
+                fs::write(&path, vec![1; size]).unwrap();
+                inventory_map.insert(ObjectPath::new(path)).unwrap();
+            }
+        }
+
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let on_deleted = {
+            let deleted = deleted.clone();
+            move |file: &DeletedFile| {
+                let path = file.path.to_pathbuf();
+                let user = path.parent().unwrap().file_name().unwrap();
+                deleted.lock().push(user.to_string_lossy().into_owned());
+            }
+        };
+        let stats = Stats::new();
+        stats.queued(8);
+        let anchors = Anchors::new([dir.as_path()].into_iter()).unwrap();
+        let mut deleter = Deleter::new(Arc::new(anchors), Pauses::new(Arc::default()), true);
+        inventory_map.fastrmrf_files(
+            &jobs,
+            &stats,
+            Some(&on_deleted),
+            &mut deleter,
+            &pass_span(0),
+        );
+        assert_eq!(
+            deleted.lock().concat(),
+            "aabaabbb",
+            "two files of 'a' for every one of 'b'"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fastrmrf_hardlinks() {
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-hardlinks-{}", std::process::id()));
+        fs::create_dir_all(dir.join("job")).unwrap();
+        fs::write(dir.join("job/file"), vec![1; 8192]).unwrap();
+        fs::hard_link(dir.join("job/file"), dir.join("link")).unwrap();
+        let path = ObjectPath::new(dir.join("job/file"));
+        let jobs = Jobs::new(1, Arc::default());
+        jobs.submit(&ObjectPath::new(dir.join("job")), None, false, Default::default(), 0, 0);
+        let mut inventory_map = InventoryMap::new();
+        inventory_map.insert(path.clone()).unwrap();
+
+        let stats = Stats::new();
+        stats.queued(1);
+        let anchors = Anchors::new([dir.as_path()].into_iter()).unwrap();
+        let mut deleter = Deleter::new(Arc::new(anchors), Pauses::new(Arc::default()), true);
+        inventory_map.fastrmrf_files(&jobs, &stats, None, &mut deleter, &pass_span(0));
+        assert!(dir.join("job/file").exists());
+        assert!(!inventory_map.contains(path));
+        assert_eq!(stats.pending(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn insert_remove() {
         crate::tests::init_env_logging();
 
         let mut inventory_map = InventoryMap::new();
 
-        inventory_map.insert(ObjectPath::new("Cargo.toml"));
-        inventory_map.insert(ObjectPath::new("Cargo.toml"));
-        inventory_map.insert(ObjectPath::new("src/lib.rs"));
+        inventory_map.insert(ObjectPath::new("Cargo.toml")).unwrap();
+        inventory_map.insert(ObjectPath::new("Cargo.toml")).unwrap();
+        inventory_map.insert(ObjectPath::new("src/lib.rs")).unwrap();
         assert!(inventory_map.contains(ObjectPath::new("Cargo.toml")));
         assert!(inventory_map.contains(ObjectPath::new("src/lib.rs")));
 
-        inventory_map.remove(ObjectPath::new("Cargo.toml"));
+        inventory_map.remove(ObjectPath::new("Cargo.toml")).unwrap();
         assert!(!inventory_map.contains(ObjectPath::new("Cargo.toml")));
-        inventory_map.remove(ObjectPath::new("src/lib.rs"));
+        inventory_map.remove(ObjectPath::new("src/lib.rs")).unwrap();
         assert!(!inventory_map.contains(ObjectPath::new("src/lib.rs")));
     }
 }
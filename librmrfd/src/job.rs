@@ -0,0 +1,927 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use dirinventory::ObjectPath;
+use parking_lot::{Condvar, Mutex};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::RmrfdError;
+use crate::atomicstats::Counter;
+use crate::completion::{JobReport, Skipped, SKIPPED_MAX};
+use crate::events::{Event, EventLog};
+use crate::shares::Shares;
+use crate::platform::{free_bytes, fs_type, FsType, Mount};
+use crate::profile;
+use crate::trace::{job_span, job_state, Span};
+use crate::pathdisplay::PathEscape;
+
+/// State of a deletion job. Jobs are deleted in two phases, first the inventory is gathered
+/// and the largest files are deleted in size order ('Running'), then the remaining small
+/// files and the directories are removed by a plain recursive sweep ('Sweeping').
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Phase one, the directory is gathered and its big files are deleted.
+    Running,
+    /// Phase two, everything left is swept. Only when armed and can't be cancelled.
+    Sweeping,
+    /// Both phases completed.
+    Done,
+    /// The job was cancelled, nothing more below its directory gets deleted.
+    Cancelled,
+    /// Too many operations of the job failed, see ErrorBudget. Nothing more below its
+    /// directory gets deleted, JobHandle::errors() tells what went wrong.
+    Aborted,
+}
+
+/// Limit on the failures of a job, see RmrfdBuilder::with_error_budget() and
+/// JobHandle::set_error_budget(). A bad disk or a permission mess would otherwise produce an
+/// error for every single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorBudget {
+    percent:        u64,
+    min_operations: u64,
+}
+
+impl ErrorBudget {
+    /// Aborts a job when more than 'percent' of its operations failed. This is judged only
+    /// after 1000 operations, see with_min_operations().
+    pub fn new(percent: u64) -> Self {
+        ErrorBudget {
+            percent,
+            min_operations: 1000,
+        }
+    }
+
+    /// Judges the budget only after 'n' operations, a few early failures don't abort a job.
+    pub fn with_min_operations(mut self, n: u64) -> Self {
+        self.min_operations = n;
+        self
+    }
+
+    /// Returns 'true' when 'errors' of 'operations' are over the budget.
+    fn exceeded(&self, errors: u64, operations: u64) -> bool {
+        operations >= self.min_operations && errors * 100 > operations * self.percent
+    }
+}
+
+/// The failures of a job, see JobHandle::errors().
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorSummary {
+    /// Operations attempted, the failed ones and the deletions counted by
+    /// JobHandle::progress().
+    pub operations: u64,
+    /// Operations which failed.
+    pub errors:     u64,
+    /// The message of the last error.
+    pub last:       Option<String>,
+}
+
+/// What a job freed on its filesystem, see JobHandle::space(). Snapshots, reflinks and files
+/// still open elsewhere keep deleted blocks allocated, some filesystems release blocks in the
+/// background and other writers change the free space as well, 'fs_type' tells about the
+/// filesystems known for that. The deleted bytes include the work of other jobs running at
+/// the same time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// Bytes of the deleted files, by their block counts.
+    pub deleted_bytes: u64,
+    /// How much the free space of the filesystem grew while the job ran, negative when it
+    /// shrank.
+    pub freed_bytes:   i64,
+    /// The kind of filesystem the job deleted on.
+    pub fs_type:       FsType,
+}
+
+impl SpaceReport {
+    /// Bytes deleted but not freed, negative when the filesystem gained more.
+    pub fn discrepancy(&self) -> i64 {
+        self.deleted_bytes as i64 - self.freed_bytes
+    }
+}
+
+/// A directory submitted for deletion.
+#[derive(Debug)]
+struct Job {
+    path:          PathBuf,
+    state:         Mutex<JobState>,
+    changed:       Condvar,
+    started:       Instant,
+    scanned_start: u64,
+    /// Free bytes of the filesystem at submission.
+    free_start:    Option<u64>,
+    fs_type:       FsType,
+    space:         Mutex<Option<SpaceReport>>,
+    uid:           Option<libc::uid_t>,
+    keep_root:     bool,
+    mount:         Mount,
+    span:          Span,
+    budget:        Mutex<Option<ErrorBudget>>,
+    errors:        Counter,
+    last_error:    Mutex<Option<String>>,
+    skipped:       Mutex<Vec<Skipped>>,
+    /// Files deleted below the path of the job.
+    deleted:       Counter,
+    /// Bytes of the files deleted below the path of the job.
+    freed:         Counter,
+    /// Directories removed by the sweep.
+    dirs:          Counter,
+    /// Files below the min_blockcount threshold left to the sweep.
+    small_files:   Counter,
+    /// Bytes of 'small_files'.
+    small_bytes:   Counter,
+    /// The epoch of the gatherer the job was queued in, see DirsQueue.
+    epoch:         u64,
+    report:        Mutex<Option<JobReport>>,
+    #[cfg(feature = "async")]
+    watch:         tokio::sync::watch::Sender<JobState>,
+}
+
+impl Job {
+    /// Changes the state of an active job. Once it is not active anymore its JobReport is
+    /// made.
+    fn set_state(&self, state: JobState, jobs: &Jobs) -> bool {
+        self.set_state_if(JobState::is_active, state, jobs)
+    }
+
+    /// Changes the state to 'state' when 'from' accepts the current active one, returns
+    /// 'true' when it did. A job stopped in phase one is counted as cancelled under the same
+    /// lock, concurrent callers can't count it twice.
+    fn set_state_if(
+        &self,
+        from: impl FnOnce(JobState) -> bool,
+        state: JobState,
+        jobs: &Jobs,
+    ) -> bool {
+        let mut current = self.state.lock();
+        let changed = current.is_active() && from(*current);
+        if changed {
+            // jobs in phase two are not tracked anymore
+            if *current == JobState::Running && state.is_stopped() {
+                jobs.cancelled.fetch_add(1, Ordering::SeqCst);
+            }
+            *current = state;
+            job_state(&self.span, state);
+            jobs.events.emit(Event::Job {
+                path: &self.path,
+                uid: self.uid,
+                state,
+                space: *self.space.lock(),
+            });
+            if !state.is_active() {
+                let report = self.report(state);
+                jobs.events.emit(Event::Report { report: &report });
+                *self.report.lock() = Some(report);
+            }
+            self.changed.notify_all();
+            #[cfg(feature = "async")]
+            self.watch.send_replace(state);
+            if state == JobState::Done {
+                profile::report(&self.path);
+            }
+        }
+        changed
+    }
+
+    /// Returns the report of the job which ended in 'state'.
+    fn report(&self, state: JobState) -> JobReport {
+        let errors = self.errors.get();
+        let deleted_files = self.deleted.get();
+        JobReport {
+            path: self.path.clone(),
+            uid: self.uid,
+            state,
+            duration: self.started.elapsed(),
+            deleted_files,
+            deleted_bytes: self.freed.get(),
+            removed_dirs: self.dirs.get(),
+            small_files: self.small_files.get(),
+            small_bytes: self.small_bytes.get(),
+            space: *self.space.lock(),
+            errors: ErrorSummary {
+                operations: deleted_files + errors,
+                errors,
+                last: self.last_error.lock().clone(),
+            },
+            skipped: self.skipped.lock().clone(),
+        }
+    }
+}
+
+/// Progress of a job, see Rmrfd::progress(). All numbers count since the job was submitted.
+/// Entries are scanned for all jobs together, 'scanned' includes the work of other jobs
+/// running at the same time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Directory entries whose metadata was fetched.
+    pub scanned:         u64,
+    /// Files deleted.
+    pub deleted_files:   u64,
+    /// Bytes freed by the deleted files.
+    pub deleted_bytes:   u64,
+    /// Files below the min_blockcount threshold left to the sweep, see Rmrfd::small_files().
+    pub small_files:     u64,
+    /// Bytes of 'small_files'.
+    pub small_bytes:     u64,
+    /// Estimated number of entries, the scanned ones plus those waiting to be stat()ed. Grows
+    /// while directories are still being listed.
+    pub estimated_total: u64,
+}
+
+/// Handle to a directory submitted with Rmrfd::delete_dir().
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    job:  Arc<Job>,
+    jobs: Arc<Jobs>,
+}
+
+impl JobState {
+    /// Returns 'true' while the job is in one of its phases.
+    pub fn is_active(self) -> bool {
+        matches!(self, JobState::Running | JobState::Sweeping)
+    }
+
+    /// Returns 'true' when the job was cancelled or aborted.
+    fn is_stopped(self) -> bool {
+        matches!(self, JobState::Cancelled | JobState::Aborted)
+    }
+}
+
+impl JobHandle {
+    /// Returns the directory this job deletes.
+    pub fn path(&self) -> &Path {
+        &self.job.path
+    }
+
+    /// Returns 'true' when the sweep has to leave the directory itself in place, it is a
+    /// rmrf or per user directory.
+    pub(crate) fn keep_root(&self) -> bool {
+        self.job.keep_root
+    }
+
+    /// Returns the mount the directory of the job was on when it was submitted, the job
+    /// never deletes anything on another filesystem or bind mount.
+    pub(crate) fn mount(&self) -> Mount {
+        self.job.mount
+    }
+
+    /// Returns the tracing span of the job.
+    pub(crate) fn span(&self) -> &Span {
+        &self.job.span
+    }
+
+    /// Completes a job after its sweep.
+    pub(crate) fn finish(&self) {
+        self.jobs.complete(&self.job);
+    }
+
+    /// Returns the user this job is attributed to, see Rmrfd::delete_dir_as().
+    pub fn uid(&self) -> Option<libc::uid_t> {
+        self.job.uid
+    }
+
+    /// Returns the current state of the job.
+    pub fn state(&self) -> JobState {
+        *self.job.state.lock()
+    }
+
+    /// Blocks until the job is done, cancelled or aborted.
+    pub fn wait(&self) -> JobState {
+        let mut state = self.job.state.lock();
+        while state.is_active() {
+            self.job.changed.wait(&mut state);
+        }
+        *state
+    }
+
+    /// Blocks until the job is done or cancelled or 'timeout' elapsed, returns the state.
+    pub fn wait_timeout(&self, timeout: Duration) -> JobState {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.job.state.lock();
+        while state.is_active()
+            && !self.job.changed.wait_until(&mut state, deadline).timed_out()
+        {}
+        *state
+    }
+
+    /// Waits asynchronously until the job is done or cancelled.
+    #[cfg(feature = "async")]
+    pub async fn wait_async(&self) -> JobState {
+        let mut watch = self.job.watch.subscribe();
+        let result = watch.wait_for(|state| !state.is_active()).await;
+        result.map_or_else(|_| self.state(), |state| *state)
+    }
+
+    /// Returns the number of objects deleted below the directory of the job so far.
+    pub fn progress(&self) -> u64 {
+        self.job.deleted.get()
+    }
+
+    /// Returns the Progress of this job given the total number of entries 'scanned' so far
+    /// and the number of entries still 'pending'.
+    pub(crate) fn progress_report(&self, scanned: u64, pending: u64) -> Progress {
+        let scanned = scanned.saturating_sub(self.job.scanned_start);
+        Progress {
+            scanned,
+            deleted_files:   self.progress(),
+            deleted_bytes:   self.job.freed.get(),
+            small_files:     self.job.small_files.get(),
+            small_bytes:     self.job.small_bytes.get(),
+            estimated_total: scanned + pending,
+        }
+    }
+
+    /// Stops deleting objects below the directory of this job. Objects already deleted are
+    /// gone. Only jobs in phase one can be cancelled.
+    pub fn cancel(&self) {
+        self.job
+            .set_state_if(|state| state == JobState::Running, JobState::Cancelled, &self.jobs);
+    }
+
+    /// Returns how the deleted bytes compare to the space freed on the filesystem once the
+    /// job is done. None before and when the free space could not be determined.
+    pub fn space(&self) -> Option<SpaceReport> {
+        *self.job.space.lock()
+    }
+
+    /// Sets the ErrorBudget of this job, overriding the one from
+    /// RmrfdBuilder::with_error_budget(). With None the job never aborts.
+    pub fn set_error_budget(&self, budget: Option<ErrorBudget>) {
+        *self.job.budget.lock() = budget;
+    }
+
+    /// Returns a summary of the failed operations of the job.
+    pub fn errors(&self) -> ErrorSummary {
+        let errors = self.job.errors.get();
+        ErrorSummary {
+            operations: self.progress() + errors,
+            errors,
+            last: self.job.last_error.lock().clone(),
+        }
+    }
+
+    /// Returns the JobReport once the job is done, cancelled or aborted.
+    pub fn report(&self) -> Option<JobReport> {
+        self.job.report.lock().clone()
+    }
+
+    /// Accounts 'files' files which freed 'bytes' deleted by the sweep of the job.
+    pub(crate) fn deleted(&self, files: u64, bytes: u64) {
+        self.jobs.deleted.add(files);
+        self.jobs.freed.add(bytes);
+        self.job.deleted.add(files);
+        self.job.freed.add(bytes);
+    }
+
+    /// Accounts 'dirs' directories removed by the sweep of the job.
+    pub(crate) fn dirs_removed(&self, dirs: u64) {
+        self.job.dirs.add(dirs);
+    }
+
+    /// Accounts a failed operation of the job, 'unaccounted' are the successful operations
+    /// not counted by progress() yet. Aborts the job when this exhausts its ErrorBudget.
+    /// Returns 'false' when the job should not go on.
+    pub(crate) fn failed(&self, error: &RmrfdError, unaccounted: u64) -> bool {
+        self.jobs.job_failed(&self.job, error, unaccounted)
+    }
+}
+
+/// Tracks the running jobs of the inventory. Jobs are completed when all inventory threads
+/// finished a pass which gathered the job completely.
+#[derive(Debug)]
+pub(crate) struct Jobs {
+    jobs:         Mutex<Vec<Arc<Job>>>,
+    threads:      usize,
+    threads_done: AtomicUsize,
+    /// The lowest epoch the threads done with the current pass reported.
+    pass_epoch:   AtomicU64,
+    deleted:      Counter,
+    freed:        Counter,
+    small_files:  Counter,
+    small_bytes:  Counter,
+    cancelled:    AtomicUsize,
+    events:       Arc<EventLog>,
+    shares:       Shares,
+}
+
+impl Jobs {
+    /// Creates the job registry for an inventory with 'threads' threads. State changes are
+    /// written to 'events'.
+    pub(crate) fn new(threads: usize, events: Arc<EventLog>) -> Arc<Jobs> {
+        Arc::new(Jobs {
+            jobs: Mutex::new(Vec::new()),
+            threads,
+            threads_done: AtomicUsize::new(0),
+            pass_epoch: AtomicU64::new(u64::MAX),
+            deleted: Counter::default(),
+            freed: Counter::default(),
+            small_files: Counter::default(),
+            small_bytes: Counter::default(),
+            cancelled: AtomicUsize::new(0),
+            events,
+            shares: Shares::default(),
+        })
+    }
+
+    /// Returns the event log.
+    pub(crate) fn events(&self) -> &EventLog {
+        &self.events
+    }
+
+    /// Returns the shares of the users the work of their jobs is interleaved by.
+    pub(crate) fn shares(&self) -> &Shares {
+        &self.shares
+    }
+
+    /// Returns how many users have running jobs, jobs not attributed to a user count as one.
+    pub(crate) fn users(&self) -> usize {
+        let mut users: Vec<_> = self
+            .jobs
+            .lock()
+            .iter()
+            .filter(|job| job.state.lock().is_active())
+            .map(|job| job.uid)
+            .collect();
+        users.sort_unstable();
+        users.dedup();
+        users.len()
+    }
+
+    /// Returns the user of the innermost job 'path' is in, None when it is in none or that
+    /// is not attributed to a user.
+    pub(crate) fn uid_of(&self, path: &ObjectPath) -> Option<libc::uid_t> {
+        let jobs = self.jobs.lock();
+        with_path(path, |path| {
+            jobs.iter()
+                .filter(|job| path.starts_with(&job.path))
+                .max_by_key(|job| job.path.as_os_str().len())
+                .and_then(|job| job.uid)
+        })
+    }
+
+    /// Registers a new job for 'path', attributed to 'uid'. With 'keep_root' the sweep leaves
+    /// 'path' itself in place. 'mount' is the mount of 'path'. 'scanned' is the number of
+    /// entries scanned so far, the progress of the job counts from there. 'epoch' is the one
+    /// 'path' was queued in for gathering.
+    pub(crate) fn submit(
+        self: &Arc<Self>,
+        path: &ObjectPath,
+        uid: Option<libc::uid_t>,
+        keep_root: bool,
+        mount: Mount,
+        scanned: u64,
+        epoch: u64,
+    ) -> JobHandle {
+        let path = path.to_pathbuf();
+        let span = job_span(&path, uid);
+        job_state(&span, JobState::Running);
+        self.events.emit(Event::Job {
+            path: &path,
+            uid,
+            state: JobState::Running,
+            space: None,
+        });
+        let free_start = free_bytes(&path).ok();
+        let fs_type = fs_type(&path).unwrap_or_default();
+        let job = Arc::new(Job {
+            path,
+            started:       Instant::now(),
+            state:         Mutex::new(JobState::Running),
+            changed:       Condvar::new(),
+            scanned_start: scanned,
+            free_start,
+            fs_type,
+            space:         Mutex::new(None),
+            uid,
+            keep_root,
+            mount,
+            span,
+            budget:        Mutex::new(None),
+            errors:        Counter::default(),
+            last_error:    Mutex::new(None),
+            skipped:       Mutex::new(Vec::new()),
+            deleted:       Counter::default(),
+            freed:         Counter::default(),
+            dirs:          Counter::default(),
+            small_files:   Counter::default(),
+            small_bytes:   Counter::default(),
+            epoch,
+            report:        Mutex::new(None),
+            #[cfg(feature = "async")]
+            watch:         tokio::sync::watch::channel(JobState::Running).0,
+        });
+        self.jobs.lock().push(job.clone());
+        JobHandle {
+            job,
+            jobs: self.clone(),
+        }
+    }
+
+    /// Accounts 'files' deleted objects which freed 'bytes' to the running jobs 'path' is
+    /// in, like failed() does for errors.
+    pub(crate) fn deleted(&self, path: &ObjectPath, files: u64, bytes: u64) {
+        self.deleted.add(files);
+        self.freed.add(bytes);
+        let jobs = self.jobs.lock();
+        if jobs.is_empty() {
+            return;
+        }
+        with_path(path, |path| {
+            jobs.iter()
+                .filter(|job| path.starts_with(&job.path))
+                .for_each(|job| {
+                    job.deleted.add(files);
+                    job.freed.add(bytes);
+                })
+        });
+    }
+
+    /// Accounts a file of 'bytes' below the min_blockcount threshold to the running jobs
+    /// 'path' is in. It is not inventoried but left to the sweep.
+    pub(crate) fn small_file(&self, path: &ObjectPath, bytes: u64) {
+        self.small_files.add(1);
+        self.small_bytes.add(bytes);
+        let jobs = self.jobs.lock();
+        if jobs.is_empty() {
+            return;
+        }
+        with_path(path, |path| {
+            jobs.iter()
+                .filter(|job| path.starts_with(&job.path))
+                .for_each(|job| {
+                    job.small_files.add(1);
+                    job.small_bytes.add(bytes);
+                })
+        });
+    }
+
+    /// Returns the number and the total size of the files accounted with small_file().
+    pub(crate) fn small_files(&self) -> (u64, u64) {
+        (self.small_files.get(), self.small_bytes.get())
+    }
+
+    /// Returns the jobs not completed yet.
+    pub(crate) fn handles(self: &Arc<Self>) -> Vec<JobHandle> {
+        self.jobs
+            .lock()
+            .iter()
+            .map(|job| JobHandle {
+                job:  job.clone(),
+                jobs: self.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the number of jobs not completed yet.
+    pub(crate) fn running(&self) -> usize {
+        self.jobs.lock().len()
+    }
+
+    /// Checks if 'path' is below the directory of a cancelled or aborted job.
+    pub(crate) fn is_cancelled(&self, path: &ObjectPath) -> bool {
+        if self.cancelled.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let jobs = self.jobs.lock();
+        with_path(path, |path| {
+            jobs.iter()
+                .any(|job| job.state.lock().is_stopped() && path.starts_with(&job.path))
+        })
+    }
+
+    /// Accounts 'error' to the running jobs it happened in, found by the path of the error.
+    pub(crate) fn failed(&self, error: &RmrfdError) {
+        let Some(path) = error.path() else {
+            return;
+        };
+        let jobs: Vec<_> = self
+            .jobs
+            .lock()
+            .iter()
+            .filter(|job| path.starts_with(&job.path))
+            .cloned()
+            .collect();
+        for job in jobs {
+            self.job_failed(&job, error, 0);
+        }
+    }
+
+    /// Completes 'job' and compares what it deleted to what its filesystem freed. The job
+    /// directory may be gone, the free space is taken from its nearest existing parent.
+    fn complete(&self, job: &Job) {
+        let space = job.free_start.and_then(|start| {
+            let end = job.path.ancestors().find_map(|dir| free_bytes(dir).ok())?;
+            Some(SpaceReport {
+                deleted_bytes: job.freed.get(),
+                freed_bytes:   end as i64 - start as i64,
+                fs_type:       job.fs_type,
+            })
+        });
+        if job.fs_type == FsType::Overlay {
+            info!(
+                "job on overlayfs: {:?}: lower layer entries are only hidden, space is freed in \
+                 the upper layer",
+                job.path.escaped()
+            );
+        }
+        match space {
+            Some(space) => info!(
+                "job done: {:?}: {} bytes deleted, {} bytes freed, {} bytes not freed",
+                job.path.escaped(),
+                space.deleted_bytes,
+                space.freed_bytes,
+                space.discrepancy()
+            ),
+            None => debug!("job done: {:?}", job.path.escaped()),
+        }
+        if job.state.lock().is_active() {
+            *job.space.lock() = space;
+        }
+        job.set_state(JobState::Done, self);
+    }
+
+    /// Accounts 'error' to 'job' and aborts it when its ErrorBudget is exhausted. Returns
+    /// 'false' when the job is not active anymore.
+    fn job_failed(&self, job: &Job, error: &RmrfdError, unaccounted: u64) -> bool {
+        job.errors.add(1);
+        let message = error.to_string();
+        if let Some(path) = error.path() {
+            let mut skipped = job.skipped.lock();
+            if skipped.len() < SKIPPED_MAX {
+                skipped.push(Skipped {
+                    path:   path.to_path_buf(),
+                    reason: message.clone(),
+                });
+            }
+        }
+        *job.last_error.lock() = Some(message);
+        let errors = job.errors.get();
+        let operations = job.deleted.get() + errors + unaccounted;
+        let exceeded = job
+            .budget
+            .lock()
+            .is_some_and(|budget| budget.exceeded(errors, operations));
+        if exceeded && job.set_state(JobState::Aborted, self) {
+            warn!(
+                "job aborted: {:?}: {} of {} operations failed, last: {}",
+                job.path.escaped(),
+                errors,
+                operations,
+                error
+            );
+            return false;
+        }
+        job.state.lock().is_active()
+    }
+
+    /// Called by each inventory thread when it finished a pass, with the 'epoch' of the
+    /// gatherer from before it took the last entries of the pass. When all threads are done
+    /// phase one of the jobs queued before the lowest of these epochs is complete, jobs
+    /// submitted later are still gathered and carried over to the next pass. With 'sweep'
+    /// they enter phase two and are returned, the caller has to sweep and finish them.
+    /// Otherwise they are done right away.
+    pub(crate) fn thread_done(self: &Arc<Self>, sweep: bool, epoch: u64) -> Vec<JobHandle> {
+        let mut sweeping = Vec::new();
+        self.pass_epoch.fetch_min(epoch, Ordering::SeqCst);
+        if self.threads_done.fetch_add(1, Ordering::SeqCst) + 1 == self.threads {
+            self.threads_done.store(0, Ordering::SeqCst);
+            let epoch = self.pass_epoch.swap(u64::MAX, Ordering::SeqCst);
+            let mut jobs = self.jobs.lock();
+            let (ended, carried): (Vec<_>, Vec<_>) =
+                jobs.drain(..).partition(|job| job.epoch < epoch);
+            *jobs = carried;
+            for job in ended {
+                let state = *job.state.lock();
+                if state.is_stopped() {
+                    self.cancelled.fetch_sub(1, Ordering::SeqCst);
+                } else if sweep {
+                    debug!("job sweeping: {:?}", job.path.escaped());
+                    job.set_state(JobState::Sweeping, self);
+                    sweeping.push(JobHandle {
+                        job,
+                        jobs: self.clone(),
+                    });
+                } else {
+                    self.complete(&job);
+                }
+            }
+        }
+        sweeping
+    }
+}
+
+thread_local! {
+    /// Paths are rendered here to match them against the jobs, accounting a deleted file
+    /// does not allocate.
+    static PATH: RefCell<PathBuf> = const { RefCell::new(PathBuf::new()) };
+}
+
+/// Calls 'f' with 'path' rendered into the path buffer of the current thread.
+fn with_path<T>(path: &ObjectPath, f: impl FnOnce(&Path) -> T) -> T {
+    PATH.with_borrow_mut(|buf| f(path.write_pathbuf(buf)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(2, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf"), None, false, Mount::default(), 0, 0);
+        assert_eq!(job.state(), JobState::Running);
+        assert!(!jobs.is_cancelled(&ObjectPath::new("/tmp/rmrf/foo")));
+
+        job.cancel();
+        assert!(jobs.is_cancelled(&ObjectPath::new("/tmp/rmrf/foo")));
+        assert!(!jobs.is_cancelled(&ObjectPath::new("/tmp/other")));
+
+        assert!(jobs.thread_done(true, 1).is_empty());
+        assert!(jobs.thread_done(true, 1).is_empty());
+        assert_eq!(job.wait(), JobState::Cancelled);
+        assert!(!jobs.is_cancelled(&ObjectPath::new("/tmp/rmrf/foo")));
+        let report = job.report().unwrap();
+        assert_eq!(report.state, JobState::Cancelled);
+        assert_eq!(report.space, None);
+    }
+
+    #[test]
+    fn cancel_concurrent() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf"), None, false, Mount::default(), 0, 0);
+        job.set_error_budget(Some(ErrorBudget::new(0).with_min_operations(0)));
+        let error = RmrfdError::Replaced(PathBuf::from("/tmp/rmrf/foo"));
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| job.cancel());
+                scope.spawn(|| jobs.failed(&error));
+            }
+        });
+        assert!(job.state().is_stopped());
+        assert_eq!(jobs.cancelled.load(Ordering::SeqCst), 1);
+
+        jobs.thread_done(true, 1);
+        assert_eq!(jobs.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn sweep() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf"), None, true, Mount::default(), 0, 0);
+        let sweeping = jobs.thread_done(true, 1);
+        assert_eq!(job.report(), None);
+        assert_eq!(sweeping.len(), 1);
+        assert!(sweeping[0].keep_root());
+        assert_eq!(job.state(), JobState::Sweeping);
+        assert_eq!(job.wait_timeout(Duration::from_millis(1)), JobState::Sweeping);
+
+        job.cancel();
+        assert_eq!(job.state(), JobState::Sweeping);
+        sweeping[0].dirs_removed(3);
+        sweeping[0].finish();
+        assert_eq!(job.wait(), JobState::Done);
+        let report = job.report().unwrap();
+        assert_eq!((report.state, report.removed_dirs), (JobState::Done, 3));
+    }
+
+    #[test]
+    fn error_budget() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf"), None, false, Mount::default(), 0, 0);
+        job.set_error_budget(Some(ErrorBudget::new(50).with_min_operations(4)));
+        let error = || RmrfdError::Replaced(PathBuf::from("/tmp/rmrf/foo"));
+        // the deletions of other jobs are no operations of this one
+        let other = jobs.submit(&ObjectPath::new("/tmp/busy"), None, false, Mount::default(), 0, 0);
+        jobs.deleted(&ObjectPath::new("/tmp/busy/foo"), 100, 0);
+
+        jobs.deleted(&ObjectPath::new("/tmp/rmrf/foo"), 2, 0);
+        jobs.failed(&error());
+        jobs.failed(&RmrfdError::Replaced(PathBuf::from("/tmp/other")));
+        assert!(job.failed(&error(), 0));
+        assert_eq!(job.state(), JobState::Running);
+
+        jobs.failed(&error());
+        assert_eq!(job.state(), JobState::Aborted);
+        assert!(jobs.is_cancelled(&ObjectPath::new("/tmp/rmrf/foo")));
+        assert!(!job.failed(&error(), 0));
+        let errors = job.errors();
+        assert_eq!((errors.errors, errors.operations), (4, 6));
+        assert_eq!(other.errors().operations, 100);
+        assert_eq!(errors.last, Some(error().to_string()));
+        let report = job.report().unwrap();
+        assert_eq!(report.state, JobState::Aborted);
+        assert_eq!((report.deleted_files, report.errors.errors), (2, 3));
+        assert_eq!(report.skipped.len(), 3);
+        assert_eq!(report.skipped[0].path, Path::new("/tmp/rmrf/foo"));
+        assert_eq!(report.skipped[0].reason, error().to_string());
+
+        assert_eq!(jobs.thread_done(true, 1).len(), 1);
+        assert_eq!(job.wait(), JobState::Aborted);
+        assert!(!jobs.is_cancelled(&ObjectPath::new("/tmp/rmrf/foo")));
+    }
+
+    #[test]
+    fn deleted() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf/a"), None, false, Mount::default(), 0, 0);
+        let other =
+            jobs.submit(&ObjectPath::new("/tmp/rmrf/b"), None, false, Mount::default(), 0, 0);
+        jobs.deleted(&ObjectPath::new("/tmp/rmrf/a/foo"), 2, 8192);
+        jobs.deleted(&ObjectPath::new("/tmp/rmrf/b/foo"), 5, 4096);
+        jobs.deleted(&ObjectPath::new("/tmp/other"), 1, 512);
+        jobs.small_file(&ObjectPath::new("/tmp/rmrf/a/bar"), 100);
+        jobs.small_file(&ObjectPath::new("/tmp/other"), 10);
+        assert_eq!(jobs.small_files(), (2, 110));
+        assert_eq!(job.progress_report(0, 0).small_files, 1);
+
+        let sweeping = jobs.thread_done(true, 1);
+        sweeping[0].deleted(1, 512);
+        sweeping.iter().for_each(JobHandle::finish);
+        let report = job.report().unwrap();
+        assert_eq!((report.deleted_files, report.deleted_bytes), (3, 8704));
+        assert_eq!((report.small_files, report.small_bytes), (1, 100));
+        let report = other.report().unwrap();
+        assert_eq!((report.deleted_files, report.deleted_bytes), (5, 4096));
+        assert_eq!(report.small_files, 0);
+        assert_eq!((job.progress(), other.progress()), (3, 5));
+    }
+
+    #[test]
+    fn space() {
+        crate::tests::init_env_logging();
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-space-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        let (a, b) = (ObjectPath::new(dir.join("a")), ObjectPath::new(dir.join("b")));
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&a, None, false, Mount::default(), 0, 0);
+        jobs.deleted(&ObjectPath::new(dir.join("a/foo")), 1, 8192);
+        // submitted while the first one runs, the bytes deleted before are not its own
+        let other = jobs.submit(&b, None, false, Mount::default(), 0, 0);
+        jobs.deleted(&ObjectPath::new(dir.join("b/foo")), 1, 4096);
+        jobs.deleted(&ObjectPath::new(dir.join("a/bar")), 1, 512);
+        jobs.thread_done(false, 1);
+
+        assert_eq!(job.wait(), JobState::Done);
+        assert_eq!(other.wait(), JobState::Done);
+        assert_eq!(job.space().unwrap().deleted_bytes, 8704);
+        assert_eq!(other.space().unwrap().deleted_bytes, 4096);
+        assert_eq!(job.report().unwrap().space.unwrap().deleted_bytes, 8704);
+        assert_eq!(other.report().unwrap().space.unwrap().deleted_bytes, 4096);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn generations() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(2, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf/a"), None, false, Mount::default(), 0, 0);
+        // queued after the gatherer drained, while the pass is still finishing
+        let late =
+            jobs.submit(&ObjectPath::new("/tmp/rmrf/b"), None, false, Mount::default(), 0, 1);
+        assert!(jobs.thread_done(true, 1).is_empty());
+        let sweeping = jobs.thread_done(true, 2);
+        assert_eq!(sweeping.len(), 1);
+        assert_eq!(job.state(), JobState::Sweeping);
+        assert_eq!(late.state(), JobState::Running);
+
+        jobs.thread_done(false, 2);
+        assert_eq!(late.state(), JobState::Running);
+        jobs.thread_done(false, 2);
+        assert_eq!(late.state(), JobState::Done);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn wait_async() {
+        crate::tests::init_env_logging();
+
+        let jobs = Jobs::new(1, Arc::default());
+        let job = jobs.submit(&ObjectPath::new("/tmp/rmrf"), None, false, Mount::default(), 0, 0);
+        let waiter = {
+            let job = job.clone();
+            tokio::spawn(async move { job.wait_async().await })
+        };
+
+        jobs.thread_done(false, 1);
+        assert_eq!(waiter.await.unwrap(), JobState::Done);
+        assert_eq!(job.wait_async().await, JobState::Done);
+    }
+}
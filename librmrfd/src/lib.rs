@@ -1,13 +1,76 @@
 //! Rust library to provide the functionality for the rmrfd
 #![warn(missing_docs)]
 #![warn(rustdoc::missing_crate_level_docs)]
-#![feature(io_error_more)]
 
 mod rmrfd;
-pub use rmrfd::Rmrfd;
+pub use rmrfd::{ReconfigRequest, Rmrfd, RmrfdBuilder};
+
+mod job;
+pub use job::{ErrorBudget, ErrorSummary, JobHandle, JobState, Progress, SpaceReport};
+
+mod notify;
+pub use notify::DeletedFile;
+
+mod plan;
+pub use plan::{DeletionPlan, SubtreeTotal};
+
+mod removetree;
+pub use removetree::{remove_tree, RemoveOptions, RemoveSummary};
+
+mod statistics;
+pub use statistics::{DeviceStatistics, LatencyHistogram, Statistics, Status};
+
+mod builderror;
+pub use builderror::BuildError;
+
+mod error;
+pub use error::{DeleteErrorKind, RmrfdError};
+
+mod threadprio;
+pub use threadprio::{IoClass, Pool};
+
+mod caps;
+pub use caps::Capability;
+
+mod completion;
+pub use completion::{JobReport, Skipped};
+
+mod pause;
+pub use pause::PauseReason;
+
+mod memory;
+pub use memory::MemoryPressure;
+
+#[cfg(feature = "protocol")]
+pub mod protocol;
+
+mod pathdisplay;
+
+mod watchdog;
+pub use watchdog::StuckThread;
+pub use pathdisplay::{ObjectPathDisplay, PathDisplay, PathEscape};
 
 mod inventory;
 mod objectlist;
+pub use objectlist::ObjectList;
+
+mod anchors;
+mod atomicstats;
+mod dirlock;
+mod events;
+mod nfs;
+mod objectpath;
+mod overlay;
+mod platform;
+pub use platform::FsType;
+mod profile;
+mod userdir;
+mod report;
+mod shares;
+mod statpool;
+mod statsfile;
+mod sweep;
+mod trace;
 
 #[cfg(test)]
 mod tests {
@@ -15,8 +78,6 @@ mod tests {
     use std::io::Write;
     use std::sync::Once;
 
-    use env_logger;
-
     use crate::*;
 
     pub fn init_env_logging() {
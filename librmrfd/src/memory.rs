@@ -0,0 +1,260 @@
+//! Keeps the memory use of the daemon below a limit, see RmrfdBuilder::with_memory_limit().
+//! Most memory goes into the inventory, which holds every file found until the gather pass is
+//! done and the files are deleted largest first. Gathering can't be paused to let deletion
+//! catch up, the inventory is only deleted when the pass finishes. Instead the inventory is
+//! kept from growing: under high pressure only larger files are inventoried, more are left
+//! to the sweep, and close to the limit every file is deleted as soon as it is found.
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use parking_lot::Mutex;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::inventory::Inventory;
+use crate::platform::{metadata_types, rss_bytes};
+use crate::statpool::StatPool;
+
+/// How often the memory use is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Percent of the limit from which the pressure is high.
+const HIGH_PERCENT: u64 = 75;
+
+/// Percent of the limit from which the pressure is critical.
+const CRITICAL_PERCENT: u64 = 90;
+
+/// Percent of the limit the memory use has to drop below before the pressure is relieved.
+/// Freed memory is not always returned to the system at once, this may take a while.
+const RELIEF_PERCENT: u64 = 60;
+
+/// The min_blockcount under high pressure when the configured one is smaller, 4MiB.
+const PRESSURE_MIN_BLOCKCOUNT: metadata_types::blksize_t = 8192;
+
+/// How close the daemon is to its memory limit, see Status::memory_pressure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryPressure {
+    /// Below the limit or no limit set.
+    #[default]
+    Normal,
+    /// Over 75% of the limit. Only files of at least 4MiB are inventoried, smaller ones are
+    /// left to the sweep.
+    High,
+    /// Over 90% of the limit. Files are deleted as soon as they are found, not in size
+    /// order.
+    Critical,
+}
+
+impl MemoryPressure {
+    /// Returns the pressure after 'rss' bytes were used with 'limit', coming from 'self'. It
+    /// only goes down when the memory use dropped below RELIEF_PERCENT.
+    fn next(self, rss: u64, limit: u64) -> MemoryPressure {
+        let percent = rss.saturating_mul(100) / limit.max(1);
+        if percent >= CRITICAL_PERCENT {
+            MemoryPressure::Critical
+        } else if percent >= HIGH_PERCENT {
+            self.max(MemoryPressure::High)
+        } else if percent < RELIEF_PERCENT {
+            MemoryPressure::Normal
+        } else {
+            self
+        }
+    }
+}
+
+/// The thresholds as configured and as applied under the current pressure.
+#[derive(Debug)]
+struct Thresholds {
+    pressure:             MemoryPressure,
+    rss:                  u64,
+    min_blockcount:       metadata_types::blksize_t,
+    early_delete_percent: metadata_types::blksize_t,
+    /// What was last passed to the stat pool and inventory.
+    applied:              Option<(metadata_types::blksize_t, metadata_types::blksize_t)>,
+}
+
+impl Thresholds {
+    /// Returns the min_blockcount and early_delete_percent for the current pressure.
+    fn effective(&self) -> (metadata_types::blksize_t, metadata_types::blksize_t) {
+        match self.pressure {
+            MemoryPressure::Normal => (self.min_blockcount, self.early_delete_percent),
+            MemoryPressure::High => (
+                self.min_blockcount.max(PRESSURE_MIN_BLOCKCOUNT),
+                self.early_delete_percent,
+            ),
+            MemoryPressure::Critical => (self.min_blockcount.max(PRESSURE_MIN_BLOCKCOUNT), 0),
+        }
+    }
+}
+
+/// Owns the min_blockcount and early_delete_percent thresholds, adapts them to the memory
+/// pressure.
+pub(crate) struct MemoryGuard {
+    limit:      Option<u64>,
+    thresholds: Mutex<Thresholds>,
+    stat_pool:  Arc<StatPool>,
+    inventory:  Arc<Inventory>,
+}
+
+impl MemoryGuard {
+    /// Creates a MemoryGuard for the configured thresholds, with 'limit' in bytes. Without a
+    /// limit the thresholds are only passed through.
+    pub(crate) fn new(
+        limit: Option<u64>,
+        min_blockcount: metadata_types::blksize_t,
+        early_delete_percent: metadata_types::blksize_t,
+        stat_pool: Arc<StatPool>,
+        inventory: Arc<Inventory>,
+    ) -> Arc<MemoryGuard> {
+        let guard = Arc::new(MemoryGuard {
+            limit,
+            thresholds: Mutex::new(Thresholds {
+                pressure: MemoryPressure::Normal,
+                rss: 0,
+                min_blockcount,
+                early_delete_percent,
+                applied: None,
+            }),
+            stat_pool,
+            inventory,
+        });
+        guard.apply(&mut guard.thresholds.lock());
+        guard
+    }
+
+    /// Changes the configured thresholds, under pressure they are applied once it is
+    /// relieved.
+    pub(crate) fn configure(
+        &self,
+        min_blockcount: Option<metadata_types::blksize_t>,
+        early_delete_percent: Option<metadata_types::blksize_t>,
+    ) {
+        let mut thresholds = self.thresholds.lock();
+        if let Some(min_blockcount) = min_blockcount {
+            thresholds.min_blockcount = min_blockcount;
+        }
+        if let Some(early_delete_percent) = early_delete_percent {
+            thresholds.early_delete_percent = early_delete_percent;
+        }
+        self.apply(&mut thresholds);
+    }
+
+    /// Returns the pressure and the memory used in bytes when last checked, 0 without a
+    /// limit.
+    pub(crate) fn pressure(&self) -> (MemoryPressure, u64) {
+        let thresholds = self.thresholds.lock();
+        (thresholds.pressure, thresholds.rss)
+    }
+
+    /// Checks the memory use and starts the thread checking it every CHECK_INTERVAL. Does
+    /// nothing without a limit or where the memory use can't be measured.
+    pub(crate) fn start(self: &Arc<Self>) -> io::Result<Option<MemoryProbe>> {
+        if self.limit.is_none() {
+            return Ok(None);
+        }
+        if let Err(err) = rss_bytes() {
+            warn!("memory limit ignored: {}", err);
+            return Ok(None);
+        }
+        self.check();
+        let (stop, stopped) = bounded(0);
+        let guard = self.clone();
+        thread::Builder::new()
+            .name(String::from("memory"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(CHECK_INTERVAL) {
+                    guard.check();
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(Some(MemoryProbe { _stop: stop }))
+    }
+
+    /// Measures the memory use and adapts the thresholds to the pressure.
+    fn check(&self) {
+        let mut thresholds = self.thresholds.lock();
+        if let Some(limit) = self.limit {
+            match rss_bytes() {
+                Ok(rss) => {
+                    let pressure = thresholds.pressure.next(rss, limit);
+                    if pressure > thresholds.pressure {
+                        warn!(
+                            "memory: {} of {} bytes used, pressure {:?}",
+                            rss, limit, pressure
+                        );
+                    } else if pressure < thresholds.pressure {
+                        info!(
+                            "memory: {} of {} bytes used, pressure {:?}",
+                            rss, limit, pressure
+                        );
+                    }
+                    thresholds.pressure = pressure;
+                    thresholds.rss = rss;
+                }
+                Err(err) => warn!("memory: measuring the memory use: {}", err),
+            }
+        }
+        self.apply(&mut thresholds);
+    }
+
+    /// Passes the thresholds for the current pressure on when they changed.
+    fn apply(&self, thresholds: &mut Thresholds) {
+        let effective = thresholds.effective();
+        let applied = thresholds.applied.replace(effective);
+        if applied.is_some_and(|(min_blockcount, _)| min_blockcount == effective.0) {
+            trace!("min_blockcount unchanged");
+        } else {
+            debug!("min_blockcount: {}", effective.0);
+            self.stat_pool.set_min_blockcount(effective.0);
+        }
+        if applied.is_some_and(|(_, early_delete_percent)| early_delete_percent == effective.1) {
+            trace!("early_delete_percent unchanged");
+        } else {
+            debug!("early_delete_percent: {}", effective.1);
+            self.inventory.set_early_delete_percent(effective.1);
+        }
+    }
+}
+
+/// Handle of the thread started by MemoryGuard::start().
+#[derive(Debug)]
+pub(crate) struct MemoryProbe {
+    _stop: Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure() {
+        use MemoryPressure::*;
+
+        assert_eq!(Normal.next(50, 100), Normal);
+        assert_eq!(Normal.next(80, 100), High);
+        assert_eq!(High.next(95, 100), Critical);
+        assert_eq!(Critical.next(80, 100), Critical);
+        assert_eq!(Critical.next(65, 100), Critical);
+        assert_eq!(High.next(59, 100), Normal);
+        assert_eq!(Normal.next(1, 0), Critical);
+
+        let mut thresholds = Thresholds {
+            pressure:             Normal,
+            rss:                  0,
+            min_blockcount:       512,
+            early_delete_percent: 50,
+            applied:              None,
+        };
+        assert_eq!(thresholds.effective(), (512, 50));
+        thresholds.pressure = High;
+        assert_eq!(thresholds.effective(), (PRESSURE_MIN_BLOCKCOUNT, 50));
+        thresholds.pressure = Critical;
+        assert_eq!(thresholds.effective(), (PRESSURE_MIN_BLOCKCOUNT, 0));
+        thresholds.min_blockcount = 100000;
+        assert_eq!(thresholds.effective(), (100000, 0));
+    }
+}
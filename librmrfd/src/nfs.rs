@@ -0,0 +1,43 @@
+//! NFS quirks. A file deleted on an NFS client while a process there still has it open is
+//! renamed to '.nfsXXXX' by the client ("silly rename") and only removed when it is closed,
+//! unlinking it in the meantime fails with EBUSY. File handles go stale (ESTALE) when the
+//! server lost track of an object, looking the path up again usually helps.
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+/// How often an operation failing with ESTALE is retried after looking the path up again.
+pub(crate) const STALE_RETRIES: u32 = 3;
+
+/// How often the silly renamed files found by a sweep are retried before they are reported.
+pub(crate) const SILLY_RETRIES: u32 = 3;
+
+/// The delay before the first retry of silly renamed files, doubled for every further one.
+pub(crate) const SILLY_DELAY: Duration = Duration::from_millis(100);
+
+/// Returns 'true' when 'err' is a stale NFS file handle.
+pub(crate) fn is_stale(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ESTALE)
+}
+
+/// Returns 'true' for the names NFS clients give silly renamed files, '.nfs' followed by at
+/// least 8 hex digits.
+pub(crate) fn is_silly_renamed(name: &OsStr) -> bool {
+    name.as_bytes()
+        .strip_prefix(b".nfs")
+        .is_some_and(|hex| hex.len() >= 8 && hex.iter().all(u8::is_ascii_hexdigit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silly_renamed() {
+        assert!(is_silly_renamed(OsStr::new(".nfs000000000012abcd00000042")));
+        assert!(!is_silly_renamed(OsStr::new(".nfs")));
+        assert!(!is_silly_renamed(OsStr::new(".nfsrc")));
+        assert!(!is_silly_renamed(OsStr::new("file.nfs00000000")));
+    }
+}
@@ -0,0 +1,21 @@
+use dirinventory::ObjectPath;
+
+use crate::platform::metadata_types;
+
+/// Passed to the callback registered with RmrfdBuilder::with_delete_callback() for every
+/// deleted file.
+#[derive(Debug)]
+pub struct DeletedFile<'a> {
+    /// The path of the deleted file.
+    pub path:   &'a ObjectPath,
+    /// Size of the file in 512 byte blocks.
+    pub blocks: metadata_types::blkcnt_t,
+    /// Device the file was on.
+    pub dev:    metadata_types::dev_t,
+    /// Inode number of the file.
+    pub ino:    metadata_types::ino_t,
+}
+
+/// Callback invoked from the inventory threads after a file got deleted. It is called while
+/// deletion is in progress and must return quickly.
+pub(crate) type DeleteCallback = dyn Fn(&DeletedFile) + Send + Sync;
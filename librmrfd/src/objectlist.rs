@@ -1,9 +1,10 @@
 use std::sync::Arc;
+use std::cmp::Ordering;
 
 use dirinventory::ObjectPath;
 
 /// Stores a sorted list of unique file paths.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ObjectList(Vec<Arc<ObjectPath>>);
 
 impl ObjectList {
@@ -12,13 +13,67 @@ impl ObjectList {
         ObjectList(Vec::new())
     }
 
-    /// Insert an object, only when not already present.
-    pub fn insert(&mut self, object: Arc<ObjectPath>) {
-        if let Err(idx) = self.0.binary_search(&object) {
-            self.0.insert(idx, object);
+    /// Insert an object, only when not already present. Returns 'true' when it was inserted.
+    pub fn insert(&mut self, object: Arc<ObjectPath>) -> bool {
+        match self.0.binary_search(&object) {
+            Ok(_) => false,
+            Err(idx) => {
+                self.0.insert(idx, object);
+                true
+            }
         }
     }
 
+    /// Inserts all objects from 'iter' which are not already present. Sorts the new objects
+    /// and merges them in O(n+m) instead inserting them one by one.
+    pub fn extend_dedup<I: IntoIterator<Item = Arc<ObjectPath>>>(&mut self, iter: I) {
+        let mut other: Vec<_> = iter.into_iter().collect();
+        other.sort();
+        other.dedup();
+        self.merge_sorted(other);
+    }
+
+    /// Moves all objects from 'other' into this list, duplicates are dropped. 'other' is
+    /// left empty.
+    pub fn merge(&mut self, other: &mut ObjectList) {
+        self.merge_sorted(std::mem::take(&mut other.0));
+    }
+
+    /// Merges a sorted, deduplicated Vec into self.
+    fn merge_sorted(&mut self, other: Vec<Arc<ObjectPath>>) {
+        if other.is_empty() {
+            return;
+        }
+        if self.0.is_empty() {
+            self.0 = other;
+            return;
+        }
+
+        let mut merged = Vec::with_capacity(self.0.len() + other.len());
+        let mut left = std::mem::take(&mut self.0).into_iter().peekable();
+        let mut right = other.into_iter().peekable();
+
+        loop {
+            match (left.peek(), right.peek()) {
+                (Some(l), Some(r)) => match l.cmp(r) {
+                    Ordering::Less => merged.extend(left.next()),
+                    Ordering::Greater => merged.extend(right.next()),
+                    Ordering::Equal => {
+                        merged.extend(left.next());
+                        right.next();
+                    }
+                },
+                _ => {
+                    merged.extend(left);
+                    merged.extend(right);
+                    break;
+                }
+            }
+        }
+
+        self.0 = merged;
+    }
+
     /// Removes an object if present.
     pub fn remove(&mut self, object: Arc<ObjectPath>) {
         if let Ok(idx) = self.0.binary_search(&object) {
@@ -31,16 +86,29 @@ impl ObjectList {
         self.0.first()
     }
 
-    /// Insert an object, only when not already present.
+    /// Returns 'true' when the object is present.
     pub fn contains(&self, object: Arc<ObjectPath>) -> bool {
         self.0.binary_search(&object).is_ok()
     }
 
     /// Iterator over all stored objects in sorted order.
-    pub fn iter(&mut self) -> std::slice::Iter<'_, Arc<ObjectPath>> {
+    pub fn iter(&self) -> std::slice::Iter<'_, Arc<ObjectPath>> {
         self.0.iter()
     }
 
+    /// Returns all stored objects as sorted slice.
+    pub fn as_slice(&self) -> &[Arc<ObjectPath>] {
+        &self.0
+    }
+
+    /// Keeps all elements for which 'f' returns true, removes all other.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&Arc<ObjectPath>) -> bool,
+    {
+        self.0.retain(f)
+    }
+
     /// Removes all elements for which 'f' returns true, keeps all other.
     pub fn ditch<F>(&mut self, mut f: F)
     where
@@ -60,6 +128,67 @@ impl ObjectList {
     }
 }
 
+#[cfg(feature = "rayon")]
+impl<'a> rayon::iter::IntoParallelIterator for &'a ObjectList {
+    type Item = &'a Arc<ObjectPath>;
+    type Iter = rayon::slice::Iter<'a, Arc<ObjectPath>>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        use rayon::iter::IntoParallelRefIterator;
+        self.0.par_iter()
+    }
+}
+
+impl IntoIterator for ObjectList {
+    type Item = Arc<ObjectPath>;
+    type IntoIter = std::vec::IntoIter<Arc<ObjectPath>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ObjectList {
+    type Item = &'a Arc<ObjectPath>;
+    type IntoIter = std::slice::Iter<'a, Arc<ObjectPath>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// ObjectLists serialize as a sequence of paths, each path as raw bytes since filenames
+/// don't need to be valid UTF-8.  The shared parents of the ObjectPaths are not preserved,
+/// deserialized lists hold ObjectPaths with the full path as single name.
+#[cfg(feature = "serde")]
+impl serde::Serialize for ObjectList {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct PathBytes(std::path::PathBuf);
+
+        impl serde::Serialize for PathBytes {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use std::os::unix::ffi::OsStrExt;
+                serializer.serialize_bytes(self.0.as_os_str().as_bytes())
+            }
+        }
+
+        serializer.collect_seq(self.0.iter().map(|path| PathBytes(path.to_pathbuf())))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ObjectList {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let paths = <Vec<Vec<u8>>>::deserialize(deserializer)?;
+        let mut object_list = ObjectList::new();
+        object_list.extend_dedup(paths.iter().map(|path| ObjectPath::new(OsStr::from_bytes(path))));
+        Ok(object_list)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -69,11 +198,95 @@ mod tests {
     fn objectlist_insert_uniq() {
         let mut ol = ObjectList::new();
 
-        ol.insert(ObjectPath::new("foo"));
+        assert!(ol.insert(ObjectPath::new("foo")));
         ol.insert(ObjectPath::new("bar"));
         ol.insert(ObjectPath::new("baz"));
-        ol.insert(ObjectPath::new("foo"));
+        assert!(!ol.insert(ObjectPath::new("foo")));
         eprintln!("{:?}", ol);
         assert_eq!(ol.len(), 3);
     }
+
+    #[test]
+    fn objectlist_merge() {
+        let mut ol = ObjectList::new();
+        ol.insert(ObjectPath::new("foo"));
+        ol.insert(ObjectPath::new("bar"));
+
+        ol.extend_dedup([
+            ObjectPath::new("baz"),
+            ObjectPath::new("foo"),
+            ObjectPath::new("baz"),
+        ]);
+        assert_eq!(ol.len(), 3);
+
+        let mut other = ObjectList::new();
+        other.insert(ObjectPath::new("aaa"));
+        other.insert(ObjectPath::new("bar"));
+        other.insert(ObjectPath::new("zzz"));
+        ol.merge(&mut other);
+        assert!(other.is_empty());
+
+        let names: Vec<_> = ol.iter().map(|p| p.name().to_owned()).collect();
+        assert_eq!(names, ["aaa", "bar", "baz", "foo", "zzz"]);
+    }
+
+    #[test]
+    fn objectlist_retain() {
+        let mut ol = ObjectList::new();
+        ol.extend_dedup([
+            ObjectPath::new("foo"),
+            ObjectPath::new("bar"),
+            ObjectPath::new("baz"),
+        ]);
+
+        ol.retain(|p| p.name() != "bar");
+        assert_eq!(ol.len(), 2);
+        assert!(!ol.contains(ObjectPath::new("bar")));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn objectlist_serde() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        use dirinventory::InternedName;
+
+        let mut ol = ObjectList::new();
+        let name = InternedName::new(OsStr::from_bytes(b"b\xffr"));
+        ol.insert(ObjectPath::new("foo").subobject(name));
+        ol.insert(ObjectPath::new("baz"));
+
+        let json = serde_json::to_string(&ol).unwrap();
+        let ol2: ObjectList = serde_json::from_str(&json).unwrap();
+
+        let paths: Vec<_> = ol2.iter().map(|p| p.to_pathbuf()).collect();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].as_os_str(), "baz");
+        assert_eq!(paths[1].as_os_str().as_bytes(), b"foo/b\xffr");
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn objectlist_par_iter() {
+        use rayon::prelude::*;
+
+        let mut ol = ObjectList::new();
+        ol.extend_dedup(["foo", "bar", "baz"].iter().map(ObjectPath::new));
+
+        assert_eq!(ol.par_iter().filter(|p| p.name().len() == 3).count(), 3);
+    }
+
+    #[test]
+    fn objectlist_iter() {
+        let mut ol = ObjectList::new();
+
+        ol.insert(ObjectPath::new("foo"));
+        ol.insert(ObjectPath::new("bar"));
+
+        let names: Vec<_> = (&ol).into_iter().map(|p| p.name().to_owned()).collect();
+        assert_eq!(names, ["bar", "foo"]);
+        assert_eq!(ol.iter().count(), ol.as_slice().len());
+        assert_eq!(ol.into_iter().count(), 2);
+    }
 }
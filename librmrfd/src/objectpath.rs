@@ -0,0 +1,70 @@
+use std::io;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use dirinventory::{InternedNames, ObjectPath};
+
+/// Builds the shared-parent ObjectPath chain for an arbitrary path. When one of the 'roots'
+/// is a prefix of 'path' the longest of them is reused as parent, only the remaining
+/// components are added, with their names interned in 'names'. Roots must be single
+/// ObjectPath nodes holding a complete path, as created by ObjectPath::new(). Paths
+/// containing '..' components are rejected.
+pub(crate) fn object_path_interned<'a, const N: usize>(
+    names: &InternedNames<N>,
+    roots: impl Iterator<Item = &'a Arc<ObjectPath>>,
+    path: &Path,
+) -> io::Result<Arc<ObjectPath>> {
+    let (mut object_path, rest) = match roots
+        .filter_map(|root| {
+            path.strip_prefix(root.name())
+                .ok()
+                .map(|rest| (root, rest))
+        })
+        .max_by_key(|(root, _)| root.name().len())
+    {
+        Some((root, rest)) => (Some(root.clone()), rest),
+        None => (None, path),
+    };
+
+    for component in rest.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(name) => {
+                object_path = Some(match object_path {
+                    Some(parent) => parent.subobject(names.interning(name)),
+                    None => ObjectPath::new(name),
+                })
+            }
+            Component::RootDir | Component::Prefix(_) if object_path.is_none() => {
+                object_path = Some(ObjectPath::new(component))
+            }
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    object_path.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn reuse_roots() {
+        let names = InternedNames::<4>::new();
+        let roots = [ObjectPath::new("/tmp/rmrf"), ObjectPath::new("/tmp")];
+
+        let path =
+            object_path_interned(&names, roots.iter(), Path::new("/tmp/rmrf/foo/bar")).unwrap();
+        assert_eq!(path.to_pathbuf(), PathBuf::from("/tmp/rmrf/foo/bar"));
+        assert_eq!(path.depth(), 3);
+
+        let path = object_path_interned(&names, roots.iter(), Path::new("/var/tmp")).unwrap();
+        assert_eq!(path.to_pathbuf(), PathBuf::from("/var/tmp"));
+        assert_eq!(path.depth(), 3);
+
+        assert!(object_path_interned(&names, roots.iter(), Path::new("/tmp/../etc")).is_err());
+    }
+}
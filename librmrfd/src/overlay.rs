@@ -0,0 +1,131 @@
+//! Overlayfs quirks. Deleting through the merged view of an overlay is left to the kernel, it
+//! maintains the whiteouts and opaque directories in the upper layer which hide the entries
+//! of the lower layers. In the layer directories themself whiteouts are character devices
+//! without blocks and opaque directories are marked by an xattr, once the overlay is
+//! unmounted they are removed like any other entry. Changing a layer while it is mounted
+//! is undefined, the lower files hidden by a removed whiteout would show up again. Paths
+//! overlapping the layers of mounted overlays are refused.
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The mount options naming the layer directories of an overlay.
+const LAYER_OPTIONS: [&str; 5] = ["lowerdir", "lowerdir+", "datadir+", "upperdir", "workdir"];
+
+/// Returns the layer of a mounted overlay which is 'path' or below or above it, None when
+/// there is none. Both sides are compared canonicalized.
+pub(crate) fn mounted_layer(path: &Path) -> io::Result<Option<PathBuf>> {
+    let layers = mounted_layers()?;
+    if layers.is_empty() {
+        return Ok(None);
+    }
+    let path = fs::canonicalize(path)?;
+    Ok(layers
+        .into_iter()
+        .find(|layer| layer.starts_with(&path) || path.starts_with(layer)))
+}
+
+/// Returns the layer directories of all overlays mounted in the mount namespace of the
+/// process.
+#[cfg(target_os = "linux")]
+fn mounted_layers() -> io::Result<Vec<PathBuf>> {
+    Ok(parse_layers(&fs::read("/proc/self/mountinfo")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mounted_layers() -> io::Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+/// Parses the layer directories of the overlays in 'mountinfo'. The fields after the ' - '
+/// separator are the filesystem type, the source and the super block options.
+fn parse_layers(mountinfo: &[u8]) -> Vec<PathBuf> {
+    let mut layers = Vec::new();
+    for line in mountinfo.split(|&byte| byte == b'\n') {
+        let Some(separator) = line.windows(3).position(|window| window == b" - ") else {
+            continue;
+        };
+        let mut fields = line[separator + 3..].split(|&byte| byte == b' ');
+        if fields.next() != Some(b"overlay") {
+            continue;
+        }
+        let Some(options) = fields.nth(1) else {
+            continue;
+        };
+        for option in options.split(|&byte| byte == b',') {
+            let Some(equals) = option.iter().position(|&byte| byte == b'=') else {
+                continue;
+            };
+            if !LAYER_OPTIONS
+                .iter()
+                .any(|name| name.as_bytes() == &option[..equals])
+            {
+                continue;
+            }
+            // several lower layers are separated by colons
+            layers.extend(
+                option[equals + 1..]
+                    .split(|&byte| byte == b':')
+                    .filter(|dir| !dir.is_empty())
+                    .map(|dir| PathBuf::from(OsStr::from_bytes(&unescape(dir)))),
+            );
+        }
+    }
+    trace!("mounted overlay layers: {:?}", layers);
+    layers
+}
+
+/// Replaces the octal escapes ('\040' for a space) of the kernel in 'field'.
+fn unescape(field: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some((&byte, tail)) = rest.split_first() {
+        // a byte needs three digits, the first one up to 3
+        let octal = tail
+            .get(..3)
+            .filter(|digits| byte == b'\\' && (b'0'..=b'3').contains(&digits[0]))
+            .filter(|digits| digits.iter().all(|digit| (b'0'..=b'7').contains(digit)))
+            .map(|digits| {
+                digits
+                    .iter()
+                    .fold(0, |value, digit| value * 8 + (digit - b'0'))
+            });
+        match octal {
+            Some(value) => {
+                unescaped.push(value);
+                rest = &tail[3..];
+            }
+            None => {
+                unescaped.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers() {
+        let mountinfo = b"22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            99 22 0:52 / /var/lib/c/merged rw,relatime - overlay overlay \
+            rw,lowerdir=/var/lib/c/l1:/var/lib/c/l2,upperdir=/var/lib/c/my\\040upper,\
+            workdir=/var/lib/c/work,xino=off\n";
+        assert_eq!(parse_layers(mountinfo), [
+            PathBuf::from("/var/lib/c/l1"),
+            PathBuf::from("/var/lib/c/l2"),
+            PathBuf::from("/var/lib/c/my upper"),
+            PathBuf::from("/var/lib/c/work"),
+        ]);
+        assert_eq!(unescape(b"a\\054b\\\\c\\9"), b"a,b\\\\c\\9");
+        assert_eq!(mounted_layer(Path::new(".")).unwrap(), None);
+    }
+}
@@ -0,0 +1,110 @@
+//! Safe rendering of untrusted filenames. Everything that ends up in logs, error messages
+//! or the event log goes through escape(), a filename can't inject newlines, terminal escape
+//! sequences or bidi overrides there, and invalid UTF-8 is shown byte by byte instead of being
+//! replaced.
+use std::borrow::Cow;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use dirinventory::ObjectPath;
+
+/// Extends ObjectPath with a safe way to render it in logs and reports.
+pub trait ObjectPathDisplay {
+    /// Returns an object that implements Display and Debug for printing the path.  Invalid
+    /// UTF-8 bytes and control characters are escaped, thus filenames can't inject newlines
+    /// or terminal escape sequences into the output.
+    fn display(&self) -> PathDisplay<'_>;
+}
+
+impl ObjectPathDisplay for ObjectPath {
+    fn display(&self) -> PathDisplay<'_> {
+        PathDisplay(Cow::Owned(self.to_pathbuf()))
+    }
+}
+
+/// Extends Path with the same escaping as ObjectPathDisplay. Path::display() itself is
+/// lossy and does no escaping at all.
+pub trait PathEscape {
+    /// Returns an object that implements Display and Debug for printing the path escaped.
+    fn escaped(&self) -> PathDisplay<'_>;
+}
+
+impl PathEscape for Path {
+    fn escaped(&self) -> PathDisplay<'_> {
+        PathDisplay(Cow::Borrowed(self))
+    }
+}
+
+/// Helper for printing paths with escaping, see ObjectPathDisplay::display() and
+/// PathEscape::escaped().
+pub struct PathDisplay<'a>(Cow<'a, Path>);
+
+/// Writes 'bytes' to 'out' with backslashes, control characters, bidi formatting characters
+/// and invalid UTF-8 escaped. With 'quote' double quotes are escaped as well.
+pub(crate) fn escape(out: &mut impl fmt::Write, bytes: &[u8], quote: bool) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.write_str("\\\\")?,
+                '"' if quote => out.write_str("\\\"")?,
+                '\n' => out.write_str("\\n")?,
+                '\r' => out.write_str("\\r")?,
+                '\t' => out.write_str("\\t")?,
+                // bidi overrides and isolates can make a name look like a different one
+                c if c.is_control()
+                    || ('\u{202a}'..='\u{202e}').contains(&c)
+                    || ('\u{2066}'..='\u{2069}').contains(&c) =>
+                {
+                    write!(out, "\\u{{{:x}}}", c as u32)?
+                }
+                c => out.write_char(c)?,
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(out, "\\x{:02x}", byte)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for PathDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        escape(f, self.0.as_os_str().as_bytes(), false)
+    }
+}
+
+impl fmt::Debug for PathDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        escape(f, self.0.as_os_str().as_bytes(), true)?;
+        f.write_str("\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use dirinventory::InternedName;
+
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        let path = ObjectPath::new("/tmp")
+            .subobject(InternedName::new(OsStr::from_bytes(b"foo\nbar\x1b[1m\xff\\")));
+        assert_eq!(
+            path.display().to_string(),
+            "/tmp/foo\\nbar\\u{1b}[1m\\xff\\\\"
+        );
+        assert_eq!(
+            format!("{:?}", ObjectPath::new("a\"b").display()),
+            "\"a\\\"b\""
+        );
+        assert_eq!(
+            format!("{:?}", Path::new("/tmp/\u{202e}txt.exe").escaped()),
+            "\"/tmp/\\u{202e}txt.exe\""
+        );
+    }
+}
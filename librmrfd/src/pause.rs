@@ -0,0 +1,215 @@
+//! Pausing the deletion on a device. Unlinking on a filesystem which went read-only, ran out
+//! of space for its metadata or is frozen only piles up errors or blocked threads. Instead the
+//! device is paused, the deleting threads wait until it is resumed, either by the probe
+//! thread when the condition cleared or by Rmrfd::resume().
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use parking_lot::{Condvar, Mutex};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::anchors::Anchors;
+use crate::events::{Event, EventLog};
+use crate::pathdisplay::PathEscape;
+use crate::platform::{is_readonly_fs, metadata_types};
+use crate::watchdog::{self, Watchdog};
+
+/// Why the deletion on a device is paused, see Rmrfd::paused().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    /// Unlinking failed with EROFS. Resumed when the filesystem is writable again.
+    ReadOnly,
+    /// Unlinking failed with ENOSPC, copy on write filesystems need space to delete. Resumed
+    /// by every probe to try again.
+    NoSpace,
+    /// A thread is stuck on the device for longer than the watchdog timeout, the filesystem
+    /// is frozen or its server hangs. Resumed when no thread is stuck there anymore.
+    Frozen,
+}
+
+impl PauseReason {
+    /// Returns the reason to pause when an unlink failed with 'err'.
+    pub(crate) fn of(err: &io::Error) -> Option<PauseReason> {
+        match err.raw_os_error() {
+            Some(libc::EROFS) => Some(PauseReason::ReadOnly),
+            Some(libc::ENOSPC) => Some(PauseReason::NoSpace),
+            _ => None,
+        }
+    }
+}
+
+/// The paused devices of a Rmrfd.
+#[derive(Debug)]
+pub(crate) struct Pauses {
+    devices:  Mutex<HashMap<metadata_types::dev_t, PauseReason>>,
+    /// Number of paused devices, checked without locking on every unlink.
+    paused:   AtomicUsize,
+    changed:  Condvar,
+    shutdown: AtomicBool,
+    events:   Arc<EventLog>,
+}
+
+impl Pauses {
+    /// Creates the registry, pausing and resuming is written to 'events'.
+    pub(crate) fn new(events: Arc<EventLog>) -> Arc<Pauses> {
+        Arc::new(Pauses {
+            devices: Mutex::new(HashMap::new()),
+            paused: AtomicUsize::new(0),
+            changed: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            events,
+        })
+    }
+
+    /// Pauses the deletion on 'dev' because of 'reason', noticed at 'path'. Does nothing when
+    /// it is paused already.
+    pub(crate) fn pause(&self, dev: metadata_types::dev_t, reason: PauseReason, path: &Path) {
+        let mut devices = self.devices.lock();
+        if devices.contains_key(&dev) {
+            return;
+        }
+        devices.insert(dev, reason);
+        self.paused.fetch_add(1, Ordering::SeqCst);
+        warn!("pausing device {} ({:?}) at {:?}", dev, reason, path.escaped());
+        self.events.emit(Event::Paused { dev, reason, path });
+    }
+
+    /// Resumes the deletion on 'dev'. Returns 'false' when it was not paused.
+    pub(crate) fn resume(&self, dev: metadata_types::dev_t) -> bool {
+        let mut devices = self.devices.lock();
+        if devices.remove(&dev).is_none() {
+            return false;
+        }
+        self.paused.fetch_sub(1, Ordering::SeqCst);
+        info!("resuming device {}", dev);
+        self.events.emit(Event::Resumed { dev });
+        self.changed.notify_all();
+        true
+    }
+
+    /// Returns the paused devices.
+    pub(crate) fn paused(&self) -> Vec<(metadata_types::dev_t, PauseReason)> {
+        self.devices.lock().iter().map(|(dev, reason)| (*dev, *reason)).collect()
+    }
+
+    /// Blocks while 'dev' is paused. Fails with 'Interrupted' when the daemon shuts down.
+    /// A waiting thread is idle for the watchdog, it would keep its device frozen otherwise.
+    pub(crate) fn wait(&self, dev: metadata_types::dev_t) -> io::Result<()> {
+        if self.paused.load(Ordering::SeqCst) == 0 {
+            return Ok(());
+        }
+        let mut devices = self.devices.lock();
+        if !devices.contains_key(&dev) {
+            return Ok(());
+        }
+        watchdog::idle();
+        while devices.contains_key(&dev) {
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            self.changed.wait(&mut devices);
+        }
+        watchdog::progress();
+        Ok(())
+    }
+
+    /// Wakes all waiting threads, they fail from now on instead of waiting.
+    pub(crate) fn shutdown(&self) {
+        // under the lock, a thread about to wait can't miss it
+        let _devices = self.devices.lock();
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    /// Starts a thread which checks the devices every 'interval'. Devices with stuck threads
+    /// are paused as frozen, paused devices are resumed when their PauseReason cleared. The
+    /// thread stops when the returned handle is dropped.
+    pub(crate) fn start_probe(
+        self: &Arc<Self>,
+        interval: Duration,
+        anchors: Arc<Anchors>,
+        watchdog: Arc<Watchdog>,
+    ) -> io::Result<PauseProbe> {
+        let (stop, stopped) = bounded(0);
+        let pauses = self.clone();
+        thread::Builder::new()
+            .name(String::from("pause probe"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    pauses.probe(&anchors, &watchdog);
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(PauseProbe { _stop: stop })
+    }
+
+    /// A single round of the probe thread.
+    fn probe(&self, anchors: &Anchors, watchdog: &Watchdog) {
+        let stuck: Vec<_> = watchdog
+            .stuck()
+            .into_iter()
+            .filter_map(|stuck| stuck.item)
+            .filter_map(|path| anchors.mount_of(&path).map(|mount| (mount.dev, path)))
+            .collect();
+        for (dev, path) in &stuck {
+            self.pause(*dev, PauseReason::Frozen, path);
+        }
+        for (dev, reason) in self.paused() {
+            let cleared = match reason {
+                PauseReason::ReadOnly => anchors
+                    .path_on(dev)
+                    .is_some_and(|path| is_readonly_fs(path).is_ok_and(|readonly| !readonly)),
+                PauseReason::NoSpace => true,
+                PauseReason::Frozen => !stuck.iter().any(|(stuck_dev, _)| *stuck_dev == dev),
+            };
+            if cleared {
+                self.resume(dev);
+            }
+        }
+    }
+}
+
+/// Handle of the thread started by Pauses::start_probe().
+#[derive(Debug)]
+pub(crate) struct PauseProbe {
+    _stop: Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_resume() {
+        crate::tests::init_env_logging();
+
+        let pauses = Pauses::new(Arc::default());
+        assert!(pauses.wait(1).is_ok());
+        pauses.pause(1, PauseReason::ReadOnly, Path::new("/tmp/rmrf/foo"));
+        pauses.pause(1, PauseReason::NoSpace, Path::new("/tmp/rmrf/foo"));
+        assert_eq!(pauses.paused(), [(1, PauseReason::ReadOnly)]);
+        assert!(pauses.wait(2).is_ok());
+
+        let waiter = {
+            let pauses = pauses.clone();
+            thread::spawn(move || pauses.wait(1))
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert!(!waiter.is_finished());
+        assert!(pauses.resume(1));
+        assert!(!pauses.resume(1));
+        assert!(waiter.join().unwrap().is_ok());
+
+        pauses.pause(1, PauseReason::Frozen, Path::new("/tmp/rmrf/foo"));
+        pauses.shutdown();
+        assert_eq!(pauses.wait(1).unwrap_err().kind(), io::ErrorKind::Interrupted);
+    }
+}
@@ -0,0 +1,201 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::platform::BLOCK_SIZE;
+use crate::pathdisplay::PathEscape;
+
+/// How many of the biggest subtrees are reported.
+const SUBTREES: usize = 10;
+
+/// Report of what deleting a directory would do, see Rmrfd::plan().
+#[derive(Debug, Clone, Default)]
+pub struct DeletionPlan {
+    /// Number of files (anything but directories) found.
+    pub files:        u64,
+    /// Number of directories found, including the top directory.
+    pub dirs:         u64,
+    /// Bytes freed when everything is deleted.
+    pub bytes:        u64,
+    /// The biggest direct subdirectories, sorted by size, largest first.
+    pub subtrees:     Vec<SubtreeTotal>,
+    /// Files which have hardlinks outside of the planned directory, deleting them won't free
+    /// any space.
+    pub shared_files: u64,
+    /// Size of the files with hardlinks outside, not included in 'bytes'.
+    pub shared_bytes: u64,
+    /// Directories on other devices which are not scanned.
+    pub mountpoints:  Vec<PathBuf>,
+    /// Number of entries which could not be read.
+    pub errors:       u64,
+}
+
+/// Totals of a subdirectory in a DeletionPlan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubtreeTotal {
+    /// The subdirectory.
+    pub path:  PathBuf,
+    /// Number of files below it.
+    pub files:        u64,
+    /// Bytes freed by deleting it, hardlinks shared with other subtrees are accounted where
+    /// the last link was found.
+    pub bytes:        u64,
+}
+
+/// Hardlinked files seen while scanning.
+struct Links {
+    nlink:   u64,
+    seen:    u64,
+    bytes:   u64,
+    subtree: Option<usize>,
+}
+
+/// Scans 'path' without deleting anything. Does not follow symlinks and does not descend
+/// into other filesystems.
+pub(crate) fn plan(path: &Path) -> io::Result<DeletionPlan> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::from(io::ErrorKind::NotADirectory));
+    }
+
+    let mut plan = DeletionPlan {
+        dirs: 1,
+        ..DeletionPlan::default()
+    };
+    let mut subtrees = Vec::new();
+    let mut links = HashMap::new();
+
+    for entry in fs::read_dir(path)? {
+        match entry.and_then(|entry| Ok((entry.path(), entry.metadata()?))) {
+            Ok((path, entry_metadata)) => {
+                let subtree = if entry_metadata.is_dir() {
+                    subtrees.push(SubtreeTotal {
+                        path: path.clone(),
+                        ..SubtreeTotal::default()
+                    });
+                    Some(subtrees.len() - 1)
+                } else {
+                    None
+                };
+                scan(
+                    &path,
+                    &entry_metadata,
+                    metadata.dev(),
+                    subtree,
+                    &mut plan,
+                    &mut subtrees,
+                    &mut links,
+                );
+            }
+            Err(err) => {
+                warn!("plan: {:?}: {}", path.escaped(), err);
+                plan.errors += 1;
+            }
+        }
+    }
+
+    for link in links.values() {
+        if link.seen < link.nlink {
+            plan.shared_files += 1;
+            plan.shared_bytes += link.bytes;
+        } else {
+            plan.bytes += link.bytes;
+            if let Some(subtree) = link.subtree {
+                subtrees[subtree].bytes += link.bytes;
+            }
+        }
+    }
+
+    subtrees.sort_by_key(|subtree| Reverse(subtree.bytes));
+    subtrees.truncate(SUBTREES);
+    plan.subtrees = subtrees;
+    Ok(plan)
+}
+
+fn scan(
+    path: &Path,
+    metadata: &fs::Metadata,
+    dev: u64,
+    subtree: Option<usize>,
+    plan: &mut DeletionPlan,
+    subtrees: &mut [SubtreeTotal],
+    links: &mut HashMap<(u64, u64), Links>,
+) {
+    if metadata.is_dir() {
+        if metadata.dev() != dev {
+            plan.mountpoints.push(path.to_path_buf());
+            return;
+        }
+        plan.dirs += 1;
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("plan: {:?}: {}", path.escaped(), err);
+                plan.errors += 1;
+                return;
+            }
+        };
+        for entry in entries {
+            match entry.and_then(|entry| Ok((entry.path(), entry.metadata()?))) {
+                Ok((path, metadata)) => {
+                    scan(&path, &metadata, dev, subtree, plan, subtrees, links)
+                }
+                Err(err) => {
+                    warn!("plan: {:?}: {}", path.escaped(), err);
+                    plan.errors += 1;
+                }
+            }
+        }
+    } else {
+        plan.files += 1;
+        if let Some(subtree) = subtree {
+            subtrees[subtree].files += 1;
+        }
+        let bytes = metadata.blocks() * BLOCK_SIZE;
+        if metadata.nlink() > 1 {
+            let link = links
+                .entry((metadata.dev(), metadata.ino()))
+                .or_insert(Links {
+                    nlink: metadata.nlink(),
+                    seen:  0,
+                    bytes,
+                    subtree,
+                });
+            link.seen += 1;
+            link.subtree = subtree;
+        } else {
+            plan.bytes += bytes;
+            if let Some(subtree) = subtree {
+                subtrees[subtree].bytes += bytes;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_src() {
+        crate::tests::init_env_logging();
+
+        let plan = super::plan(Path::new("src")).unwrap();
+        assert_eq!(plan.files, fs::read_dir("src").unwrap().count() as u64);
+        assert_eq!(plan.dirs, 1);
+        assert!(plan.bytes > 0);
+        assert!(plan.subtrees.is_empty());
+        assert_eq!(plan.errors, 0);
+
+        let plan = super::plan(Path::new(".")).unwrap();
+        assert!(plan.subtrees.iter().any(|subtree| subtree.path == Path::new("./src")));
+
+        assert!(super::plan(Path::new("Cargo.toml")).is_err());
+    }
+}
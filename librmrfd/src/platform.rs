@@ -0,0 +1,404 @@
+//! Operating system specific parts. The gather and delete engine only needs what every Unix
+//! offers, the few places where platforms differ are collected here. Features which only
+//! exist on Linux (io priorities, per thread niceness) report 'Unsupported' elsewhere.
+use std::io;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use dirinventory::openat::{AsPath, Dir, DirIter};
+pub(crate) use dirinventory::openat::metadata_types;
+
+use crate::threadprio::IoClass;
+
+/// Unit of 'st_blocks'. Linux, the BSDs and macOS all count in 512 byte blocks, independent
+/// of the block size of the filesystem.
+pub(crate) const BLOCK_SIZE: u64 = 512;
+
+/// Converts a 'st_blocks' count into bytes.
+pub(crate) fn blocks_to_bytes(blocks: metadata_types::blkcnt_t) -> u64 {
+    blocks as u64 * BLOCK_SIZE
+}
+
+/// Where the open fds of the process are accessible as directories.
+#[cfg(target_os = "linux")]
+pub(crate) const FD_DIR: &str = "/proc/self/fd";
+#[cfg(not(target_os = "linux"))]
+pub(crate) const FD_DIR: &str = "/dev/fd";
+
+/// Keeps reads from updating the access time. Linux only, 0 elsewhere.
+#[cfg(target_os = "linux")]
+const O_NOATIME: libc::c_int = libc::O_NOATIME;
+#[cfg(not(target_os = "linux"))]
+const O_NOATIME: libc::c_int = 0;
+
+/// Calls 'open' with O_NOATIME as extra flag where permitted, scanning millions of
+/// directories shouldn't touch their access times. Only the owner of a file or a process
+/// with CAP_FOWNER may use it, on EPERM 'open' is called again without.
+fn noatime<T>(open: impl Fn(libc::c_int) -> io::Result<T>) -> io::Result<T> {
+    match open(O_NOATIME) {
+        Err(err) if O_NOATIME != 0 && err.raw_os_error() == Some(libc::EPERM) => open(0),
+        result => result,
+    }
+}
+
+/// Lists 'dir' like Dir::list_self(), with O_NOATIME where permitted. Handles opened with
+/// Dir::sub_dir() are O_PATH where available and never read, listing reopens them for
+/// reading.
+pub(crate) fn list_dir(dir: &Dir) -> io::Result<DirIter> {
+    noatime(|flags| dir.with(flags).clone_upgrade())?.list()
+}
+
+/// Returns the statvfs of the filesystem 'path' is on.
+fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut statvfs = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: 'path' is a valid C string and statvfs only writes to the passed struct.
+    if unsafe { libc::statvfs(path.as_ptr(), statvfs.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful statvfs call above.
+    Ok(unsafe { statvfs.assume_init() })
+}
+
+/// Checks if 'path' is on a filesystem mounted read-only.
+pub(crate) fn is_readonly_fs(path: &Path) -> io::Result<bool> {
+    Ok(statvfs(path)?.f_flag & libc::ST_RDONLY != 0)
+}
+
+/// Returns the free bytes of the filesystem 'path' is on, including the ones reserved for
+/// root.
+pub(crate) fn free_bytes(path: &Path) -> io::Result<u64> {
+    let statvfs = statvfs(path)?;
+    Ok(statvfs.f_bfree as u64 * statvfs.f_frsize as u64)
+}
+
+/// Checks if the entry 'name' of 'dir' is flagged immutable or append-only, "." checks
+/// 'dir' itself. Such files can't be deleted and nothing can be deleted from such
+/// directories, not even by root. Only regular files and directories are opened to look,
+/// without following symlinks, others are reported as not flagged.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_immutable<P: AsPath + Copy>(dir: &Dir, name: P) -> io::Result<bool> {
+    use std::os::unix::io::FromRawFd;
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+    const FS_APPEND_FL: libc::c_int = 0x20;
+
+    let is_file_or_dir = |file_type: std::fs::FileType| file_type.is_file() || file_type.is_dir();
+    let metadata = dir.metadata(name)?;
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(false);
+    }
+    let name = name.to_path().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let flags =
+        libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC;
+    // SAFETY: 'name' is a valid C string, the returned fd is owned by 'file'
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ref().as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: 'fd' was just opened and is not used elsewhere
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+    // replaced by something else since it was looked at
+    if !is_file_or_dir(file.metadata()?.file_type()) {
+        return Ok(false);
+    }
+    let mut flags: libc::c_int = 0;
+    // SAFETY: the kernel writes an int despite the ioctl being declared for a long
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_immutable<P: AsPath + Copy>(_dir: &Dir, _name: P) -> io::Result<bool> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// The kind of filesystem a job deletes on, see SpaceReport. Some filesystems don't free
+/// what was deleted where one would expect it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsType {
+    /// An overlay, like the root of a container. Entries coming from a lower layer are only
+    /// hidden by a whiteout, nothing is freed for them. The space freed is the one of the
+    /// upper layer, on the filesystem the upper directory is on.
+    Overlay,
+    /// NFS, the server may release the space of deleted files later.
+    Nfs,
+    /// Any other filesystem.
+    #[default]
+    Other,
+}
+
+/// Returns the kind of filesystem 'path' is on.
+#[cfg(target_os = "linux")]
+pub(crate) fn fs_type(path: &Path) -> io::Result<FsType> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut statfs = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: 'path' is a valid C string and statfs only writes to the passed struct.
+    if unsafe { libc::statfs(path.as_ptr(), statfs.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful statfs call above.
+    Ok(match unsafe { statfs.assume_init() }.f_type {
+        libc::OVERLAYFS_SUPER_MAGIC => FsType::Overlay,
+        libc::NFS_SUPER_MAGIC => FsType::Nfs,
+        _ => FsType::Other,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn fs_type(_path: &Path) -> io::Result<FsType> {
+    Ok(FsType::Other)
+}
+
+/// The mount a directory is on. A bind mount has the same device as its source, it can only
+/// be told apart by the mount id. Linux reports that since 5.8, elsewhere and on older
+/// kernels only the devices are compared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Mount {
+    pub(crate) dev: metadata_types::dev_t,
+    pub(crate) id:  Option<u64>,
+}
+
+impl Mount {
+    /// Returns the mount the directory 'dir' is on.
+    pub(crate) fn of(dir: &Dir) -> io::Result<Mount> {
+        Ok(Mount {
+            dev: dir.self_metadata()?.dev().unwrap_or(0),
+            id:  mount_id(dir.as_raw_fd())?,
+        })
+    }
+}
+
+/// Returns the mount id of the open file 'fd', None when the kernel does not report it.
+#[cfg(target_os = "linux")]
+fn mount_id(fd: RawFd) -> io::Result<Option<u64>> {
+    let mut statx = std::mem::MaybeUninit::<libc::statx>::uninit();
+    // SAFETY: the empty path with AT_EMPTY_PATH refers to 'fd' itself, works with O_PATH
+    // descriptors too. statx only writes to the passed struct.
+    let ret = unsafe {
+        libc::statx(
+            fd,
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_MNT_ID,
+            statx.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    // SAFETY: initialized by the successful statx call above.
+    let statx = unsafe { statx.assume_init() };
+    Ok((statx.stx_mask & libc::STATX_MNT_ID != 0).then_some(statx.stx_mnt_id))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_id(_fd: RawFd) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Sets the io scheduling class of the calling thread.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_ioprio(io_class: IoClass) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    // SAFETY: plain syscall, 'who' 0 addresses the calling thread
+    match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_class.ioprio()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_ioprio(_io_class: IoClass) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Sets the niceness of the calling thread.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_nice(nice: libc::c_int) -> io::Result<()> {
+    // SAFETY: on Linux the nice value is per thread, addressed by its tid
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Other Unixes only have a niceness per process, changing it from a pool thread would
+/// affect all threads.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_nice(_nice: libc::c_int) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Upper bound when raising the open files limit. An unlimited hard limit is not accepted as
+/// soft limit, Linux refuses more than 'fs.nr_open' (1048576 by default) and macOS more than
+/// OPEN_MAX.
+#[cfg(target_os = "macos")]
+const NOFILE_MAX: u64 = 10240;
+#[cfg(not(target_os = "macos"))]
+const NOFILE_MAX: u64 = 1 << 20;
+
+/// Returns the soft and hard limit of open files of the process.
+// rlim_t is not 64 bit everywhere
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn nofile_limits() -> io::Result<(u64, u64)> {
+    let mut rlimit = std::mem::MaybeUninit::<libc::rlimit>::uninit();
+    // SAFETY: getrlimit only writes to the passed struct.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlimit.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful getrlimit call above.
+    let rlimit = unsafe { rlimit.assume_init() };
+    Ok((rlimit.rlim_cur as u64, rlimit.rlim_max as u64))
+}
+
+/// Raises the soft limit of open files as far as the hard limit allows, at most to
+/// NOFILE_MAX. Never lowers it. Returns the soft and hard limit in effect afterwards.
+pub(crate) fn raise_nofile_limit() -> io::Result<(u64, u64)> {
+    let (soft, hard) = nofile_limits()?;
+    let target = hard.min(NOFILE_MAX);
+    if soft >= target {
+        return Ok((soft, hard));
+    }
+    let rlimit = libc::rlimit {
+        rlim_cur: target as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the passed struct.
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((target, hard))
+}
+
+/// Returns the resident set size of the process in bytes.
+#[cfg(target_os = "linux")]
+pub(crate) fn rss_bytes() -> io::Result<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/self/statm"))?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as u64)
+}
+
+/// getrusage() only reports the peak, not the current use.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn rss_bytes() -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nofile() {
+        let (soft, hard) = raise_nofile_limit().unwrap();
+        assert_eq!((soft, hard), nofile_limits().unwrap());
+        assert!(soft <= hard);
+        assert!(soft >= hard.min(NOFILE_MAX));
+    }
+
+    #[test]
+    fn rss() {
+        let rss = rss_bytes().unwrap();
+        let buffer = vec![1u8; 64 << 20];
+        assert!(rss_bytes().unwrap() >= rss + (32 << 20));
+        drop(buffer);
+    }
+
+    #[test]
+    fn readonly_fs() {
+        assert!(!is_readonly_fs(Path::new(".")).unwrap());
+        assert!(is_readonly_fs(Path::new("does/not/exist")).is_err());
+        assert!(free_bytes(Path::new(".")).unwrap() > 0);
+        assert_eq!(fs_type(Path::new("/proc")).unwrap(), FsType::Other);
+        assert!(fs_type(Path::new("does/not/exist")).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn immutable() {
+        use crate::{DeleteErrorKind, RmrfdError};
+
+        let dir = std::env::temp_dir().join(format!("rmrfd-immutable-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        std::fs::write(&file, b"x").unwrap();
+        std::os::unix::fs::symlink(&file, dir.join("link")).unwrap();
+        let fifo = CString::new(dir.join("fifo").as_os_str().as_bytes()).unwrap();
+        // SAFETY: 'fifo' is a valid C string
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let handle = Dir::open(&dir).unwrap();
+        assert!(!is_immutable(&handle, "file").unwrap());
+        assert!(!is_immutable(&handle, ".").unwrap());
+        // neither followed nor opened
+        assert!(!is_immutable(&handle, "link").unwrap());
+        assert!(!is_immutable(&handle, "fifo").unwrap());
+        assert!(!is_immutable(&Dir::open("/dev").unwrap(), "null").unwrap());
+        assert!(is_immutable(&handle, "missing").is_err());
+
+        // needs CAP_LINUX_IMMUTABLE and a filesystem supporting the flags
+        let set_flags = |flags: libc::c_int| {
+            let file = std::fs::File::open(&file).unwrap();
+            // SAFETY: the kernel reads an int despite the ioctl being declared for a long
+            unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) == 0 }
+        };
+        if set_flags(0x10) {
+            assert!(is_immutable(&handle, "file").unwrap());
+            let err = handle.remove_file("file").unwrap_err();
+            let err = RmrfdError::delete_in(&handle, "file", file.clone(), err);
+            assert!(matches!(
+                err,
+                RmrfdError::Delete {
+                    kind: DeleteErrorKind::Immutable,
+                    ..
+                }
+            ));
+            assert!(set_flags(0));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn noatime_fallback() {
+        let dir = std::env::temp_dir().join(format!("rmrfd-noatime-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        // needs root, O_NOATIME on another user's directory is refused without CAP_FOWNER
+        if std::os::unix::fs::chown(dir.join("sub"), Some(65534), Some(65534)).is_ok() {
+            // capabilities are per thread, don't drop them in the test runner's threads
+            let path = dir.clone();
+            std::thread::spawn(move || {
+                crate::caps::restrict(crate::caps::Capabilities::default()).unwrap();
+                let dir = Dir::open(&path).unwrap();
+                let sub = dir.sub_dir("sub").unwrap();
+                let err = sub.with(O_NOATIME).clone_upgrade().unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+                assert_eq!(list_dir(&sub).unwrap().count(), 0);
+                assert_eq!(list_dir(&dir).unwrap().count(), 1);
+            })
+            .join()
+            .unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mount() {
+        let mount = Mount::of(&Dir::open(".").unwrap()).unwrap();
+        assert_eq!(mount, Mount::of(&Dir::open("src").unwrap()).unwrap());
+        assert_ne!(mount, Mount::of(&Dir::open("/proc").unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,145 @@
+//! Self profiling, see RmrfdBuilder::with_profiling(). Measures the time every thread spends
+//! in the syscalls rmrfd issues itself. The directories listed by the gather threads are
+//! opened and read inside dirinventory and are not covered. Profiling is process wide and
+//! stays enabled once switched on, without it timing costs a single atomic load.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::atomicstats::Counter;
+use crate::pathdisplay::PathEscape;
+
+/// The syscalls being profiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Syscall {
+    /// Opening a directory.
+    Open,
+    /// Fetching the metadata of an entry.
+    Stat,
+    /// Reading directory entries.
+    Getdents,
+    /// Removing a file or directory.
+    Unlink,
+}
+
+const SYSCALLS: [Syscall; 4] = [
+    Syscall::Open,
+    Syscall::Stat,
+    Syscall::Getdents,
+    Syscall::Unlink,
+];
+
+/// Number of calls and the time they took.
+#[derive(Debug, Default)]
+struct Timing {
+    calls: Counter,
+    nanos: Counter,
+}
+
+/// The Timing of every syscall of one thread.
+type Timings = [Timing; SYSCALLS.len()];
+
+/// Timings of all threads by thread name.
+#[derive(Debug, Default)]
+struct Profile {
+    threads: Mutex<HashMap<String, Arc<Timings>>>,
+}
+
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+thread_local! {
+    /// The Timings of the current thread, looked up on its first record only.
+    static TIMINGS: RefCell<Option<Arc<Timings>>> = const { RefCell::new(None) };
+}
+
+/// Switches profiling on.
+pub(crate) fn enable() {
+    PROFILE.get_or_init(Profile::default);
+}
+
+/// Calls 'f' and accounts the time it took to 'syscall' when profiling is enabled.
+pub(crate) fn timed<T>(syscall: Syscall, f: impl FnOnce() -> T) -> T {
+    match PROFILE.get() {
+        None => f(),
+        Some(_) => {
+            let start = Instant::now();
+            let result = f();
+            record(syscall, start.elapsed());
+            result
+        }
+    }
+}
+
+/// Accounts a 'syscall' which took 'elapsed', for callers which measure themselves.
+pub(crate) fn record(syscall: Syscall, elapsed: Duration) {
+    if let Some(profile) = PROFILE.get() {
+        TIMINGS.with_borrow_mut(|timings| {
+            let timings = timings.get_or_insert_with(|| {
+                let name = thread::current().name().unwrap_or("unnamed").to_string();
+                profile.threads.lock().entry(name).or_default().clone()
+            });
+            let timing = &timings[syscall as usize];
+            timing.calls.add(1);
+            timing.nanos.add(elapsed.as_nanos() as u64);
+        });
+    }
+}
+
+/// Logs the breakdown of all threads since profiling was enabled, called when the job for
+/// 'path' is done.
+pub(crate) fn report(path: &Path) {
+    if let Some(profile) = PROFILE.get() {
+        let mut threads: Vec<_> = profile
+            .threads
+            .lock()
+            .iter()
+            .map(|(name, timings)| (name.clone(), timings.clone()))
+            .collect();
+        threads.sort_by(|a, b| a.0.cmp(&b.0));
+        info!("profile: job done: {:?}", path.escaped());
+        for (name, timings) in threads {
+            for syscall in SYSCALLS {
+                let timing = &timings[syscall as usize];
+                let calls = timing.calls.get();
+                if calls > 0 {
+                    let nanos = timing.nanos.get();
+                    info!(
+                        "profile: {}: {:?}: {} calls, {:?} total, {:?} average",
+                        name,
+                        syscall,
+                        calls,
+                        Duration::from_nanos(nanos),
+                        Duration::from_nanos(nanos / calls)
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile() {
+        crate::tests::init_env_logging();
+        enable();
+
+        assert_eq!(timed(Syscall::Stat, || 42), 42);
+        record(Syscall::Unlink, Duration::from_micros(10));
+
+        let name = thread::current().name().unwrap().to_string();
+        let thread = PROFILE.get().unwrap().threads.lock()[&name].clone();
+        assert_eq!(thread[Syscall::Stat as usize].calls.get(), 1);
+        assert_eq!(thread[Syscall::Unlink as usize].nanos.get(), 10_000);
+        report(Path::new("/tmp/rmrf"));
+    }
+}
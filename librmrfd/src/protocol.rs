@@ -0,0 +1,221 @@
+//! The messages on the control socket of the rmrfd daemon, see the README. A client opens a
+//! session for every deletion in which each of its requests is answered by one response. A
+//! message is a keyword and an argument separated by a space and terminated by a nul byte.
+//! The session ends with the first error.
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::RmrfdError;
+
+/// Where the daemon listens when not configured otherwise.
+pub const DEFAULT_SOCKET: &str = "/run/rmrfd.sock";
+
+/// The longest message accepted, a path of PATH_MAX and the keyword.
+const MAX_MESSAGE: u64 = 4096 + 16;
+
+/// A request of a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Asks for a directory to move the absolute 'path' into, see Rmrfd::reserve(). Answered
+    /// with the path of the reserved directory.
+    Path(PathBuf),
+    /// Starts deleting the reserved directory. With -1 the answer comes at once, with 0 when
+    /// the size to be freed is known and with 1 to 100 when that percentage of it is freed.
+    /// Answered with the number of 1k blocks.
+    Sync(i8),
+    /// Cancels the jobs deleting 'path'. Cancelling the jobs of other users is privileged.
+    /// Answered with the number of jobs cancelled.
+    Cancel(PathBuf),
+    /// Resumes the deletion on a paused device, see Rmrfd::resume(). Privileged, answered
+    /// with 0.
+    Resume(libc::dev_t),
+}
+
+/// The answer of the daemon to a Request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The request succeeded, with a path or a number.
+    Ok(OsString),
+    /// The request failed with an errno.
+    Err(i32),
+}
+
+impl Request {
+    /// Reads the next request from 'reader'. Returns None when the client closed the session.
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Request>, RmrfdError> {
+        let message = match read_message(reader)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        match split(&message) {
+            (b"PATH", path) if path.first() == Some(&b'/') => {
+                Ok(Some(Request::Path(PathBuf::from(OsStr::from_bytes(path)))))
+            }
+            (b"SYNC", sync) => parse(sync)
+                .filter(|sync| (-1..=100).contains(sync))
+                .map(|sync| Some(Request::Sync(sync)))
+                .ok_or_else(|| invalid(&message)),
+            (b"CANCEL", path) if path.first() == Some(&b'/') => {
+                Ok(Some(Request::Cancel(PathBuf::from(OsStr::from_bytes(path)))))
+            }
+            (b"RESUME", dev) => parse(dev)
+                .map(|dev| Some(Request::Resume(dev)))
+                .ok_or_else(|| invalid(&message)),
+            _ => Err(invalid(&message)),
+        }
+    }
+
+    /// Writes the request to 'writer'.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Request::Path(path) => write_message(writer, b"PATH", path.as_os_str().as_bytes()),
+            Request::Sync(sync) => write_message(writer, b"SYNC", sync.to_string().as_bytes()),
+            Request::Cancel(path) => {
+                write_message(writer, b"CANCEL", path.as_os_str().as_bytes())
+            }
+            Request::Resume(dev) => write_message(writer, b"RESUME", dev.to_string().as_bytes()),
+        }
+    }
+}
+
+impl Response {
+    /// Reads the response to a request from 'reader'.
+    pub fn read(reader: &mut impl BufRead) -> Result<Response, RmrfdError> {
+        let message = read_message(reader)?
+            .ok_or_else(|| RmrfdError::Protocol(String::from("session closed")))?;
+        match split(&message) {
+            (b"OK", value) => Ok(Response::Ok(OsStr::from_bytes(value).to_os_string())),
+            (b"ERR", errno) => parse(errno)
+                .map(Response::Err)
+                .ok_or_else(|| invalid(&message)),
+            _ => Err(invalid(&message)),
+        }
+    }
+
+    /// Writes the response to 'writer'.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Response::Ok(value) => write_message(writer, b"OK", value.as_bytes()),
+            Response::Err(errno) => write_message(writer, b"ERR", errno.to_string().as_bytes()),
+        }
+    }
+}
+
+impl From<&RmrfdError> for Response {
+    fn from(error: &RmrfdError) -> Self {
+        Response::Err(error.errno())
+    }
+}
+
+/// Reads a message without its terminator, None at the end of the session.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, RmrfdError> {
+    let mut message = Vec::new();
+    reader.take(MAX_MESSAGE).read_until(0, &mut message)?;
+    match message.pop() {
+        None => Ok(None),
+        Some(0) => Ok(Some(message)),
+        Some(_) => Err(RmrfdError::Protocol(String::from(
+            "message too long or truncated",
+        ))),
+    }
+}
+
+/// Writes a message made of 'keyword' and 'argument'.
+fn write_message(writer: &mut impl Write, keyword: &[u8], argument: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(keyword.len() + argument.len() + 2);
+    message.extend_from_slice(keyword);
+    message.push(b' ');
+    message.extend_from_slice(argument);
+    message.push(0);
+    writer.write_all(&message)?;
+    writer.flush()
+}
+
+/// Splits a message into its keyword and argument.
+fn split(message: &[u8]) -> (&[u8], &[u8]) {
+    match message.iter().position(|&byte| byte == b' ') {
+        Some(space) => (&message[..space], &message[space + 1..]),
+        None => (message, &[]),
+    }
+}
+
+/// Parses a numeric argument.
+fn parse<T: std::str::FromStr>(argument: &[u8]) -> Option<T> {
+    std::str::from_utf8(argument).ok()?.parse().ok()
+}
+
+/// Returns the error for the unexpected 'message'.
+fn invalid(message: &[u8]) -> RmrfdError {
+    RmrfdError::Protocol(format!(
+        "invalid message {:?}",
+        String::from_utf8_lossy(message)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = Vec::new();
+        Request::Path(PathBuf::from("/foo/bar baz"))
+            .write(&mut buf)
+            .unwrap();
+        Request::Sync(-1).write(&mut buf).unwrap();
+        Request::Cancel(PathBuf::from("/foo/.rmrf/tmp.1"))
+            .write(&mut buf)
+            .unwrap();
+        Request::Resume(2049).write(&mut buf).unwrap();
+        Response::Ok(OsString::from("/foo/.rmrf/tmp.1/"))
+            .write(&mut buf)
+            .unwrap();
+        Response::Err(libc::EXDEV).write(&mut buf).unwrap();
+        assert_eq!(&buf[..18], b"PATH /foo/bar baz\0");
+
+        let mut reader = Cursor::new(buf);
+        assert_eq!(
+            Request::read(&mut reader).unwrap(),
+            Some(Request::Path(PathBuf::from("/foo/bar baz")))
+        );
+        assert_eq!(Request::read(&mut reader).unwrap(), Some(Request::Sync(-1)));
+        assert_eq!(
+            Request::read(&mut reader).unwrap(),
+            Some(Request::Cancel(PathBuf::from("/foo/.rmrf/tmp.1")))
+        );
+        assert_eq!(Request::read(&mut reader).unwrap(), Some(Request::Resume(2049)));
+        assert_eq!(
+            Response::read(&mut reader).unwrap(),
+            Response::Ok(OsString::from("/foo/.rmrf/tmp.1/"))
+        );
+        assert_eq!(
+            Response::read(&mut reader).unwrap(),
+            Response::Err(libc::EXDEV)
+        );
+        assert_eq!(Request::read(&mut reader).unwrap(), None);
+        assert!(Response::read(&mut reader).is_err());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        for message in [
+            &b"PATH foo\0"[..],
+            b"SYNC 101\0",
+            b"SYNC\0",
+            b"DELETE /foo\0",
+            b"RESUME sda\0",
+            b"PATH /foo",
+        ] {
+            assert!(matches!(
+                Request::read(&mut Cursor::new(message)),
+                Err(RmrfdError::Protocol(_))
+            ));
+        }
+        let long = vec![b'/'; MAX_MESSAGE as usize + 1];
+        assert!(Request::read(&mut Cursor::new(long)).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::{LatencyHistogram, Rmrfd, RmrfdError};
+use crate::platform::{metadata_types, BLOCK_SIZE};
+use crate::pathdisplay::PathEscape;
+
+/// Settings for remove_tree().
+#[derive(Debug, Clone)]
+pub struct RemoveOptions {
+    gather_threads: usize,
+    stat_threads:   usize,
+    min_blockcount: metadata_types::blksize_t,
+    keep_root:      bool,
+}
+
+impl Default for RemoveOptions {
+    fn default() -> Self {
+        RemoveOptions {
+            gather_threads: 16,
+            stat_threads:   16,
+            min_blockcount: 512,
+            keep_root:      false,
+        }
+    }
+}
+
+impl RemoveOptions {
+    /// How many worker threads list directories.
+    pub fn with_gather_threads(mut self, n: usize) -> Self {
+        self.gather_threads = n;
+        self
+    }
+
+    /// How many worker threads fetch metadata.
+    pub fn with_stat_threads(mut self, n: usize) -> Self {
+        self.stat_threads = n;
+        self
+    }
+
+    /// Files larger than these many (512 byte) blocks are deleted largest first, the
+    /// smaller ones in the final sweep.
+    pub fn with_min_blockcount(mut self, c: metadata_types::blksize_t) -> Self {
+        self.min_blockcount = c;
+        self
+    }
+
+    /// Only delete the contents, keep the directory itself.
+    pub fn with_keep_root(mut self, keep_root: bool) -> Self {
+        self.keep_root = keep_root;
+        self
+    }
+}
+
+/// What remove_tree() did.
+#[derive(Debug, Clone, Default)]
+pub struct RemoveSummary {
+    /// Number of files (anything but directories) deleted.
+    pub files:          u64,
+    /// Number of directories deleted.
+    pub dirs:           u64,
+    /// Bytes freed, files with hardlinks outside of the tree are not included.
+    pub bytes:          u64,
+    /// Number of entries which could not be listed or deleted.
+    pub errors:         u64,
+    /// How long the deletion took.
+    pub elapsed:        Duration,
+    /// How long unlinking the files took.
+    pub unlink_latency: LatencyHistogram,
+}
+
+/// Deletes 'path' and everything below it like 'rm -rf', without a running daemon. Uses the
+/// same machinery as Rmrfd: the tree is gathered by a thread pool and the big files are
+/// deleted in size order, then the rest is swept, see JobState. Blocks until done. Errors on
+/// single entries are counted in the summary, only failing to start is returned as error.
+pub fn remove_tree(path: &Path, options: &RemoveOptions) -> Result<RemoveSummary, RmrfdError> {
+    let start = Instant::now();
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        // symlinks are removed, not followed
+        fs::remove_file(path).map_err(|err| RmrfdError::delete(path.to_path_buf(), err))?;
+        return Ok(RemoveSummary {
+            files: 1,
+            bytes: if metadata.nlink() == 1 {
+                metadata.blocks() * BLOCK_SIZE
+            } else {
+                0
+            },
+            elapsed: start.elapsed(),
+            ..RemoveSummary::default()
+        });
+    }
+
+    let path = fs::canonicalize(path)?;
+    let mut summary = RemoveSummary::default();
+    {
+        let rmrfd = Rmrfd::build()
+            .with_gather_threads(options.gather_threads)
+            .with_stat_threads(options.stat_threads)
+            .with_min_blockcount(options.min_blockcount)
+            .with_startup_scan(false)
+            .add_dir(path.as_os_str())?
+            .arm(true)
+            .start()?;
+        rmrfd.delete_dir(&path)?.wait();
+
+        let statistics = rmrfd.statistics();
+        for device in statistics.devices.values() {
+            summary.files += device.files_deleted;
+            summary.dirs += device.dirs_deleted;
+            summary.bytes += device.bytes_freed;
+            summary.unlink_latency.merge(&device.unlink_latency);
+        }
+        summary.errors = statistics.errors;
+    }
+
+    // the job leaves the directory it was registered for in place
+    if !options.keep_root {
+        match fs::remove_dir(&path) {
+            Ok(()) => summary.dirs += 1,
+            Err(err) => {
+                warn!("{}", RmrfdError::delete(path.clone(), err));
+                summary.errors += 1;
+            }
+        }
+    }
+    summary.elapsed = start.elapsed();
+    info!("remove_tree {:?}: {:?}", path.escaped(), summary);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_tree() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-remove-tree-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub/dir")).unwrap();
+        fs::write(root.join("big"), vec![1u8; 256 * 1024]).unwrap();
+        fs::write(root.join("sub/small"), b"small").unwrap();
+        fs::write(root.join("sub/dir/big"), vec![1u8; 256 * 1024]).unwrap();
+
+        let summary = super::remove_tree(
+            &root,
+            &RemoveOptions::default()
+                .with_gather_threads(2)
+                .with_stat_threads(2)
+                .with_min_blockcount(64),
+        )
+        .unwrap();
+
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.dirs, 3);
+        assert_eq!(summary.errors, 0);
+        assert_eq!(summary.unlink_latency.count(), 3);
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::Statistics;
+
+/// Periodically logs a rollup of the statistics, see RmrfdBuilder::with_report_interval().
+/// The reporter thread stops when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Reporter {
+    _stop: Sender<()>,
+}
+
+impl Reporter {
+    /// Starts a thread which calls 'snapshot' every 'interval' and logs what changed since
+    /// the last report.
+    pub(crate) fn start<F>(interval: Duration, snapshot: F) -> io::Result<Reporter>
+    where
+        F: Fn() -> Statistics + Send + 'static,
+    {
+        let (stop, stopped) = bounded(0);
+        thread::Builder::new()
+            .name(String::from("report"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                let mut last = snapshot();
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let current = snapshot();
+                    report(&last, &current, interval);
+                    last = current;
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(Reporter { _stop: stop })
+    }
+}
+
+/// Logs the difference between 'last' and 'current' and the work still pending.
+fn report(last: &Statistics, current: &Statistics, interval: Duration) {
+    let mut devices: Vec<_> = current.devices.iter().collect();
+    devices.sort_by_key(|(device, _)| **device);
+    for (device, stats) in devices {
+        let before = last.devices.get(device).copied().unwrap_or_default();
+        let files = stats.files_deleted - before.files_deleted;
+        let bytes = stats.bytes_freed - before.bytes_freed;
+        info!(
+            "report: device {}: {} files, {} bytes freed in the last {:?}, now {:.0} files/s, \
+             {:.0} bytes/s, {} files, {} bytes total, 99% of unlinks below {:?}",
+            device,
+            files,
+            bytes,
+            interval,
+            stats.files_per_sec,
+            stats.bytes_per_sec,
+            stats.files_deleted,
+            stats.bytes_freed,
+            stats.unlink_latency.percentile(99.0).unwrap_or_default()
+        );
+    }
+    info!(
+        "report: {} jobs, {} entries to gather, {} to stat, {} small files ({} bytes) left, {} \
+         errors",
+        current.jobs,
+        current.gather_queue,
+        current.stat_in_flight,
+        current.small_files.0,
+        current.small_files.1,
+        current.errors
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::statistics::Stats;
+
+    #[test]
+    fn reporter() {
+        crate::tests::init_env_logging();
+
+        let stats = Stats::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let reporter = {
+            let stats = stats.clone();
+            let calls = calls.clone();
+            Reporter::start(Duration::from_millis(5), move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                stats.snapshot((0, 0))
+            })
+            .unwrap()
+        };
+
+        stats.deleted(1, 2, 1024);
+        thread::sleep(Duration::from_millis(50));
+        assert!(calls.load(Ordering::SeqCst) > 1);
+
+        drop(reporter);
+        thread::sleep(Duration::from_millis(20));
+        let calls_after_drop = calls.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(calls.load(Ordering::SeqCst), calls_after_drop);
+    }
+}
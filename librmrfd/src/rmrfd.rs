@@ -1,23 +1,89 @@
-use std::io;
+use std::io::{self, Write};
 use std::fs;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
 use std::ffi::OsStr;
+#[cfg(feature = "config")]
+use std::ffi::OsString;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "config")]
+use std::env;
+#[cfg(feature = "config")]
+use std::str::FromStr;
 use std::collections::HashMap;
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::time::{Duration, SystemTime};
+
+use crossbeam_channel::unbounded;
+use parking_lot::Mutex;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use dirinventory::{
-    openat, openat::metadata_types, Dir, DynResult, Gatherer, GathererBuilder, GathererHandle,
-    InternedName, ObjectPath, ProcessEntry,
+    openat, Dir, Gatherer, GathererBuilder, GathererHandle, InternedName,
+    ObjectPath, ProcessEntry,
+};
+
+use crate::{BuildError, RmrfdError};
+use crate::completion::{JobReport, ReportCallback};
+use crate::dirlock::DirLock;
+use crate::events::EventLog;
+use crate::inventory::Inventory;
+use crate::anchors::{cstr, Anchors};
+use crate::caps::{self, Capabilities, Capability};
+use crate::job::{ErrorBudget, JobHandle, Jobs, Progress};
+use crate::memory::{MemoryGuard, MemoryProbe};
+use crate::plan::{plan, DeletionPlan};
+use crate::report::Reporter;
+use crate::statsfile::StatsFile;
+#[cfg(feature = "config")]
+use crate::shares::parse_shares;
+use crate::platform::{
+    is_readonly_fs, metadata_types, nofile_limits, raise_nofile_limit, Mount, FD_DIR,
 };
+use crate::notify::{DeleteCallback, DeletedFile};
+use crate::{Statistics, Status};
+use crate::objectpath::object_path_interned;
+use crate::pause::{PauseProbe, PauseReason};
+use crate::pathdisplay::{ObjectPathDisplay, PathEscape};
+use crate::overlay;
+use crate::profile;
+use crate::statpool::StatPool;
+use crate::userdir::{check_user_dir, temp_dir, user_dir, user_dir_uid};
+use crate::watchdog::{StuckThread, Watchdog, WatchdogThread};
+use crate::threadprio::{IoClass, Pool, ThreadPriority};
 
-use crate::inventory::{Inventory, ObjectKey};
+/// Fds kept free of the open files limit for everything but the gatherer: the control
+/// socket and its clients, logs, reports and files opened while sweeping.
+const FD_RESERVE: usize = 128;
 
 /// The daemon state
 pub struct Rmrfd {
     inventory_gatherer: Arc<Gatherer>,
-    rmrf_dirs:          HashMap<Arc<ObjectPath>, metadata_types::dev_t>,
+    inventory:          Arc<Inventory>,
+    stat_pool:          Arc<StatPool>,
+    rmrf_dirs:          HashMap<Arc<ObjectPath>, RmrfDir>,
+    dirs_queue:         Arc<DirsQueue>,
+    startup_jobs:       Vec<JobHandle>,
+    user_dirs:          bool,
+    reporter:           Option<Reporter>,
+    stats_file:         Option<StatsFile>,
+    watchdog:           Arc<Watchdog>,
+    watchdog_thread:    Option<WatchdogThread>,
+    anchors:            Arc<Anchors>,
+    error_budget:       Mutex<Option<ErrorBudget>>,
+    pause_probe:        Option<PauseProbe>,
+    armed:              bool,
+    /// The soft and hard limit of open files.
+    fd_limits:          (u64, u64),
+    fd_budget:          usize,
+    memory:             Arc<MemoryGuard>,
+    _memory_probe:      Option<MemoryProbe>,
+    /// The jobs started by reap() by the paths they delete.
+    reaping:            Mutex<HashMap<PathBuf, JobHandle>>,
 }
 
 impl Rmrfd {
@@ -26,14 +92,695 @@ impl Rmrfd {
     pub fn build() -> RmrfdBuilder {
         RmrfdBuilder::default()
     }
+
+    /// Returns the number and the total size in bytes of all files which are not inventoried
+    /// because they are below the min_blockcount threshold.  These files are left for the
+    /// final sweep.
+    pub fn small_files(&self) -> (u64, u64) {
+        self.inventory.jobs().small_files()
+    }
+
+    /// Returns the jobs not completed yet.
+    pub fn jobs(&self) -> Vec<JobHandle> {
+        self.inventory.jobs().handles()
+    }
+
+    /// Returns the jobs for the contents found in the rmrf directories at startup, see
+    /// RmrfdBuilder::with_startup_scan().
+    pub fn startup_jobs(&self) -> &[JobHandle] {
+        &self.startup_jobs
+    }
+
+    /// Submits a job for every rmrf directory which is not empty. Entries may be left over
+    /// from before a crash or were moved in while the daemon was not running. The rmrf
+    /// directories themself are kept. With per user directories each of them becomes a job
+    /// attributed to its user. Directories registered with RmrfdBuilder::add_reap_dir() are
+    /// left to reap().
+    fn scan_rmrf_dirs(&mut self) -> Result<(), RmrfdError> {
+        let mut roots: Vec<_> = self
+            .rmrf_dirs
+            .iter()
+            .filter(|(_, dir)| dir.max_age.is_none())
+            .map(|(path, _)| path.to_pathbuf())
+            .collect();
+        roots.sort();
+        for root in roots {
+            if self.user_dirs {
+                for entry in fs::read_dir(&root)? {
+                    let entry = entry?;
+                    match user_dir_uid(&entry.file_name()) {
+                        Some(uid) if check_user_dir(&entry.path(), uid).is_ok() => {
+                            if fs::read_dir(entry.path())?.next().is_some() {
+                                info!("startup: processing existing entries in {:?}", entry.path());
+                                let object_path = self.rmrf_object_path(&entry.path())?;
+                                let job = self.submit(object_path, Some(uid))?;
+                                self.startup_jobs.push(job);
+                            }
+                        }
+                        _ => warn!("startup: ignoring {:?}", entry.path().escaped()),
+                    }
+                }
+            } else if fs::read_dir(&root)?.next().is_some() {
+                info!("startup: processing existing entries in {:?}", root);
+                let job = self.delete_dir(&root)?;
+                self.startup_jobs.push(job);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the entries of the directories registered with RmrfdBuilder::add_reap_dir()
+    /// which were not modified for longer than the maximum age of their directory, with per
+    /// user directories the entries in those. The age is taken from the modification time of
+    /// the entry itself, for directories that changes only when entries are added or removed
+    /// directly in them. Directories become jobs, which are returned, files are deleted right
+    /// away. Entries still being deleted by an earlier call are skipped. Failures are logged.
+    /// Meant to be called periodically.
+    pub fn reap(&self) -> Vec<JobHandle> {
+        let now = SystemTime::now();
+        let mut reaping = self.reaping.lock();
+        reaping.retain(|_, job| job.state().is_active());
+        let mut jobs = Vec::new();
+        for (root, max_age) in self.rmrf_dirs.iter().filter_map(|(path, dir)| {
+            dir.max_age.map(|max_age| (path.to_pathbuf(), max_age))
+        }) {
+            for (parent, uid) in self.reap_parents(&root) {
+                let entries = match fs::read_dir(&parent) {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        warn!("reap: {:?}: {}", parent.escaped(), err);
+                        continue;
+                    }
+                };
+                for entry in entries {
+                    match entry.and_then(|entry| {
+                        let metadata = entry.metadata()?;
+                        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+                        Ok((entry.path(), metadata.is_dir(), age))
+                    }) {
+                        Ok((path, _, age)) if age <= max_age || reaping.contains_key(&path) => {}
+                        Ok((path, is_dir, age)) => {
+                            info!("reap: {:?} unmodified for {}s", path.escaped(), age.as_secs());
+                            if is_dir {
+                                match self.rmrf_object_path(&path).and_then(|object_path| {
+                                    self.submit(object_path, uid)
+                                }) {
+                                    Ok(job) => {
+                                        reaping.insert(path, job.clone());
+                                        jobs.push(job);
+                                    }
+                                    Err(err) => warn!("reap: {}", err),
+                                }
+                            } else if let Err(err) = self.reap_file(&path) {
+                                warn!("reap: {:?}: {}", path.escaped(), err);
+                            }
+                        }
+                        Err(err) => warn!("reap: in {:?}: {}", parent.escaped(), err),
+                    }
+                }
+            }
+        }
+        jobs
+    }
+
+    /// Returns the directories reap() looks into for the rmrf directory 'root', the per user
+    /// directories in it with their users or 'root' itself.
+    fn reap_parents(&self, root: &Path) -> Vec<(PathBuf, Option<libc::uid_t>)> {
+        if !self.user_dirs {
+            return vec![(root.to_path_buf(), None)];
+        }
+        fs::read_dir(root)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let uid = user_dir_uid(&entry.file_name())?;
+                check_user_dir(&entry.path(), uid).ok()?;
+                Some((entry.path(), Some(uid)))
+            })
+            .collect()
+    }
+
+    /// Deletes the file 'path' found by reap() when armed. It is unlinked relative to its
+    /// parent directory opened below the anchors.
+    fn reap_file(&self, path: &Path) -> io::Result<()> {
+        if !self.armed {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        let (dir, name) = self.anchors.open_parent(path, &mut buf)?;
+        dir.remove_file(cstr(&mut buf, name)?)
+    }
+
+    /// Returns a snapshot of the deletion counters, error counts and queue depths.
+    pub fn statistics(&self) -> Statistics {
+        collect_statistics(&self.inventory, &self.inventory_gatherer, &self.stat_pool)
+    }
+
+    /// Returns the queue depths of the deletion pipeline. Cheaper than statistics(), meant to
+    /// be polled to see whether scanning or deleting is the bottleneck.
+    pub fn status(&self) -> Status {
+        let (files_per_sec, bytes_per_sec) = self.inventory.stats().throughput();
+        let (memory_pressure, memory_used) = self.memory.pressure();
+        Status {
+            jobs:           self.inventory.jobs().running(),
+            dirs_queue:     self.dirs_queue.len() as usize,
+            gather_queue:   gather_queue(&self.inventory_gatherer),
+            stat_queue:     self.stat_pool.queued(),
+            stat_in_flight: self.stat_pool.in_flight(),
+            delete_queue:   self.inventory.stats().pending() as usize,
+            stuck_threads:  self.watchdog.stuck().len(),
+            files_per_sec,
+            bytes_per_sec,
+            fd_soft_limit:  self.fd_limits.0,
+            fd_hard_limit:  self.fd_limits.1,
+            fd_budget:      self.fd_budget,
+            fds_used:       dirinventory::used_handles(),
+            memory_used,
+            memory_pressure,
+        }
+    }
+
+    /// Returns the devices the deletion is paused on and why. Deletion pauses automatically
+    /// when a filesystem goes read-only, out of space or freezes, see
+    /// RmrfdBuilder::with_pause_probe().
+    pub fn paused(&self) -> Vec<(metadata_types::dev_t, PauseReason)> {
+        self.inventory.pauses().paused()
+    }
+
+    /// Resumes the deletion on the device 'dev'. When the reason to pause persists it is
+    /// paused again on the next failure. Returns 'false' when it was not paused.
+    pub fn resume(&self, dev: metadata_types::dev_t) -> bool {
+        info!("resume: device {}", dev);
+        self.inventory.pauses().resume(dev)
+    }
+
+    /// Returns 'true' unless 'capability' is known to be missing, see
+    /// RmrfdBuilder::with_drop_capabilities(). Without support for capabilities in the OS
+    /// all are assumed to be held.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        !caps::lacks(capability)
+    }
+
+    /// Returns the worker threads which are busy with the same item for longer than the
+    /// watchdog timeout, see RmrfdBuilder::with_watchdog(). Empty when all threads make
+    /// progress.
+    pub fn health(&self) -> Vec<StuckThread> {
+        self.watchdog.stuck()
+    }
+
+    /// Creates the ObjectPath for an arbitrary path, for example one passed in from a
+    /// client.  When the path is below a registered rmrf directory then the ObjectPath of
+    /// that directory becomes the parent, the remaining names are interned.
+    pub fn object_path(&self, path: &Path) -> Result<Arc<ObjectPath>, RmrfdError> {
+        object_path_interned(self.stat_pool.names(), self.rmrf_dirs.keys(), path)
+            .map_err(|_| RmrfdError::InvalidPath(path.to_path_buf()))
+    }
+
+    /// Deletes the directory 'path' which must be below a registered rmrf directory. Returns
+    /// a handle to wait for, observe or cancel the deletion.
+    pub fn delete_dir(&self, path: &Path) -> Result<JobHandle, RmrfdError> {
+        self.submit(self.rmrf_object_path(path)?, None)
+    }
+
+    /// Deletes the directory 'path' on behalf of the user 'uid'. With per user directories
+    /// enabled the path must be inside the users own directory, see
+    /// RmrfdBuilder::with_user_dirs(). The job is attributed to 'uid'.
+    pub fn delete_dir_as(&self, path: &Path, uid: libc::uid_t) -> Result<JobHandle, RmrfdError> {
+        let object_path = self.rmrf_object_path(path)?;
+        if self.user_dirs {
+            let pathbuf = object_path.to_pathbuf();
+            let user_dir = self
+                .rmrf_dirs
+                .keys()
+                .map(|dir| dir.to_pathbuf().join(uid.to_string()))
+                .find(|user_dir| pathbuf.starts_with(user_dir) && pathbuf != *user_dir)
+                .ok_or_else(|| RmrfdError::PermissionDenied(pathbuf.clone()))?;
+            check_user_dir(&user_dir, uid)
+                .map_err(|_| RmrfdError::PermissionDenied(user_dir.clone()))?;
+        }
+        self.submit(object_path, Some(uid))
+    }
+
+    /// Returns the directory of the user 'uid' in the rmrf directory 'rmrf_dir', it is
+    /// created when it does not exist yet.
+    pub fn user_dir(&self, rmrf_dir: &Path, uid: libc::uid_t) -> Result<PathBuf, RmrfdError> {
+        if !self.user_dirs
+            || !self
+                .rmrf_dirs
+                .keys()
+                .any(|dir| dir.to_pathbuf() == rmrf_dir)
+        {
+            return Err(RmrfdError::PermissionDenied(rmrf_dir.to_path_buf()));
+        }
+        user_dir(rmrf_dir, uid).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => {
+                RmrfdError::PermissionDenied(rmrf_dir.join(uid.to_string()))
+            }
+            _ => RmrfdError::Io(err),
+        })
+    }
+
+    /// Reserves a new directory for the user 'uid' to move 'path' into, it is deleted with
+    /// delete_dir_as() afterwards. The directory is created in a rmrf directory on the
+    /// filesystem of 'path' at the same directory level as 'path' or above, the deepest when
+    /// several qualify. With per user directories it is created in the one of 'uid'.
+    pub fn reserve(&self, path: &Path, uid: libc::uid_t) -> Result<PathBuf, RmrfdError> {
+        let invalid = || RmrfdError::InvalidPath(path.to_path_buf());
+        let name = path.file_name().ok_or_else(invalid)?;
+        let parent = fs::canonicalize(path.parent().ok_or_else(invalid)?)?;
+        // on overlayfs files report the device of the layer they come from, directories the
+        // one of the overlay
+        let metadata = fs::symlink_metadata(parent.join(name))?;
+        let dev = match metadata.is_dir() {
+            true => metadata.dev(),
+            false => fs::metadata(&parent)?.dev(),
+        };
+        let rmrf_dir = self
+            .rmrf_dirs
+            .iter()
+            .filter(|(_, dir)| dir.dev == dev && dir.fd.is_none())
+            .map(|(path, _)| path.to_pathbuf())
+            .filter(|dir| dir.parent().is_some_and(|level| parent.starts_with(level)))
+            .max_by_key(|dir| dir.as_os_str().len())
+            .ok_or_else(|| RmrfdError::NoRmrfDir(path.to_path_buf()))?;
+        let dir = if self.user_dirs {
+            self.user_dir(&rmrf_dir, uid)?
+        } else {
+            rmrf_dir
+        };
+        info!("reserve: {:?} for {:?} uid {}", dir.escaped(), path.escaped(), uid);
+        Ok(temp_dir(&dir, uid)?)
+    }
+
+    /// Starts a job deleting 'object_path'. The rmrf directories and the per user directories
+    /// themself are emptied but kept.
+    fn submit(
+        &self,
+        object_path: Arc<ObjectPath>,
+        uid: Option<libc::uid_t>,
+    ) -> Result<JobHandle, RmrfdError> {
+        info!("delete_dir: {:?} uid {:?}", object_path.display(), uid);
+        let pathbuf = object_path.to_pathbuf();
+        // Only the job root is opened by path in the gatherer, everything below relative to
+        // it. Refuse paths with a symlink or a mount between the rmrf directory and the root.
+        let mount = self
+            .anchors
+            .open_dir(&pathbuf, &mut Vec::new())
+            .and_then(|dir| Mount::of(&dir))
+            .map_err(|err| match err.raw_os_error() {
+                Some(libc::EXDEV) => RmrfdError::Mountpoint(pathbuf.clone()),
+                _ => RmrfdError::Gather {
+                    path:   pathbuf.clone(),
+                    source: err,
+                },
+            })?;
+        match overlay::mounted_layer(&pathbuf) {
+            Ok(Some(layer)) => {
+                warn!("{:?} overlaps the overlay layer {:?}", pathbuf.escaped(), layer.escaped());
+                return Err(RmrfdError::OverlayLayer(pathbuf));
+            }
+            Ok(None) => {}
+            Err(err) => debug!("overlay layers of {:?}: {}", pathbuf.escaped(), err),
+        }
+        let keep_root = self.rmrf_dirs.keys().map(|dir| dir.to_pathbuf()).any(|dir| {
+            dir == pathbuf || (self.user_dirs && pathbuf.parent() == Some(dir.as_path()))
+        });
+        let epoch = self.dirs_queue.queued();
+        let job = self.inventory.jobs().submit(
+            &object_path,
+            uid,
+            keep_root,
+            mount,
+            self.stat_pool.scanned(),
+            epoch,
+        );
+        job.set_error_budget(*self.error_budget.lock());
+        self.inventory_gatherer.load_dir_recursive(object_path);
+        Ok(job)
+    }
+
+    /// Sends the Progress of 'job' every 'interval' on the returned channel. When the job is
+    /// done or cancelled a last report is sent and the channel is closed. Dropping the
+    /// receiver stops the reports at the next interval.
+    pub fn progress(
+        &self,
+        job: &JobHandle,
+        interval: Duration,
+    ) -> Result<mpsc::Receiver<Progress>, RmrfdError> {
+        let (sender, receiver) = mpsc::channel();
+        let job = job.clone();
+        let stat_pool = self.stat_pool.clone();
+        thread::Builder::new()
+            .name(String::from("progress"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                loop {
+                    let active = job.wait_timeout(interval).is_active();
+                    let progress =
+                        job.progress_report(stat_pool.scanned(), stat_pool.in_flight() as u64);
+                    if sender.send(progress).is_err() || !active {
+                        break;
+                    }
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(receiver)
+    }
+
+    /// Async variant of delete_dir(), submitting never blocks for long. Await the deletion
+    /// with JobHandle::wait_async().
+    #[cfg(feature = "async")]
+    pub async fn submit_async(&self, path: &Path) -> Result<JobHandle, RmrfdError> {
+        self.delete_dir(path)
+    }
+
+    /// Scans the directory 'path' which must be below a registered rmrf directory without
+    /// deleting anything and reports what a deletion would do. The scan runs in the calling
+    /// thread.
+    pub fn plan(&self, path: &Path) -> Result<DeletionPlan, RmrfdError> {
+        let object_path = self.rmrf_object_path(path)?;
+        Ok(plan(&object_path.to_pathbuf())?)
+    }
+
+    /// Returns the ObjectPath for 'path' when it is below a registered rmrf directory. The
+    /// path is canonicalized and checked on its open handle, see Anchors::resolve().
+    fn rmrf_object_path(&self, path: &Path) -> Result<Arc<ObjectPath>, RmrfdError> {
+        let resolved = self
+            .anchors
+            .resolve(path)
+            .map_err(|err| RmrfdError::Gather {
+                path:   path.to_path_buf(),
+                source: err,
+            })?
+            .ok_or_else(|| RmrfdError::NotBelowRmrfDir(path.to_path_buf()))?;
+        self.object_path(&resolved)
+    }
+
+    /// Changes the configuration of the running daemon. Only the settings given in 'request'
+    /// are changed. The changes are applied at safe points, entries already in the pipeline
+    /// are processed with the old settings.
+    pub fn reconfigure(&self, request: ReconfigRequest) -> Result<(), RmrfdError> {
+        if let Some(min_blockcount) = request.min_blockcount {
+            info!("reconfigure: min_blockcount {}", min_blockcount);
+        }
+        if let Some(early_delete_percent) = request.early_delete_percent {
+            info!("reconfigure: early_delete_percent {}", early_delete_percent);
+        }
+        // under memory pressure the thresholds are applied once it is relieved
+        self.memory
+            .configure(request.min_blockcount, request.early_delete_percent);
+        if let Some(stat_threads) = request.stat_threads {
+            info!("reconfigure: stat_threads {}", stat_threads);
+            self.stat_pool.set_threads(stat_threads)?;
+        }
+        if let Some(error_budget) = request.error_budget {
+            info!("reconfigure: {:?}", error_budget);
+            *self.error_budget.lock() = Some(error_budget);
+        }
+        if let Some(uid_shares) = request.uid_shares {
+            info!("reconfigure: uid_shares {:?}", uid_shares);
+            self.inventory.jobs().shares().set(uid_shares);
+        }
+        Ok(())
+    }
+}
+
+/// Settings to be changed on a running Rmrfd, see Rmrfd::reconfigure(). Anything not set
+/// is left unchanged. The number of gather and inventory threads and the rmrf directories
+/// are fixed at start.
+#[derive(Debug, Default, Clone)]
+pub struct ReconfigRequest {
+    min_blockcount:       Option<metadata_types::blksize_t>,
+    early_delete_percent: Option<metadata_types::blksize_t>,
+    stat_threads:         Option<usize>,
+    error_budget:         Option<ErrorBudget>,
+    uid_shares:           Option<Vec<(libc::uid_t, u32)>>,
+}
+
+impl ReconfigRequest {
+    /// The variables of RmrfdBuilder::from_vars() which from_vars() reads as well, the only
+    /// ones which can be changed on a running Rmrfd.
+    pub const VARS: [&'static str; 5] = [
+        "RMRFD_STAT_THREADS",
+        "RMRFD_MIN_BLOCKS",
+        "RMRFD_EARLY_DELETE_PERCENT",
+        "RMRFD_ERROR_PERCENT",
+        "RMRFD_UID_SHARES",
+    ];
+
+    /// Creates a ReconfigRequest for the VARS 'vars' returns a value for, parsed like
+    /// RmrfdBuilder::from_vars() does. Used to apply a changed configuration file.
+    #[cfg(feature = "config")]
+    pub fn from_vars(vars: impl Fn(&str) -> Option<OsString>) -> Result<Self, BuildError> {
+        let mut request = ReconfigRequest::default();
+
+        if let Some(n) = var_parse(&vars, "RMRFD_STAT_THREADS")? {
+            if n == 0 {
+                return Err(BuildError::NoThreads("stat"));
+            }
+            request = request.with_stat_threads(n);
+        }
+        if let Some(c) = var_parse(&vars, "RMRFD_MIN_BLOCKS")? {
+            request = request.with_min_blockcount(c);
+        }
+        if let Some(c) = var_parse(&vars, "RMRFD_EARLY_DELETE_PERCENT")? {
+            request = request.with_early_delete_percent(c);
+        }
+        if let Some(percent) = var_parse(&vars, "RMRFD_ERROR_PERCENT")? {
+            request = request.with_error_budget(ErrorBudget::new(percent));
+        }
+        if let Some(shares) = var_shares(&vars)? {
+            request.uid_shares = Some(shares);
+        }
+
+        Ok(request)
+    }
+
+    /// Filter for files only larger than these much (512 byte) blocks.
+    pub fn with_min_blockcount(mut self, c: metadata_types::blksize_t) -> Self {
+        self.min_blockcount = Some(c);
+        self
+    }
+
+    /// Percentage of the largest file seen so far a single linked file must reach to be
+    /// deleted early.
+    pub fn with_early_delete_percent(mut self, c: metadata_types::blksize_t) -> Self {
+        self.early_delete_percent = Some(c);
+        self
+    }
+
+    /// How many threads fetch the metadata of directory entries.
+    pub fn with_stat_threads(mut self, n: usize) -> Self {
+        assert!(n > 0, "Must at least use one thread");
+        self.stat_threads = Some(n);
+        self
+    }
+
+    /// The ErrorBudget of jobs submitted from now on, running jobs keep theirs.
+    pub fn with_error_budget(mut self, budget: ErrorBudget) -> Self {
+        self.error_budget = Some(budget);
+        self
+    }
+
+    /// The share of the user 'uid', see RmrfdBuilder::with_uid_share(). The shares given
+    /// replace all previous ones, users not given get the default share of 1 again.
+    pub fn with_uid_share(mut self, uid: libc::uid_t, share: u32) -> Self {
+        assert!(share > 0, "A share must be at least 1");
+        self.uid_shares.get_or_insert_with(Vec::new).push((uid, share));
+        self
+    }
+}
+
+impl Drop for Rmrfd {
+    fn drop(&mut self) {
+        self.inventory.shutdown();
+    }
+}
+
+/// Counts the directories handed to the gatherer and not finished yet, its queue itself can't
+/// be inspected. Every time the count drops to zero the gatherer drained and the epoch
+/// advances. Both share one atomic, the directories in the low 32 bits, the epoch above.
+#[derive(Debug, Default)]
+pub(crate) struct DirsQueue(AtomicU64);
+
+/// The directories in the state of a DirsQueue.
+const DIRS_MASK: u64 = u32::MAX as u64;
+
+/// One epoch in the state of a DirsQueue.
+const EPOCH: u64 = DIRS_MASK + 1;
+
+thread_local! {
+    /// The directory the gather thread is listing, known from its first entry on.
+    static LISTING: RefCell<Option<Arc<ObjectPath>>> = const { RefCell::new(None) };
+}
+
+impl DirsQueue {
+    /// Accounts a directory handed to the gatherer, returns the epoch it is queued in. It is
+    /// gathered completely once the epoch advanced.
+    fn queued(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) / EPOCH
+    }
+
+    /// Accounts a finished directory.
+    fn done(&self) {
+        let mut state = self.0.load(Ordering::SeqCst);
+        loop {
+            let next = match state & DIRS_MASK {
+                0 => return,
+                1 => (state & !DIRS_MASK).wrapping_add(EPOCH),
+                _ => state - 1,
+            };
+            match self
+                .0
+                .compare_exchange_weak(state, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Returns how often the gatherer drained.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) / EPOCH
+    }
+
+    /// Remembers that the calling gather thread lists 'dir'.
+    fn listed(&self, dir: &Arc<ObjectPath>) {
+        LISTING.with_borrow_mut(|listing| {
+            if !listing.as_ref().is_some_and(|listing| Arc::ptr_eq(listing, dir)) {
+                *listing = Some(dir.clone());
+            }
+        });
+    }
+
+    /// Accounts an error for 'path'. An unreadable entry of the directory being listed is
+    /// followed by its EndOfDirectory, only a directory which could not be opened or listed
+    /// is done here. A listing failing before its first entry can't be told apart from
+    /// that and is counted as done early.
+    fn failed(&self, path: &Arc<ObjectPath>) {
+        if !LISTING.with_borrow(|listing| {
+            listing.as_ref().is_some_and(|listing| Arc::ptr_eq(listing, path))
+        }) {
+            self.done();
+        }
+    }
+
+    /// Accounts the end of the listing of a directory.
+    fn end_of_directory(&self) {
+        LISTING.set(None);
+        self.done();
+    }
+
+    fn len(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) & DIRS_MASK
+    }
+}
+
+/// Returns the number of entries waiting in the output channels of 'gatherer'.
+fn gather_queue(gatherer: &Gatherer) -> usize {
+    gatherer
+        .channels_as_vec()
+        .iter()
+        .map(|channel| channel.len())
+        .sum()
+}
+
+/// Collects the statistics of all parts of the daemon, see Rmrfd::statistics().
+fn collect_statistics(
+    inventory: &Inventory,
+    gatherer: &Gatherer,
+    stat_pool: &StatPool,
+) -> Statistics {
+    let mut statistics = inventory.stats().snapshot(inventory.jobs().small_files());
+    statistics.jobs = inventory.jobs().running();
+    statistics.gather_queue = gather_queue(gatherer);
+    statistics.stat_queue = stat_pool.queued();
+    statistics.stat_in_flight = stat_pool.in_flight();
+    (statistics.stat_in_flight_max, statistics.stat_in_flight_avg) = stat_pool.in_flight_peak();
+    statistics
+}
+
+/// Parses the variable 'var' when 'vars' returns a value for it.
+#[cfg(feature = "config")]
+fn var_parse<T: FromStr>(
+    vars: &impl Fn(&str) -> Option<OsString>,
+    var: &'static str,
+) -> Result<Option<T>, BuildError> {
+    match vars(var).map(OsString::into_string) {
+        Some(Ok(value)) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| BuildError::InvalidEnv { var, value }),
+        None => Ok(None),
+        Some(Err(value)) => Err(BuildError::InvalidEnv {
+            var,
+            value: value.to_string_lossy().into_owned(),
+        }),
+    }
+}
+
+/// Parses the shares in RMRFD_UID_SHARES when 'vars' returns a value for it.
+#[cfg(feature = "config")]
+fn var_shares(
+    vars: &impl Fn(&str) -> Option<OsString>,
+) -> Result<Option<Vec<(libc::uid_t, u32)>>, BuildError> {
+    let var = "RMRFD_UID_SHARES";
+    let Some(value) = vars(var) else {
+        return Ok(None);
+    };
+    let value = value.to_string_lossy().into_owned();
+    parse_shares(&value)
+        .map(Some)
+        .ok_or(BuildError::InvalidEnv { var, value })
+}
+
+/// A registered rmrf directory.
+#[derive(Debug)]
+struct RmrfDir {
+    dev:     metadata_types::dev_t,
+    /// Entries are only deleted when older, see RmrfdBuilder::add_reap_dir().
+    max_age: Option<Duration>,
+    /// Keeps the directory open when it was registered by fd.
+    fd:      Option<OwnedFd>,
+    /// Held while the daemon runs.
+    lock:    Option<Arc<DirLock>>,
 }
 
 /// Builder for constructing the daemon
 pub struct RmrfdBuilder {
     gatherer_builder:     GathererBuilder,
+    gather_threads:       usize,
+    inventory_backlog:    usize,
+    inventory_threads:    usize,
+    inventory_channels:   usize,
+    stat_threads:         usize,
+    stat_batch:           usize,
+    stat_flush_interval:  Duration,
     min_blockcount:       metadata_types::blksize_t,
     early_delete_percent: metadata_types::blksize_t,
-    rmrf_dirs:            HashMap<Arc<ObjectPath>, metadata_types::dev_t>,
+    rmrf_dirs:            HashMap<Arc<ObjectPath>, RmrfDir>,
+    gather_priority:      ThreadPriority,
+    stat_priority:        ThreadPriority,
+    inventory_priority:   ThreadPriority,
+    on_deleted:           Option<Arc<DeleteCallback>>,
+    startup_scan:         bool,
+    user_dirs:            bool,
+    report_interval:      Option<Duration>,
+    stats_file:           Option<(PathBuf, Duration)>,
+    profiling:            bool,
+    watchdog_timeout:     Option<Duration>,
+    event_log:            Option<Box<dyn Write + Send>>,
+    report_dir:           Option<PathBuf>,
+    on_report:            Option<Arc<ReportCallback>>,
+    drop_capabilities:    bool,
+    error_budget:         Option<ErrorBudget>,
+    uid_shares:           Vec<(libc::uid_t, u32)>,
+    fd_limit:             Option<usize>,
+    memory_limit:         Option<u64>,
+    pause_probe:          Option<Duration>,
     rmrf_armed:           bool,
 }
 
@@ -42,37 +789,197 @@ impl Default for RmrfdBuilder {
     fn default() -> Self {
         RmrfdBuilder {
             gatherer_builder:     Gatherer::build(),
-            /// Filter for files bigger than 32kb smaller ones would only bloat memory and
-            /// give no much benefit when deleting in size order.
+            gather_threads:       16,
+            inventory_backlog:    0,
+            inventory_threads:    1,
+            inventory_channels:   0,
+            stat_threads:         16,
+            stat_batch:           256,
+            stat_flush_interval:  Duration::from_millis(10),
+            // Filter for files bigger than 256kb smaller ones would only bloat memory and
+            // give no much benefit when deleting in size order.
             min_blockcount:       512,
             early_delete_percent: 50,
             rmrf_dirs:            HashMap::new(),
+            gather_priority:      ThreadPriority::default(),
+            stat_priority:        ThreadPriority::default(),
+            inventory_priority:   ThreadPriority::default(),
+            on_deleted:           None,
+            startup_scan:         true,
+            user_dirs:            false,
+            report_interval:      None,
+            stats_file:           None,
+            profiling:            false,
+            watchdog_timeout:     None,
+            event_log:            None,
+            report_dir:           None,
+            on_report:            None,
+            drop_capabilities:    false,
+            error_budget:         None,
+            uid_shares:           Vec::new(),
+            fd_limit:             None,
+            memory_limit:         None,
+            pause_probe:          Some(Duration::from_secs(10)),
             rmrf_armed:           false,
         }
     }
 }
 
 impl RmrfdBuilder {
+    /// Creates a RmrfdBuilder with the defaults overridden from environment variables:
+    /// RMRFD_THREADS (gather threads), RMRFD_INVENTORY_THREADS, RMRFD_INVENTORY_CHANNELS,
+    /// RMRFD_INVENTORY_BACKLOG, RMRFD_STAT_THREADS, RMRFD_STAT_BATCH, RMRFD_STAT_FLUSH_MS,
+    /// RMRFD_MIN_BLOCKS, RMRFD_EARLY_DELETE_PERCENT, RMRFD_REPORT_SECS, RMRFD_EVENT_LOG (a
+    /// file the events are appended to), RMRFD_REPORT_DIR (the job reports are written to),
+    /// RMRFD_STATS_FILE (rewritten every second), RMRFD_PROFILE ('true' or 'false'),
+    /// RMRFD_WATCHDOG_SECS, RMRFD_DROP_CAPS ('true' or 'false'), RMRFD_ERROR_PERCENT (the
+    /// percent of an ErrorBudget), RMRFD_UID_SHARES (like '1000:4,1001:2', see
+    /// with_uid_share()), RMRFD_FD_LIMIT (see with_fd_limit()), RMRFD_MEMORY_LIMIT_MB (see
+    /// with_memory_limit()) and RMRFD_SPOOL_DIRS (a ':' separated list of rmrf directories).
+    /// Arming is deliberately not configurable this way.
+    #[cfg(feature = "config")]
+    pub fn from_env() -> Result<Self, BuildError> {
+        RmrfdBuilder::from_vars(|var| env::var_os(var))
+    }
+
+    /// Creates a RmrfdBuilder like from_env() with the variables looked up by 'vars', for
+    /// configuration files which set them. ReconfigRequest::from_vars() applies the
+    /// settings which can be changed while running.
+    #[cfg(feature = "config")]
+    pub fn from_vars(vars: impl Fn(&str) -> Option<OsString>) -> Result<Self, BuildError> {
+        let mut builder = RmrfdBuilder::default();
+
+        if let Some(n) = var_parse(&vars, "RMRFD_THREADS")? {
+            builder = builder.with_gather_threads(n);
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_INVENTORY_THREADS")? {
+            builder = builder.with_inventory_threads(n);
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_INVENTORY_CHANNELS")? {
+            builder = builder.with_inventory_channels(n);
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_INVENTORY_BACKLOG")? {
+            builder = builder.with_inventory_backlog(n);
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_STAT_THREADS")? {
+            builder = builder.with_stat_threads(n);
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_STAT_BATCH")? {
+            builder = builder.with_stat_batch(n);
+        }
+        if let Some(ms) = var_parse(&vars, "RMRFD_STAT_FLUSH_MS")? {
+            builder = builder.with_stat_flush_interval(Duration::from_millis(ms));
+        }
+        if let Some(c) = var_parse(&vars, "RMRFD_MIN_BLOCKS")? {
+            builder = builder.with_min_blockcount(c);
+        }
+        if let Some(c) = var_parse(&vars, "RMRFD_EARLY_DELETE_PERCENT")? {
+            builder = builder.with_early_delete_percent(c);
+        }
+        if let Some(secs) = var_parse(&vars, "RMRFD_REPORT_SECS")? {
+            builder = builder.with_report_interval(Duration::from_secs(secs));
+        }
+        if let Some(path) = vars("RMRFD_STATS_FILE") {
+            builder = builder.with_stats_file(path, Duration::from_secs(1));
+        }
+        if let Some(secs) = var_parse(&vars, "RMRFD_WATCHDOG_SECS")? {
+            builder = builder.with_watchdog(Duration::from_secs(secs));
+        }
+        if let Some(profiling) = var_parse(&vars, "RMRFD_PROFILE")? {
+            builder = builder.with_profiling(profiling);
+        }
+        if let Some(drop) = var_parse(&vars, "RMRFD_DROP_CAPS")? {
+            builder = builder.with_drop_capabilities(drop);
+        }
+        if let Some(percent) = var_parse(&vars, "RMRFD_ERROR_PERCENT")? {
+            builder = builder.with_error_budget(ErrorBudget::new(percent));
+        }
+        if let Some(n) = var_parse(&vars, "RMRFD_FD_LIMIT")? {
+            builder = builder.with_fd_limit(n);
+        }
+        if let Some(mb) = var_parse::<u64>(&vars, "RMRFD_MEMORY_LIMIT_MB")? {
+            builder = builder.with_memory_limit(mb << 20);
+        }
+        if let Some(shares) = var_shares(&vars)? {
+            for (uid, share) in shares {
+                builder = builder.with_uid_share(uid, share);
+            }
+        }
+        if let Some(path) = vars("RMRFD_EVENT_LOG") {
+            builder = builder.with_event_log(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            );
+        }
+        if let Some(dir) = vars("RMRFD_REPORT_DIR") {
+            builder = builder.with_report_dir(dir);
+        }
+        if let Some(dirs) = vars("RMRFD_SPOOL_DIRS") {
+            for dir in env::split_paths(&dirs).filter(|dir| !dir.as_os_str().is_empty()) {
+                builder = builder.add_dir(dir.as_os_str())?;
+            }
+        }
+
+        Ok(builder)
+    }
+
     /// How many InventoryEntries can be pending. The consumer that adds InventoryEntries to
     /// the InventoryGatherer should in most cases be much faster than the directory worker
-    /// threads. Thus this number can be small.
+    /// threads. Thus this number can be small. When zero (the default) 4096 entries per
+    /// gather thread are used.
     pub fn with_inventory_backlog(mut self, n: usize) -> Self {
         self.rmrf_armed = false;
-        self.gatherer_builder = self.gatherer_builder.with_inventory_backlog(n);
+        self.inventory_backlog = n;
         self
     }
 
     /// How many worker threads are used to gather the inventory.
     pub fn with_gather_threads(mut self, n: usize) -> Self {
         self.rmrf_armed = false;
-        self.gatherer_builder = self.gatherer_builder.with_gather_threads(n);
+        self.gather_threads = n;
         self
     }
 
     /// The number of threads the inventory uses to process entries.
     pub fn with_inventory_threads(mut self, n: usize) -> Self {
         self.rmrf_armed = false;
-        self.gatherer_builder = self.gatherer_builder.with_output_channels(n);
+        self.inventory_threads = n;
+        self
+    }
+
+    /// The number of channels entries are sharded over on their way to the inventory. The
+    /// channels are distributed over the inventory threads, each thread selects on all its
+    /// channels. When zero (the default) one channel per inventory thread is used.
+    pub fn with_inventory_channels(mut self, n: usize) -> Self {
+        self.rmrf_armed = false;
+        self.inventory_channels = n;
+        self
+    }
+
+    /// How many threads fetch the metadata of directory entries. This is independent of the
+    /// number of gather threads which only list directories. On high latency filesystems
+    /// (NFS) it is beneficial to have many more stat calls in flight than gather threads.
+    pub fn with_stat_threads(mut self, n: usize) -> Self {
+        self.rmrf_armed = false;
+        self.stat_threads = n;
+        self
+    }
+
+    /// Number of entries the stat threads batch together before sending them to the
+    /// inventory. Larger batches reduce the per message overhead.
+    pub fn with_stat_batch(mut self, n: usize) -> Self {
+        self.rmrf_armed = false;
+        self.stat_batch = n;
+        self
+    }
+
+    /// Maximum time a stat thread holds back a partial batch. Batches are flushed anyway
+    /// whenever there is no more work queued.
+    pub fn with_stat_flush_interval(mut self, interval: Duration) -> Self {
+        self.rmrf_armed = false;
+        self.stat_flush_interval = interval;
         self
     }
 
@@ -91,6 +998,216 @@ impl RmrfdBuilder {
         self
     }
 
+    /// Sets the IO scheduling class for the threads of 'pool'. By default threads inherit
+    /// the class of the process. Linux only, elsewhere a warning is logged.
+    pub fn with_io_class(mut self, pool: Pool, class: IoClass) -> Self {
+        self.rmrf_armed = false;
+        self.priority_mut(pool).io_class = Some(class);
+        self
+    }
+
+    /// Sets the nice value for the threads of 'pool'. Raising the priority (negative
+    /// values) needs CAP_SYS_NICE. Linux only, elsewhere a warning is logged.
+    pub fn with_nice(mut self, pool: Pool, nice: i32) -> Self {
+        self.rmrf_armed = false;
+        self.priority_mut(pool).nice = Some(nice);
+        self
+    }
+
+    /// Registers a callback which is called after every deleted file, for example to keep
+    /// external indexes up to date. It runs in the inventory threads and must return quickly.
+    /// Files removed by the final sweep of a job are only counted, they are not reported.
+    pub fn with_delete_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&DeletedFile) + Send + Sync + 'static,
+    {
+        self.rmrf_armed = false;
+        self.on_deleted = Some(Arc::new(callback));
+        self
+    }
+
+    /// Whether the contents already present in the rmrf directories are deleted at start.
+    /// Enabled by default.
+    pub fn with_startup_scan(mut self, scan: bool) -> Self {
+        self.rmrf_armed = false;
+        self.startup_scan = scan;
+        self
+    }
+
+    /// Use one subdirectory per user in the rmrf directories, named by the numeric uid. They
+    /// are created on demand by Rmrfd::user_dir() owned by the user with mode 0700.
+    /// Submissions with Rmrfd::delete_dir_as() must be inside the users own directory.
+    pub fn with_user_dirs(mut self, user_dirs: bool) -> Self {
+        self.rmrf_armed = false;
+        self.user_dirs = user_dirs;
+        self
+    }
+
+    fn priority_mut(&mut self, pool: Pool) -> &mut ThreadPriority {
+        match pool {
+            Pool::Gather => &mut self.gather_priority,
+            Pool::Stat => &mut self.stat_priority,
+            Pool::Inventory => &mut self.inventory_priority,
+        }
+    }
+
+    /// Logs a summary every 'interval': bytes freed and files deleted per device since the
+    /// last report and the work still pending. Off by default.
+    pub fn with_report_interval(mut self, interval: Duration) -> Self {
+        self.rmrf_armed = false;
+        self.report_interval = Some(interval);
+        self
+    }
+
+    /// Writes the statistics every 'interval' to the file 'path' for monitoring agents which
+    /// don't talk to the daemon. The file holds one 'name value' pair per line and is replaced
+    /// atomically by renaming a temporary 'path.tmp' over it. It is removed when the Rmrfd is
+    /// dropped.
+    pub fn with_stats_file(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.rmrf_armed = false;
+        self.stats_file = Some((path.into(), interval));
+        self
+    }
+
+    /// Starts a watchdog logging worker threads which are busy with the same item for longer
+    /// than 'timeout', for example on a hung NFS mount. Without it Rmrfd::health() uses a
+    /// timeout of 60 seconds.
+    pub fn with_watchdog(mut self, timeout: Duration) -> Self {
+        self.rmrf_armed = false;
+        self.watchdog_timeout = Some(timeout);
+        self
+    }
+
+    /// Debug mode, measures the time every thread spends in opening directories, stat(),
+    /// reading directory entries and unlinking and logs a breakdown whenever a job is done.
+    /// Helps to tune thread counts and batch sizes for a filesystem. Profiling is process wide
+    /// and can't be switched off again.
+    pub fn with_profiling(mut self, profiling: bool) -> Self {
+        self.rmrf_armed = false;
+        self.profiling = profiling;
+        self
+    }
+
+    /// Writes a JSON object per line to 'writer' for every job state change, every directory
+    /// removed by a sweep and every error, for external automation to follow. Each object has
+    /// a 'time' (seconds since the epoch) and an 'event' ("job", "dir", "error", "paused",
+    /// "resumed" or "report", see JobReport) field. The lines are written by a thread of its
+    /// own, when 1024 lines wait for a slow writer further events are dropped with a warning.
+    /// Pass a File opened for appending or from an inherited fd.
+    pub fn with_event_log<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        self.rmrf_armed = false;
+        self.event_log = Some(Box::new(writer));
+        self
+    }
+
+    /// Writes the JobReport of every job which is done, cancelled or aborted as JSON object
+    /// to a file of its own in 'dir', the names sort by the time the reports were written.
+    /// The files appear complete and are never removed by rmrfd.
+    pub fn with_report_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.rmrf_armed = false;
+        self.report_dir = Some(dir.into());
+        self
+    }
+
+    /// Registers a callback which is called with the JobReport of every job which is done,
+    /// cancelled or aborted, for example to notify other systems. It runs in the thread
+    /// ending the job, an inventory thread or the one calling JobHandle::cancel(), and must
+    /// return quickly.
+    pub fn with_report_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&JobReport) + Send + Sync + 'static,
+    {
+        self.rmrf_armed = false;
+        self.on_report = Some(Arc::new(callback));
+        self
+    }
+
+    /// Drops all capabilities rmrfd does not need when starting: CAP_CHOWN, CAP_DAC_OVERRIDE,
+    /// CAP_DAC_READ_SEARCH and CAP_FOWNER are kept, CAP_SYS_NICE when a pool gets a higher
+    /// priority. This applies to the thread calling start() and can't be undone. Linux only,
+    /// elsewhere a warning is logged. Independent of this the capabilities held are logged at
+    /// start, deletions failing for a missing one are skipped and reported as
+    /// DeleteErrorKind::Capability.
+    pub fn with_drop_capabilities(mut self, drop: bool) -> Self {
+        self.rmrf_armed = false;
+        self.drop_capabilities = drop;
+        self
+    }
+
+    /// Aborts jobs when too many of their operations fail, instead of trying every single
+    /// file on a broken disk. Applies to all jobs, JobHandle::set_error_budget() changes it
+    /// for one job. Off by default.
+    pub fn with_error_budget(mut self, budget: ErrorBudget) -> Self {
+        self.rmrf_armed = false;
+        self.error_budget = Some(budget);
+        self
+    }
+
+    /// Gives the jobs of the user 'uid' 'share' turns for every turn of a user with the
+    /// default share of 1. While jobs of several users are running their files are deleted
+    /// and their jobs swept by turns, the giant deletion of one user does not hold up all
+    /// others. Jobs not attributed to a user share the default.
+    pub fn with_uid_share(mut self, uid: libc::uid_t, share: u32) -> Self {
+        assert!(share > 0, "A share must be at least 1");
+        self.rmrf_armed = false;
+        self.uid_shares.push((uid, share));
+        self
+    }
+
+    /// Caps how many directories the gatherer keeps open. At start the soft limit of open
+    /// files is raised as far as the hard limit allows, by default the gatherer may use all
+    /// of it except FD_RESERVE fds and one per rmrf directory, stat and inventory thread.
+    /// When the limit is reached directories are queued again until handles are released,
+    /// see Status::fds_used. Keep it above the number of gather threads plus 100.
+    pub fn with_fd_limit(mut self, n: usize) -> Self {
+        self.rmrf_armed = false;
+        self.fd_limit = Some(n);
+        self
+    }
+
+    /// Keeps the resident memory of the daemon below 'bytes'. The inventory holds every file
+    /// found until it is deleted at the end of a pass. From 75% of the limit on only files
+    /// of at least 4MiB are inventoried, from 90% on files are deleted as soon as they are
+    /// found instead of largest first. The configured thresholds are restored when the use
+    /// dropped below 60%, see Status::memory_pressure. Linux only, elsewhere a warning is
+    /// logged. No limit by default.
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.rmrf_armed = false;
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// How often paused devices are probed, see Rmrfd::paused(). A device paused because it
+    /// went read-only is resumed when it is writable again, one out of space is resumed on
+    /// every probe to try again. Devices with threads stuck for longer than the watchdog
+    /// timeout are paused as frozen and resumed when they make progress again. With None
+    /// nothing is probed, paused devices wait for Rmrfd::resume(). Every 10 seconds by
+    /// default.
+    pub fn with_pause_probe(mut self, interval: Option<Duration>) -> Self {
+        self.rmrf_armed = false;
+        self.pause_probe = interval;
+        self
+    }
+
+    /// The capabilities needed by this configuration.
+    fn needed_capabilities(&self) -> Capabilities {
+        let mut needed = Capabilities::default()
+            .with(Capability::Chown)
+            .with(Capability::DacOverride)
+            .with(Capability::DacReadSearch)
+            .with(Capability::Fowner);
+        if [self.gather_priority, self.stat_priority, self.inventory_priority]
+            .iter()
+            .any(|priority| {
+                priority.nice.is_some_and(|nice| nice < 0)
+                    || matches!(priority.io_class, Some(IoClass::RealTime(_)))
+            })
+        {
+            needed = needed.with(Capability::SysNice);
+        }
+        needed
+    }
+
     /// Safety switch, without arming nothing will be deleted, used for testing and do nothing
     /// options. Arming must be the last call before '.start()'.
     pub fn arm(mut self, state: bool) -> Self {
@@ -99,98 +1216,337 @@ impl RmrfdBuilder {
     }
 
     /// register rmrf directories that are watched for deleting entries.
-    pub fn add_dir(mut self, dir: &OsStr) -> io::Result<Self> {
+    pub fn add_dir(self, dir: &OsStr) -> Result<Self, BuildError> {
+        self.insert_dir(dir, None)
+    }
+
+    /// Registers the rmrf directory 'dir' as a cache or scratch space. Unlike with add_dir()
+    /// its entries are only deleted once they were not modified for longer than 'max_age',
+    /// and not at startup but when Rmrfd::reap() is called.
+    pub fn add_reap_dir(self, dir: &OsStr, max_age: Duration) -> Result<Self, BuildError> {
+        self.insert_dir(dir, Some(max_age))
+    }
+
+    /// Registers the rmrf directory 'dir', see add_dir() and add_reap_dir().
+    fn insert_dir(mut self, dir: &OsStr, max_age: Option<Duration>) -> Result<Self, BuildError> {
         self.rmrf_armed = false;
         let canonical_path = fs::canonicalize(dir)?;
         if !canonical_path.is_dir() {
-            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
         }
         let dev = canonical_path.metadata()?.dev();
-        self.rmrf_dirs.insert(ObjectPath::new(canonical_path), dev);
+        self.rmrf_dirs.insert(
+            ObjectPath::new(canonical_path),
+            RmrfDir {
+                dev,
+                max_age,
+                fd: None,
+                lock: None,
+            },
+        );
         Ok(self)
     }
 
-    /// Creates the Rmrfd and starts worker threads.
-    pub fn start(self) -> io::Result<Rmrfd> {
+    /// Register a rmrf directory by an already opened file descriptor. The directory is
+    /// then accessed through the fd, even when its path is not reachable for the daemon
+    /// (mount namespaces, reduced privileges). Paths below it show up as
+    /// '/proc/self/fd/<fd>/...' in logs.
+    pub fn add_dir_fd(mut self, fd: OwnedFd) -> Result<Self, BuildError> {
+        self.rmrf_armed = false;
+        let metadata = fs::File::from(fd.try_clone()?).metadata()?;
+        if !metadata.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory).into());
+        }
+        let path = Path::new(FD_DIR).join(fd.as_raw_fd().to_string());
+        self.rmrf_dirs.insert(
+            ObjectPath::new(path),
+            RmrfDir {
+                dev:     metadata.dev(),
+                max_age: None,
+                fd:      Some(fd),
+                lock:    None,
+            },
+        );
+        Ok(self)
+    }
+
+    /// Checks the configuration for settings which can't work.
+    fn validate(&self) -> Result<(), BuildError> {
+        if self.rmrf_dirs.is_empty() {
+            return Err(BuildError::NoDirs);
+        }
+        for (pool, threads) in [
+            ("gather", self.gather_threads),
+            ("inventory", self.inventory_threads),
+            ("stat", self.stat_threads),
+        ] {
+            if threads == 0 {
+                return Err(BuildError::NoThreads(pool));
+            }
+        }
+        if self.stat_batch == 0 {
+            return Err(BuildError::NoBatch);
+        }
+        if self.inventory_backlog != 0 && self.inventory_backlog < self.gather_threads {
+            return Err(BuildError::BacklogTooSmall {
+                backlog: self.inventory_backlog,
+                threads: self.gather_threads,
+            });
+        }
+        for dir in self.rmrf_dirs.keys() {
+            if is_readonly_fs(&dir.to_pathbuf())? {
+                return Err(BuildError::ReadOnly(dir.to_pathbuf()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes an exclusive lock on every rmrf directory, refuses to work on directories
+    /// another rmrfd process is already working on. Instances within one process share the
+    /// lock.
+    fn lock_rmrf_dirs(&mut self) -> Result<(), BuildError> {
+        for (path, dir) in self.rmrf_dirs.iter_mut() {
+            let path = path.to_pathbuf();
+            dir.lock = Some(DirLock::lock(&path).map_err(|err| {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    BuildError::Locked(path)
+                } else {
+                    BuildError::Io(err)
+                }
+            })?);
+        }
+        Ok(())
+    }
+
+    /// Raises the limit of open files and returns the soft and hard limit with the number of
+    /// directories the gatherer may keep open.
+    fn fd_budget(&self) -> io::Result<((u64, u64), usize)> {
+        let limits = match raise_nofile_limit() {
+            Ok(limits) => limits,
+            Err(err) => {
+                warn!("raising the open files limit: {}", err);
+                nofile_limits()?
+            }
+        };
+        let reserve =
+            FD_RESERVE + self.rmrf_dirs.len() + self.stat_threads + self.inventory_threads;
+        let mut budget = usize::try_from(limits.0)
+            .unwrap_or(usize::MAX)
+            .saturating_sub(reserve);
+        if let Some(fd_limit) = self.fd_limit {
+            budget = budget.min(fd_limit);
+        }
+        info!(
+            "open files limit {} (hard {}), fd budget {}",
+            limits.0, limits.1, budget
+        );
+        if budget < self.gather_threads + 100 {
+            warn!(
+                "fd budget {} is low for {} gather threads, scanning may stall",
+                budget, self.gather_threads
+            );
+        }
+        Ok((limits, budget))
+    }
+
+    /// Validates the configuration, creates the Rmrfd and starts worker threads.
+    pub fn start(mut self) -> Result<Rmrfd, BuildError> {
+        self.validate()?;
+        self.lock_rmrf_dirs()?;
         info!("armed: {}", self.rmrf_armed);
-        let inventory_gatherer = self.gatherer_builder.start(Box::new(
-            move |gatherer: GathererHandle, entry: ProcessEntry, parent_dir: Option<Arc<Dir>>| {
-                match entry {
-                    ProcessEntry::Result(Ok(entry), parent_path) => match entry.simple_type() {
-                        Some(openat::SimpleType::Dir) => {
-                            trace!(
-                                "gather: subdir: {:?}",
-                                parent_path
-                                    .clone()
-                                    .subobject(InternedName::new(entry.file_name()))
-                            );
-                            gatherer.traverse_dir(&entry, parent_path, parent_dir);
-                        }
-                        _ => match parent_dir.unwrap().metadata(entry.file_name()) {
-                            Ok(metadata) => {
-                                trace!(
-                                    "gather: metadata: {:?}",
-                                    parent_path
-                                        .clone()
-                                        .subobject(InternedName::new(entry.file_name()))
-                                );
-                                if metadata.size().unwrap_or(0) > self.min_blockcount {
-                                    gatherer.output_metadata(
-                                        ObjectKey::try_from(&metadata)
-                                            .map_or(0, |key| key.bucket_hash()),
-                                        &entry,
+        // before any thread is spawned, they inherit the capabilities
+        if self.drop_capabilities {
+            if let Err(err) = caps::restrict(self.needed_capabilities()) {
+                if err.kind() != io::ErrorKind::Unsupported {
+                    return Err(err.into());
+                }
+                warn!("capabilities not dropped: {}", err);
+            }
+        }
+        if let Err(err) = caps::detect() {
+            warn!("detecting capabilities: {}", err);
+        }
+        if self.profiling {
+            profile::enable();
+        }
+        let (fd_limits, fd_budget) = self.fd_budget()?;
+        let inventory_channels = if self.inventory_channels == 0 {
+            self.inventory_threads
+        } else {
+            self.inventory_channels
+        };
+        let events = Arc::new(
+            self.event_log
+                .take()
+                .map(EventLog::new)
+                .transpose()?
+                .unwrap_or_default()
+                .with_report_dir(self.report_dir.take())
+                .with_report_callback(self.on_report.take()),
+        );
+        let jobs = Jobs::new(self.inventory_threads.min(inventory_channels), events.clone());
+
+        let (stat_senders, stat_receivers) = (0..inventory_channels)
+            .map(|_| unbounded())
+            .unzip();
+        let watchdog = Watchdog::new(self.watchdog_timeout.unwrap_or(Duration::from_secs(60)));
+        let stat_pool = StatPool::start(
+            self.stat_threads,
+            4096 * self.stat_threads,
+            self.min_blockcount,
+            jobs.clone(),
+            stat_senders,
+            self.stat_batch,
+            self.stat_flush_interval,
+            self.stat_priority,
+            watchdog.clone(),
+        )?;
+        let stat_pool_gather = stat_pool.clone();
+        let dirs_queue = Arc::new(DirsQueue::default());
+        let dirs_queue_gather = dirs_queue.clone();
+        let gather_priority = self.gather_priority;
+
+        let inventory_gatherer = self
+            .gatherer_builder
+            .with_gather_threads(self.gather_threads)
+            .with_fd_limit(fd_budget)
+            .with_inventory_backlog(self.inventory_backlog)
+            .with_output_channels(inventory_channels)
+            .start(Box::new(
+                move |gatherer: GathererHandle, entry: ProcessEntry, parent_dir: Option<Arc<Dir>>| {
+                    // The gather threads are spawned by dirinventory, set their priority on
+                    // their first entry.
+                    gather_priority.apply_once();
+                    match entry {
+                        ProcessEntry::Result(Ok(entry), parent_path) => {
+                            dirs_queue_gather.listed(&parent_path);
+                            match entry.simple_type() {
+                                Some(openat::SimpleType::Dir) => {
+                                    trace!(
+                                        "gather: subdir: {:?}",
+                                        parent_path
+                                            .clone()
+                                            .subobject(InternedName::new(entry.file_name()))
+                                            .display()
+                                    );
+                                    dirs_queue_gather.queued();
+                                    gatherer.traverse_dir(&entry, parent_path, parent_dir);
+                                }
+                                _ => {
+                                    stat_pool_gather.stat(
+                                        parent_dir.unwrap(),
+                                        entry.file_name(),
                                         parent_path,
-                                        metadata,
                                     );
                                 }
                             }
-                            Err(err) => {
-                                // FIXME: channel
-                                gatherer.output_error(0, Box::new(err), parent_path);
-                            }
-                        },
-                    },
-                    ProcessEntry::Result(Err(err), parent_path) => {
-                        // FIXME: channel
-                        gatherer.output_error(0, Box::new(err), parent_path);
+                        }
+                        ProcessEntry::Result(Err(err), parent_path) => {
+                            dirs_queue_gather.failed(&parent_path);
+                            // FIXME: channel
+                            gatherer.output_error(0, Box::new(err), parent_path);
+                        }
+                        ProcessEntry::EndOfDirectory(_) => dirs_queue_gather.end_of_directory(),
                     }
-                    ProcessEntry::EndOfDirectory(_) => {}
-                }
-            },
-        ))?;
+                },
+            ))?;
 
+        let anchors = Arc::new(Anchors::new(
+            self.rmrf_dirs.keys().map(|dir| Path::new(dir.name())),
+        )?);
         let inventory = Inventory::new(
+            self.inventory_threads,
             inventory_gatherer.channels_as_vec(),
+            stat_receivers,
+            stat_pool.clone(),
             self.early_delete_percent,
-        );
+            self.inventory_priority,
+            self.on_deleted,
+            self.rmrf_armed,
+            events,
+            anchors.clone(),
+            jobs,
+            dirs_queue.clone(),
+        )?;
 
-        // create fastrmrf instance
-        // slowrmrf
+        inventory.jobs().shares().set(self.uid_shares.drain(..));
+        let memory = MemoryGuard::new(
+            self.memory_limit,
+            self.min_blockcount,
+            self.early_delete_percent,
+            stat_pool.clone(),
+            inventory.clone(),
+        );
 
-        Ok(Rmrfd {
+        let mut rmrfd = Rmrfd {
             inventory_gatherer,
+            inventory,
+            stat_pool,
             rmrf_dirs: self.rmrf_dirs,
-        })
-    }
+            dirs_queue,
+            startup_jobs: Vec::new(),
+            user_dirs: self.user_dirs,
+            reporter: None,
+            stats_file: None,
+            watchdog,
+            watchdog_thread: None,
+            anchors,
+            error_budget: Mutex::new(self.error_budget),
+            pause_probe: None,
+            armed: self.rmrf_armed,
+            fd_limits,
+            fd_budget,
+            _memory_probe: memory.start()?,
+            memory,
+            reaping: Mutex::new(HashMap::new()),
+        };
+
+        if let Some(interval) = self.pause_probe {
+            rmrfd.pause_probe = Some(rmrfd.inventory.pauses().start_probe(
+                interval,
+                rmrfd.anchors.clone(),
+                rmrfd.watchdog.clone(),
+            )?);
+        }
+
+        if self.watchdog_timeout.is_some() {
+            rmrfd.watchdog_thread = Some(rmrfd.watchdog.start()?);
+        }
+
+        if let Some(interval) = self.report_interval {
+            let inventory = rmrfd.inventory.clone();
+            let gatherer = rmrfd.inventory_gatherer.clone();
+            let stat_pool = rmrfd.stat_pool.clone();
+            rmrfd.reporter = Some(Reporter::start(interval, move || {
+                collect_statistics(&inventory, &gatherer, &stat_pool)
+            })?);
+        }
 
-    // directory watcher loop
+        if let Some((path, interval)) = self.stats_file.take() {
+            let inventory = rmrfd.inventory.clone();
+            let gatherer = rmrfd.inventory_gatherer.clone();
+            let stat_pool = rmrfd.stat_pool.clone();
+            rmrfd.stats_file = Some(StatsFile::start(path, interval, move || {
+                collect_statistics(&inventory, &gatherer, &stat_pool)
+            })?);
+        }
 
-    #[cfg(test)]
-    pub fn delete_dir(&self, dir: ObjectPath) {
-        // rmrfd
-        //     .inventory_gatherer
-        //     .load_dir_recursive(ObjectPath::new("/home/ct/rmrfd_test"));
+        if self.startup_scan {
+            rmrfd.scan_rmrf_dirs().map_err(io::Error::other)?;
+        }
 
-        // wait for done
+        Ok(rmrfd)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::ffi::{OsStr, OsString};
+    use std::ffi::OsStr;
+    use std::thread;
+    use std::time::Duration;
 
-    use crate::Rmrfd;
-    use crate::rmrfd::ObjectPath;
+    use crate::{BuildError, ErrorBudget, JobState, ReconfigRequest, Rmrfd, RmrfdError};
+    use crate::rmrfd::{metadata_types, DirsQueue, ObjectPath};
 
     #[test]
     fn smoke() {
@@ -198,7 +1554,562 @@ mod tests {
         let rmrfd = Rmrfd::build()
             .with_min_blockcount(64)
             .with_inventory_threads(1)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
             .start();
+        assert!(rmrfd.is_ok());
+    }
+
+    #[test]
+    fn fd_limit() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_fd_limit(200)
+            .with_startup_scan(false)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .start()
+            .unwrap();
+        let status = rmrfd.status();
+        assert!(status.fd_budget <= 200);
+        assert_eq!(
+            (status.fd_soft_limit, status.fd_hard_limit),
+            crate::platform::nofile_limits().unwrap()
+        );
+    }
+
+    #[test]
+    fn small_files() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_min_blockcount(metadata_types::blksize_t::MAX)
+            .with_inventory_threads(1)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        rmrfd
+            .inventory_gatherer
+            .load_dir_recursive(ObjectPath::new("src"));
+
+        let files = std::fs::read_dir("src").unwrap().count() as u64;
+        let start = std::time::Instant::now();
+        while rmrfd.small_files().0 < files
+            && start.elapsed() < std::time::Duration::from_secs(10)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let (count, bytes) = rmrfd.small_files();
+        assert_eq!(count, files);
+        assert!(bytes > 0);
+    }
+
+    #[test]
+    fn validate() {
+        crate::tests::init_env_logging();
+        assert!(matches!(Rmrfd::build().start(), Err(BuildError::NoDirs)));
+
+        let builder = || {
+            Rmrfd::build()
+                .add_dir(OsStr::new("src"))
+                .unwrap()
+                .with_startup_scan(false)
+        };
+        assert!(matches!(
+            builder().with_stat_threads(0).start(),
+            Err(BuildError::NoThreads("stat"))
+        ));
+        assert!(matches!(
+            builder().with_stat_batch(0).start(),
+            Err(BuildError::NoBatch)
+        ));
+        assert!(matches!(
+            builder()
+                .with_gather_threads(8)
+                .with_inventory_backlog(4)
+                .start(),
+            Err(BuildError::BacklogTooSmall {
+                backlog: 4,
+                threads: 8
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn from_env() {
+        crate::tests::init_env_logging();
+        // the environment is shared by all tests, it is only read here, the variables are
+        // covered by from_vars
+        let builder = crate::rmrfd::RmrfdBuilder::from_env();
+        if std::env::var_os("RMRFD_SPOOL_DIRS").is_none() {
+            assert!(builder.unwrap().rmrf_dirs.is_empty());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "config")]
+    fn from_vars() {
+        use std::collections::HashMap;
+        use std::ffi::OsString;
+
+        crate::tests::init_env_logging();
+        let from_map = |map: HashMap<&str, &str>| {
+            crate::rmrfd::RmrfdBuilder::from_vars(|var| map.get(var).map(OsString::from))
+        };
+        let mut map = HashMap::from([
+            ("RMRFD_STAT_THREADS", "3"),
+            ("RMRFD_MIN_BLOCKS", "128"),
+            ("RMRFD_SPOOL_DIRS", "src:"),
+        ]);
+        let builder = from_map(map.clone()).unwrap();
+        assert_eq!(builder.stat_threads, 3);
+        assert_eq!(builder.min_blockcount, 128);
+        assert_eq!(builder.rmrf_dirs.len(), 1);
+
+        map.insert("RMRFD_STAT_THREADS", "many");
+        assert!(matches!(
+            from_map(map),
+            Err(BuildError::InvalidEnv {
+                var: "RMRFD_STAT_THREADS",
+                ..
+            })
+        ));
+
+        let vars = |var: &str| match var {
+            "RMRFD_EARLY_DELETE_PERCENT" => Some(std::ffi::OsString::from("75")),
+            "RMRFD_THREADS" => Some(std::ffi::OsString::from(" 5 ")),
+            "RMRFD_UID_SHARES" => Some(std::ffi::OsString::from("1000:4,1001:2")),
+            "RMRFD_FD_LIMIT" => Some(std::ffi::OsString::from("1000")),
+            "RMRFD_MEMORY_LIMIT_MB" => Some(std::ffi::OsString::from("64")),
+            _ => None,
+        };
+
+        let builder = crate::rmrfd::RmrfdBuilder::from_vars(vars).unwrap();
+        assert_eq!(builder.gather_threads, 5);
+        assert_eq!(builder.early_delete_percent, 75);
+        assert_eq!(builder.uid_shares, [(1000, 4), (1001, 2)]);
+        assert_eq!(builder.fd_limit, Some(1000));
+        assert_eq!(builder.memory_limit, Some(64 << 20));
+        assert!(builder.rmrf_dirs.is_empty());
+
+        let request = ReconfigRequest::from_vars(vars).unwrap();
+        assert_eq!(request.early_delete_percent, Some(75));
+        assert_eq!(request.stat_threads, None);
+        assert_eq!(request.uid_shares, Some(vec![(1000, 4), (1001, 2)]));
+        assert!(matches!(
+            ReconfigRequest::from_vars(|_| Some(std::ffi::OsString::from("0"))),
+            Err(BuildError::NoThreads("stat"))
+        ));
+    }
+
+    #[test]
+    fn add_dir_fd() {
+        use std::os::unix::fs::MetadataExt;
+
+        crate::tests::init_env_logging();
+        let fd = std::fs::File::open("src").unwrap().into();
+        let rmrfd = Rmrfd::build()
+            .add_dir_fd(fd)
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let root = rmrfd.rmrf_dirs.keys().next().unwrap().to_pathbuf();
+        let path = rmrfd.object_path(&root.join("lib.rs")).unwrap();
+        assert_eq!(path.depth(), 2);
+        assert_eq!(
+            path.metadata().unwrap().ino(),
+            Some(std::fs::metadata("src/lib.rs").unwrap().ino())
+        );
+
+        assert!(Rmrfd::build()
+            .add_dir_fd(std::fs::File::open("Cargo.toml").unwrap().into())
+            .is_err());
+    }
+
+    #[test]
+    fn dirs_queue() {
+        let queue = DirsQueue::default();
+        let dir = ObjectPath::new("/tmp/rmrf");
+        let subdir = ObjectPath::new("/tmp/rmrf/sub");
+
+        queue.queued();
+        queue.listed(&dir);
+        queue.queued();
+        // an unreadable entry of 'dir', its EndOfDirectory still comes
+        queue.failed(&dir);
+        assert_eq!(queue.len(), 2);
+        queue.end_of_directory();
+        assert_eq!(queue.len(), 1);
+        // 'subdir' can't be opened
+        queue.failed(&subdir);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.epoch(), 1);
+        queue.end_of_directory();
+        assert_eq!(queue.epoch(), 1);
+        assert_eq!(queue.queued(), 1);
+    }
+
+    #[test]
+    fn delete_dir() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_min_blockcount(0)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let src = std::fs::canonicalize("src").unwrap();
+        let job = rmrfd.delete_dir(&src).unwrap();
+        assert_eq!(
+            job.wait_timeout(std::time::Duration::from_secs(10)),
+            JobState::Done
+        );
+        assert!(job.progress() > 0);
+
+        let status = rmrfd.status();
+        assert_eq!(status.jobs, 0);
+        assert_eq!(status.dirs_queue, 0);
+        assert_eq!(status.delete_queue, 0);
+        assert_eq!(status.stuck_threads, 0);
+        assert!(status.fd_soft_limit <= status.fd_hard_limit);
+        assert!(status.fd_budget > 0);
+        assert!((status.fd_budget as u64) < status.fd_soft_limit);
+        assert!(rmrfd.health().is_empty());
+
+        let statistics = rmrfd.statistics();
+        assert!(statistics.uptime > std::time::Duration::ZERO);
+        assert_eq!(
+            statistics
+                .devices
+                .values()
+                .map(|device| device.files_deleted)
+                .sum::<u64>(),
+            job.progress()
+        );
+
+        assert!(matches!(
+            rmrfd.delete_dir(&std::fs::canonicalize(".").unwrap()),
+            Err(RmrfdError::NotBelowRmrfDir(_))
+        ));
+    }
+
+    #[test]
+    fn progress() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_min_blockcount(0)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let job = rmrfd
+            .delete_dir(&std::fs::canonicalize("src").unwrap())
+            .unwrap();
+        let reports: Vec<_> = rmrfd
+            .progress(&job, std::time::Duration::from_millis(1))
+            .unwrap()
+            .iter()
+            .collect();
+        assert!(!job.state().is_active());
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.scanned, std::fs::read_dir("src").unwrap().count() as u64);
+        assert_eq!(last.deleted_files, job.progress());
+        assert!(last.deleted_bytes > 0);
+        assert!(last.estimated_total >= last.scanned);
+    }
+
+    #[test]
+    fn delete_callback() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        crate::tests::init_env_logging();
+        let count = Arc::new(AtomicU64::new(0));
+        let count_callback = count.clone();
+        let rmrfd = Rmrfd::build()
+            .with_min_blockcount(0)
+            .with_delete_callback(move |deleted| {
+                assert!(deleted.ino != 0);
+                count_callback.fetch_add(1, Ordering::Relaxed);
+            })
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let job = rmrfd
+            .delete_dir(&std::fs::canonicalize("src").unwrap())
+            .unwrap();
+        job.wait();
+        assert_eq!(count.load(Ordering::Relaxed), job.progress());
+    }
+
+    #[test]
+    fn plan() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let plan = rmrfd.plan(&std::fs::canonicalize("src").unwrap()).unwrap();
+        assert_eq!(plan.files, std::fs::read_dir("src").unwrap().count() as u64);
+        assert!(rmrfd.plan(&std::fs::canonicalize(".").unwrap()).is_err());
+        // relative and differently spelled paths resolve to the rmrf directory
+        assert_eq!(rmrfd.plan(std::path::Path::new("./src/../src")).unwrap().files, plan.files);
+        assert!(matches!(
+            rmrfd.plan(std::path::Path::new("src/../..")),
+            Err(RmrfdError::NotBelowRmrfDir(_))
+        ));
+    }
+
+    #[test]
+    fn startup_scan() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_min_blockcount(0)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert_eq!(rmrfd.startup_jobs().len(), 1);
+        assert_eq!(rmrfd.startup_jobs()[0].wait(), JobState::Done);
+    }
+
+    #[test]
+    fn user_dirs() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-user_dirs-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+        // SAFETY: getuid can't fail
+        let uid = unsafe { libc::getuid() };
+
+        let rmrfd = Rmrfd::build()
+            .with_user_dirs(true)
+            .add_dir(root.as_os_str())
+            .unwrap()
+            .start()
+            .unwrap();
+
+        let user_dir = rmrfd.user_dir(&root, uid).unwrap();
+        let dir = user_dir.join("foo");
+        std::fs::create_dir(&dir).unwrap();
+
+        assert!(matches!(
+            rmrfd.delete_dir_as(&dir, uid.wrapping_add(1)),
+            Err(RmrfdError::PermissionDenied(_))
+        ));
+        assert!(matches!(
+            rmrfd.delete_dir_as(&user_dir, uid),
+            Err(RmrfdError::PermissionDenied(_))
+        ));
+        let job = rmrfd.delete_dir_as(&dir, uid).unwrap();
+        assert_eq!(job.uid(), Some(uid));
+        assert_eq!(job.wait(), JobState::Done);
+        assert!(job.space().is_some());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reserve() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-reserve-{}", std::process::id()));
+        std::fs::create_dir_all(root.join(".rmrf")).unwrap();
+        std::fs::create_dir_all(root.join("sub/victim")).unwrap();
+        std::fs::write(root.join("sub/file"), b"data").unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+        // SAFETY: getuid can't fail
+        let uid = unsafe { libc::getuid() };
+
+        let rmrfd = Rmrfd::build()
+            .with_user_dirs(true)
+            .add_dir(root.join(".rmrf").as_os_str())
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert!(matches!(
+            rmrfd.reserve(&root, uid),
+            Err(RmrfdError::NoRmrfDir(_))
+        ));
+        let victim = root.join("sub/victim");
+        let reserved = rmrfd.reserve(&victim, uid).unwrap();
+        assert!(reserved.starts_with(root.join(".rmrf").join(uid.to_string())));
+        std::fs::rename(&victim, reserved.join("victim")).unwrap();
+        assert!(rmrfd.reserve(&root.join("sub/file"), uid).is_ok());
+        let job = rmrfd.delete_dir_as(&reserved, uid).unwrap();
+        assert_eq!(job.wait(), JobState::Done);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reap() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-reap-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("scratch/old")).unwrap();
+        std::fs::create_dir_all(root.join("cache/new")).unwrap();
+        std::fs::write(root.join("scratch/file"), b"old").unwrap();
+        let root = std::fs::canonicalize(root).unwrap();
+
+        let rmrfd = Rmrfd::build()
+            .add_reap_dir(root.join("scratch").as_os_str(), Duration::ZERO)
+            .unwrap()
+            .add_reap_dir(root.join("cache").as_os_str(), Duration::from_secs(3600))
+            .unwrap()
+            .start()
+            .unwrap();
+        assert!(rmrfd.startup_jobs().is_empty());
+
+        thread::sleep(Duration::from_millis(10));
+        let jobs = rmrfd.reap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].path(), root.join("scratch/old"));
+        assert_eq!(jobs[0].wait(), JobState::Done);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn locked() {
+        use std::os::unix::io::AsRawFd;
+
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-locked-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let other = std::fs::File::open(&root).unwrap();
+        // SAFETY: flock on a valid fd
+        unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+
+        assert!(matches!(
+            Rmrfd::build().add_dir(root.as_os_str()).unwrap().start(),
+            Err(BuildError::Locked(_))
+        ));
+
+        drop(other);
+        std::fs::remove_dir(&root).unwrap();
+    }
+
+    #[test]
+    fn object_path() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let src = std::fs::canonicalize("src").unwrap();
+        let path = rmrfd.object_path(&src.join("lib.rs")).unwrap();
+        assert_eq!(path.to_pathbuf(), src.join("lib.rs"));
+        assert_eq!(path.depth(), 2);
+    }
+
+    #[test]
+    fn inventory_channels() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_inventory_threads(2)
+            .with_inventory_channels(5)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        rmrfd
+            .inventory_gatherer
+            .load_dir_recursive(ObjectPath::new("src"));
+
+        // dropping shuts the inventory threads down
+        drop(rmrfd);
+    }
+
+    #[test]
+    fn reconfigure() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_stat_threads(4)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        rmrfd
+            .reconfigure(
+                ReconfigRequest::default()
+                    .with_min_blockcount(metadata_types::blksize_t::MAX)
+                    .with_early_delete_percent(100)
+                    .with_stat_threads(2)
+                    .with_error_budget(ErrorBudget::new(10))
+                    .with_uid_share(1000, 3),
+            )
+            .unwrap();
+        assert_eq!(rmrfd.stat_pool.threads(), 2);
+        assert_eq!(*rmrfd.error_budget.lock(), Some(ErrorBudget::new(10)));
+        assert_eq!(rmrfd.inventory.jobs().shares().get(Some(1000)), 3);
+
+        rmrfd
+            .inventory_gatherer
+            .load_dir_recursive(ObjectPath::new("src"));
+
+        let files = std::fs::read_dir("src").unwrap().count() as u64;
+        let start = std::time::Instant::now();
+        while rmrfd.small_files().0 < files
+            && start.elapsed() < std::time::Duration::from_secs(10)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(rmrfd.small_files().0, files);
+    }
+
+    #[test]
+    fn memory_limit() {
+        crate::tests::init_env_logging();
+        let rmrfd = Rmrfd::build()
+            .with_memory_limit(1 << 20)
+            .with_min_blockcount(0)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
+            .start()
+            .unwrap();
+
+        let status = rmrfd.status();
+        assert_eq!(status.memory_pressure, crate::MemoryPressure::Critical);
+        assert!(status.memory_used > 1 << 20);
+
+        // files are deleted as they are found, the job completes as usual
+        rmrfd
+            .reconfigure(ReconfigRequest::default().with_early_delete_percent(100))
+            .unwrap();
+        let src = std::fs::canonicalize("src").unwrap();
+        let job = rmrfd.delete_dir(&src).unwrap();
+        assert_eq!(
+            job.wait_timeout(std::time::Duration::from_secs(10)),
+            JobState::Done
+        );
+        assert_eq!(rmrfd.status().delete_queue, 0);
     }
 
     #[test]
@@ -208,6 +2119,9 @@ mod tests {
         let rmrfd = Rmrfd::build()
             .with_min_blockcount(1024)
             .with_inventory_threads(8)
+            .add_dir(OsStr::new("src"))
+            .unwrap()
+            .with_startup_scan(false)
             .start()
             .unwrap();
 
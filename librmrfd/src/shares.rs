@@ -0,0 +1,108 @@
+//! Fair sharing of the deletion between the users submitting jobs. The files of all jobs on a
+//! device are deleted by the same inventory threads in one pass, largest first. On shared
+//! machines the giant deletion of one user would then hold up everyone else. When jobs of
+//! several users are running the work is interleaved by weighted round robin instead: each
+//! user in turn gets as many files as its share before the next one, see
+//! RmrfdBuilder::with_uid_share(). Work is counted in files, unlinking costs about the same
+//! for any size.
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::Mutex;
+
+/// The share of users without one configured and of jobs not attributed to a user.
+pub(crate) const DEFAULT_SHARE: u32 = 1;
+
+/// The configured shares of the users.
+#[derive(Debug, Default)]
+pub(crate) struct Shares(Mutex<HashMap<libc::uid_t, u32>>);
+
+impl Shares {
+    /// Replaces all shares with 'shares'.
+    pub(crate) fn set(&self, shares: impl IntoIterator<Item = (libc::uid_t, u32)>) {
+        *self.0.lock() = shares.into_iter().collect();
+    }
+
+    /// Returns the share of 'uid'.
+    pub(crate) fn get(&self, uid: Option<libc::uid_t>) -> u32 {
+        uid.and_then(|uid| self.0.lock().get(&uid).copied())
+            .unwrap_or(DEFAULT_SHARE)
+    }
+
+    /// Orders 'items' attributed to users by weighted round robin. The users take turns in
+    /// the order they first appear, the items of each user keep their order.
+    pub(crate) fn interleave<T>(&self, items: Vec<(Option<libc::uid_t>, T)>) -> Vec<T> {
+        let len = items.len();
+        let mut queues: Vec<(u32, VecDeque<T>)> = Vec::new();
+        let mut users: Vec<Option<libc::uid_t>> = Vec::new();
+        for (uid, item) in items {
+            match users.iter().position(|user| *user == uid) {
+                Some(n) => queues[n].1.push_back(item),
+                None => {
+                    users.push(uid);
+                    queues.push((self.get(uid), VecDeque::from([item])));
+                }
+            }
+        }
+
+        let mut ordered = Vec::with_capacity(len);
+        while !queues.is_empty() {
+            for (share, queue) in &mut queues {
+                let turn = queue.len().min(*share as usize);
+                ordered.extend(queue.drain(..turn));
+            }
+            queues.retain(|(_, queue)| !queue.is_empty());
+        }
+        ordered
+    }
+}
+
+/// Parses shares like '1000:4,1001:2', a share must be at least 1.
+#[cfg(feature = "config")]
+pub(crate) fn parse_shares(shares: &str) -> Option<Vec<(libc::uid_t, u32)>> {
+    shares
+        .split(',')
+        .map(str::trim)
+        .filter(|share| !share.is_empty())
+        .map(|share| {
+            let (uid, share) = share.split_once(':')?;
+            let share = share.trim().parse().ok().filter(|&share| share > 0)?;
+            Some((uid.trim().parse().ok()?, share))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave() {
+        let shares = Shares::default();
+        shares.set([(1000, 2)]);
+        assert_eq!(shares.get(Some(1000)), 2);
+        assert_eq!(shares.get(None), DEFAULT_SHARE);
+
+        let items = vec![
+            (Some(1000), 'a'),
+            (Some(1000), 'b'),
+            (Some(1000), 'c'),
+            (Some(1000), 'd'),
+            (Some(1000), 'e'),
+            (Some(1001), 'x'),
+            (None, 'n'),
+            (Some(1001), 'y'),
+        ];
+        let ordered: String = shares.interleave(items).into_iter().collect();
+        assert_eq!(ordered, "abxncdye");
+
+        #[cfg(feature = "config")]
+        {
+            assert_eq!(
+                parse_shares("1000:4, 1001:1,"),
+                Some(vec![(1000, 4), (1001, 1)])
+            );
+            assert_eq!(parse_shares("1000:0"), None);
+            assert_eq!(parse_shares("1000"), None);
+        }
+    }
+}
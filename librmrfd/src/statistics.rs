@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+
+use crate::atomicstats::{Counter, Gauge, Histogram, Rate, HISTOGRAM_BUCKETS};
+use crate::platform::metadata_types;
+use crate::MemoryPressure;
+
+/// Snapshot of the daemon statistics, see Rmrfd::statistics().
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    /// Time since the daemon was started.
+    pub uptime:             Duration,
+    /// Deletion counters per device.
+    pub devices:            HashMap<metadata_types::dev_t, DeviceStatistics>,
+    /// Files deleted per second, averaged since start.
+    pub delete_rate:        f64,
+    /// Errors reported from gathering and stat()ing entries.
+    pub errors:             u64,
+    /// Jobs which are not completed yet.
+    pub jobs:               usize,
+    /// Number and total size of files left for the final sweep, see Rmrfd::small_files().
+    pub small_files:        (u64, u64),
+    /// Entries waiting in the gatherer output channels.
+    pub gather_queue:       usize,
+    /// Entries waiting to be stat()ed.
+    pub stat_queue:         usize,
+    /// Entries queued, being stat()ed or waiting in a batch.
+    pub stat_in_flight:     usize,
+    /// The highest 'stat_in_flight' seen.
+    pub stat_in_flight_max: usize,
+    /// The average of 'stat_in_flight'.
+    pub stat_in_flight_avg: f64,
+}
+
+/// Queue depths of the deletion pipeline, see Rmrfd::status(). Backlogs in 'dirs_queue',
+/// 'gather_queue' or 'stat_queue' mean scanning is the bottleneck, a growing 'delete_queue'
+/// means deleting is.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Status {
+    /// Jobs which are not completed yet.
+    pub jobs:            usize,
+    /// Directories waiting to be listed or being listed. Approximate, a directory whose
+    /// listing fails before its first entry may be counted as done early.
+    pub dirs_queue:      usize,
+    /// Entries waiting in the gatherer output channels.
+    pub gather_queue:    usize,
+    /// Entries waiting to be stat()ed.
+    pub stat_queue:      usize,
+    /// Entries queued, being stat()ed or waiting in a batch.
+    pub stat_in_flight:  usize,
+    /// Files in the inventory waiting to be deleted at the end of a pass.
+    pub delete_queue:    usize,
+    /// Worker threads not making progress, see Rmrfd::health().
+    pub stuck_threads:   usize,
+    /// Files deleted per second on all devices, see DeviceStatistics::files_per_sec.
+    pub files_per_sec:   f64,
+    /// Bytes freed per second on all devices, see DeviceStatistics::bytes_per_sec.
+    pub bytes_per_sec:   f64,
+    /// The soft limit of open files, raised to the hard limit at start.
+    pub fd_soft_limit:   u64,
+    /// The hard limit of open files, 'u64::MAX' when unlimited.
+    pub fd_hard_limit:   u64,
+    /// How many directories the gatherer may keep open, see RmrfdBuilder::with_fd_limit().
+    pub fd_budget:       usize,
+    /// Directories the gatherer keeps open. When this reaches 'fd_budget' directories are
+    /// queued again instead of being listed and scanning slows down.
+    pub fds_used:        usize,
+    /// Resident memory of the daemon in bytes, only measured with
+    /// RmrfdBuilder::with_memory_limit().
+    pub memory_used:     u64,
+    /// How close 'memory_used' is to the limit.
+    pub memory_pressure: MemoryPressure,
+}
+
+/// Deletion counters of a single device.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DeviceStatistics {
+    /// Number of files deleted.
+    pub files_deleted:  u64,
+    /// Sum of the sizes of the deleted files.
+    pub bytes_freed:    u64,
+    /// Number of directories removed by sweeps.
+    pub dirs_deleted:   u64,
+    /// How long unlinking files took.
+    pub unlink_latency: LatencyHistogram,
+    /// Files deleted per second, averaged over the last few seconds.
+    pub files_per_sec:  f64,
+    /// Bytes freed per second, averaged over the last few seconds.
+    pub bytes_per_sec:  f64,
+}
+
+/// Histogram of syscall latencies with logarithmic buckets. Slow outliers are the first sign
+/// of a filesystem or disk degrading under the deletion load.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// Returns the counts of all buckets. Bucket 'i' counts calls which took less than 2^i
+    /// microseconds and are not in a lower bucket, the last bucket counts all slower calls.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Adds the counts of 'other'.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        self.buckets
+            .iter_mut()
+            .zip(other.buckets)
+            .for_each(|(bucket, n)| *bucket += n);
+    }
+
+    /// Returns the number of recorded calls.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the upper bound of the bucket holding the given 'percentile' (0 to 100) of all
+    /// calls, 'None' when nothing was recorded. Calls in the last bucket have no upper bound,
+    /// 'Duration::MAX' is returned for them.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * percentile / 100.0).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|n| {
+                seen += n;
+                seen >= rank
+            })
+            .unwrap_or(HISTOGRAM_BUCKETS - 1);
+        Some(if bucket == HISTOGRAM_BUCKETS - 1 {
+            Duration::MAX
+        } else {
+            Duration::from_micros(1 << bucket)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeviceCounters {
+    files_deleted:  Counter,
+    bytes_freed:    Counter,
+    dirs_deleted:   Counter,
+    unlink_latency: Histogram,
+    files_rate:     Rate,
+    bytes_rate:     Rate,
+}
+
+/// The counters behind Statistics. Only registering a new device takes the write lock,
+/// everything else is atomic.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    start:   Instant,
+    devices: RwLock<HashMap<metadata_types::dev_t, Arc<DeviceCounters>>>,
+    files:   Counter,
+    errors:  Counter,
+    pending: Gauge,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Arc<Stats> {
+        Arc::new(Stats {
+            start:   Instant::now(),
+            devices: RwLock::new(HashMap::new()),
+            files:   Counter::default(),
+            errors:  Counter::default(),
+            pending: Gauge::default(),
+        })
+    }
+
+    /// Accounts 'files' deleted files which freed 'bytes' on 'device'.
+    pub(crate) fn deleted(&self, device: metadata_types::dev_t, files: u64, bytes: u64) {
+        let counters = self.device(device);
+        let now = self.now();
+        counters.files_deleted.add(files);
+        counters.bytes_freed.add(bytes);
+        counters.files_rate.add(files, now);
+        counters.bytes_rate.add(bytes, now);
+        self.files.add(files);
+    }
+
+    /// Accounts 'dirs' removed directories on 'device'.
+    pub(crate) fn dirs_deleted(&self, device: metadata_types::dev_t, dirs: u64) {
+        self.device(device).dirs_deleted.add(dirs);
+    }
+
+    /// Returns the seconds since start, the time base of the rates.
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn device(&self, device: metadata_types::dev_t) -> Arc<DeviceCounters> {
+        let counters = self.devices.read().get(&device).cloned();
+        counters.unwrap_or_else(|| self.devices.write().entry(device).or_default().clone())
+    }
+
+    /// Accounts how long unlinking a file on 'device' took.
+    pub(crate) fn unlinked(&self, device: metadata_types::dev_t, latency: Duration) {
+        self.device(device).unlink_latency.record(latency);
+    }
+
+    /// Accounts 'n' files added to the inventory for deletion.
+    pub(crate) fn queued(&self, n: u64) {
+        self.pending.add(n);
+    }
+
+    /// Accounts 'n' files taken out of the inventory, deleted or not.
+    pub(crate) fn dequeued(&self, n: u64) {
+        self.pending.sub(n);
+    }
+
+    /// Returns the files deleted and bytes freed per second summed over all devices.
+    pub(crate) fn throughput(&self) -> (f64, f64) {
+        let now = self.now();
+        self.devices
+            .read()
+            .values()
+            .fold((0.0, 0.0), |(files, bytes), counters| {
+                (
+                    files + counters.files_rate.get(now),
+                    bytes + counters.bytes_rate.get(now),
+                )
+            })
+    }
+
+    /// Returns the number of files in the inventory waiting to be deleted.
+    pub(crate) fn pending(&self) -> u64 {
+        self.pending.get()
+    }
+
+    /// Accounts 'n' errors.
+    pub(crate) fn errors(&self, n: u64) {
+        self.errors.add(n);
+    }
+
+    /// Accounts an error.
+    pub(crate) fn error(&self) {
+        self.errors(1);
+    }
+
+    /// Fills in the fields of 'Statistics' known here, the queue depths are left to the
+    /// caller.
+    pub(crate) fn snapshot(&self, small_files: (u64, u64)) -> Statistics {
+        let uptime = self.start.elapsed();
+        let now = uptime.as_secs();
+        Statistics {
+            uptime,
+            devices: self
+                .devices
+                .read()
+                .iter()
+                .map(|(device, counters)| {
+                    (
+                        *device,
+                        DeviceStatistics {
+                            files_deleted:  counters.files_deleted.get(),
+                            bytes_freed:    counters.bytes_freed.get(),
+                            dirs_deleted:   counters.dirs_deleted.get(),
+                            unlink_latency: LatencyHistogram {
+                                buckets: counters.unlink_latency.get(),
+                            },
+                            files_per_sec:  counters.files_rate.get(now),
+                            bytes_per_sec:  counters.bytes_rate.get(now),
+                        },
+                    )
+                })
+                .collect(),
+            delete_rate: self.files.rate(uptime),
+            errors: self.errors.get(),
+            jobs: 0,
+            small_files,
+            gather_queue: 0,
+            stat_queue: 0,
+            stat_in_flight: 0,
+            stat_in_flight_max: 0,
+            stat_in_flight_avg: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_percentile() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        histogram.buckets[3] = 9;
+        histogram.buckets[HISTOGRAM_BUCKETS - 1] = 1;
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(8)));
+        assert_eq!(histogram.percentile(90.0), Some(Duration::from_micros(8)));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::MAX));
+    }
+}
@@ -0,0 +1,372 @@
+use std::io;
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dirinventory::{Dir, InternedName, InternedNames, InventoryEntryMessage, ObjectPath};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use parking_lot::Mutex;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::atomicstats::{Counter, Gauge};
+use crate::inventory::ObjectKey;
+use crate::job::Jobs;
+use crate::pathdisplay::ObjectPathDisplay;
+use crate::platform::metadata_types;
+use crate::profile::{timed, Syscall};
+use crate::threadprio::ThreadPriority;
+use crate::watchdog::{self, Item, Watchdog};
+
+/// A directory entry waiting to be stat()ed.
+struct StatRequest {
+    dir:         Arc<Dir>,
+    name:        InternedName,
+    parent_path: Arc<ObjectPath>,
+}
+
+/// Fetches the metadata of directory entries in its own thread pool. Listing directories and
+/// stat()ing the entries have different parallelism sweet spots, on high latency filesystems
+/// many more stat calls can be in flight than directories are listed.  The gather threads
+/// only push (dir, name) pairs here, the results are send to the inventory in batches.
+pub(crate) struct StatPool {
+    requests:       Sender<StatRequest>,
+    receiver:       Receiver<StatRequest>,
+    outputs:        Vec<Sender<Vec<InventoryEntryMessage>>>,
+    workers:        Mutex<Vec<Arc<AtomicBool>>>,
+    names:          InternedNames<32>,
+    in_flight:      Gauge,
+    scanned:        Counter,
+    min_blockcount: AtomicU64,
+    jobs:           Arc<Jobs>,
+    batch_size:     usize,
+    flush_interval: Duration,
+    priority:       ThreadPriority,
+    watchdog:       Arc<Watchdog>,
+}
+
+impl StatPool {
+    /// Creates a StatPool with 'threads' worker threads. 'backlog' limits the number of
+    /// pending requests, 'outputs' are the channels to the inventory threads. Files below
+    /// 'min_blockcount' are not sent there but accounted to 'jobs'. Results are
+    /// send in batches of up to 'batch_size' messages, pending batches are flushed at least
+    /// every 'flush_interval' or when there is no more work queued. The threads run with the
+    /// given 'priority' and register at the 'watchdog'.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn start(
+        threads: usize,
+        backlog: usize,
+        min_blockcount: metadata_types::blksize_t,
+        jobs: Arc<Jobs>,
+        outputs: Vec<Sender<Vec<InventoryEntryMessage>>>,
+        batch_size: usize,
+        flush_interval: Duration,
+        priority: ThreadPriority,
+        watchdog: Arc<Watchdog>,
+    ) -> io::Result<Arc<StatPool>> {
+        let (requests, receiver) = bounded(backlog);
+        let stat_pool = Arc::new(StatPool {
+            requests,
+            receiver,
+            outputs,
+            workers: Mutex::new(Vec::with_capacity(threads)),
+            names: InternedNames::new(),
+            in_flight: Gauge::default(),
+            scanned: Counter::default(),
+            min_blockcount: AtomicU64::new(min_blockcount as u64),
+            jobs,
+            batch_size,
+            flush_interval,
+            priority,
+            watchdog,
+        });
+
+        stat_pool.set_threads(threads)?;
+
+        Ok(stat_pool)
+    }
+
+    /// Grows or shrinks the pool to 'threads' worker threads. Surplus threads finish the
+    /// request they are working on, flush their batches and exit.
+    pub(crate) fn set_threads(self: &Arc<Self>, threads: usize) -> io::Result<()> {
+        assert!(threads > 0, "Must at least use one thread");
+        let mut workers = self.workers.lock();
+
+        while workers.len() > threads {
+            if let Some(stop) = workers.pop() {
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+
+        while workers.len() < threads {
+            let stop = Arc::new(AtomicBool::new(false));
+            self.clone().spawn_stat_thread(workers.len(), stop.clone())?;
+            workers.push(stop);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of worker threads.
+    #[cfg(test)]
+    pub(crate) fn threads(&self) -> usize {
+        self.workers.lock().len()
+    }
+
+    /// Changes the threshold below which files are only accounted as small files. Applies
+    /// to all entries stat()ed from now on.
+    pub(crate) fn set_min_blockcount(&self, min_blockcount: metadata_types::blksize_t) {
+        self.min_blockcount
+            .store(min_blockcount as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the watchdog the worker threads register at.
+    pub(crate) fn watchdog(&self) -> &Arc<Watchdog> {
+        &self.watchdog
+    }
+
+    /// Returns the table where the names of stat()ed entries are interned.
+    pub(crate) fn names(&self) -> &InternedNames<32> {
+        &self.names
+    }
+
+    /// Queue the entry 'name' in 'dir' for fetching its metadata.
+    pub(crate) fn stat(&self, dir: Arc<Dir>, name: &OsStr, parent_path: Arc<ObjectPath>) {
+        self.in_flight.add(1);
+        // The pool holds a receiver itself, sending can't fail.
+        let _ = self.requests.send(StatRequest {
+            dir,
+            name: self.names.interning(name),
+            parent_path,
+        });
+    }
+
+    /// Returns 'true' when no requests are queued, being processed or waiting in a batch.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.in_flight.get() == 0
+    }
+
+    /// Returns the number of requests waiting for a stat thread.
+    pub(crate) fn queued(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns the number of requests queued, being processed or waiting in a batch.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.get() as usize
+    }
+
+    /// Returns the highest and the average number of requests in flight.
+    pub(crate) fn in_flight_peak(&self) -> (usize, f64) {
+        (self.in_flight.max() as usize, self.in_flight.avg())
+    }
+
+    /// Returns the number of entries stat()ed so far.
+    pub(crate) fn scanned(&self) -> u64 {
+        self.scanned.get()
+    }
+
+    /// Blocks until all queued requests are processed and their results are sent to the
+    /// output channels.
+    pub(crate) fn wait_idle(&self) {
+        while !self.is_idle() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Stat a single entry, returns the output channel and the message to be send.
+    fn process(
+        &self,
+        request: StatRequest,
+        channels: usize,
+    ) -> Option<(usize, InventoryEntryMessage)> {
+        watchdog::busy(Item::Object(request.parent_path.clone()));
+        let metadata = timed(Syscall::Stat, || request.dir.metadata(&*request.name));
+        watchdog::idle();
+        self.scanned.add(1);
+        let path = request.parent_path.subobject(request.name);
+        trace!("stat: {:?}", path.display());
+
+        match metadata {
+            Ok(metadata) => {
+                if metadata.blocks().unwrap_or(0) as u64
+                    > self.min_blockcount.load(Ordering::Relaxed)
+                {
+                    let channel = ObjectKey::try_from(&metadata)
+                        .map_or(0, |key| key.bucket_hash())
+                        % channels;
+                    Some((channel, InventoryEntryMessage::Metadata { path, metadata }))
+                } else {
+                    self.jobs.small_file(&path, metadata.size().unwrap_or(0) as u64);
+                    None
+                }
+            }
+            Err(err) => {
+                // FIXME: channel
+                warn!("{:?} at {:?}", err, path.display());
+                Some((0, InventoryEntryMessage::Err {
+                    path,
+                    error: Box::new(err),
+                }))
+            }
+        }
+    }
+
+    /// Sends a batch when not empty. Only then the requests in this batch are accounted as
+    /// done, thus 'wait_idle()' does not return while results are held back here.
+    fn send_batch(
+        &self,
+        batch: &mut Vec<InventoryEntryMessage>,
+        output: &Sender<Vec<InventoryEntryMessage>>,
+    ) {
+        if !batch.is_empty() {
+            let len = batch.len();
+            // Ignore send results, the inventory may have dropped its receiver.
+            let _ = output.send(std::mem::replace(
+                batch,
+                Vec::with_capacity(self.batch_size),
+            ));
+            self.in_flight.sub(len as u64);
+        }
+    }
+
+    /// Sends all pending batches.
+    fn flush(
+        &self,
+        batches: &mut [Vec<InventoryEntryMessage>],
+        outputs: &[Sender<Vec<InventoryEntryMessage>>],
+    ) {
+        batches
+            .iter_mut()
+            .zip(outputs)
+            .for_each(|(batch, output)| self.send_batch(batch, output));
+    }
+
+    fn spawn_stat_thread(
+        self: Arc<Self>,
+        n: usize,
+        stop: Arc<AtomicBool>,
+    ) -> io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new()
+            .name(format!("stat/{}", n))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                self.priority.apply();
+                self.watchdog.register();
+                let receiver = &self.receiver;
+                let outputs = &self.outputs;
+                let mut batches: Vec<Vec<InventoryEntryMessage>> = outputs
+                    .iter()
+                    .map(|_| Vec::with_capacity(self.batch_size))
+                    .collect();
+                let mut last_flush = Instant::now();
+
+                loop {
+                    if stop.load(Ordering::Relaxed) {
+                        self.flush(&mut batches, outputs);
+                        debug!("thread stopped: {}", thread::current().name().unwrap());
+                        break;
+                    }
+                    match receiver.recv_timeout(self.flush_interval) {
+                        Ok(request) => {
+                            match self.process(request, outputs.len()) {
+                                Some((channel, message)) => {
+                                    batches[channel].push(message);
+                                    if batches[channel].len() >= self.batch_size {
+                                        self.send_batch(&mut batches[channel], &outputs[channel]);
+                                    }
+                                }
+                                None => {
+                                    self.in_flight.sub(1);
+                                }
+                            }
+                            if receiver.is_empty()
+                                || last_flush.elapsed() >= self.flush_interval
+                            {
+                                self.flush(&mut batches, outputs);
+                                last_flush = Instant::now();
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.flush(&mut batches, outputs);
+                            last_flush = Instant::now();
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+
+    use super::*;
+
+    #[test]
+    fn stat_entry() {
+        crate::tests::init_env_logging();
+
+        let (sender, receiver) = unbounded();
+        let stat_pool = StatPool::start(
+            2,
+            16,
+            0,
+            Jobs::new(1, Arc::default()),
+            vec![sender],
+            64,
+            Duration::from_millis(10),
+            ThreadPriority::default(),
+            Watchdog::new(Duration::from_secs(60)),
+        )
+        .unwrap();
+
+        stat_pool.stat(
+            Arc::new(Dir::open(".").unwrap()),
+            OsStr::new("Cargo.toml"),
+            ObjectPath::new("."),
+        );
+        stat_pool.wait_idle();
+
+        let batch = receiver.try_recv().unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(matches!(batch[0], InventoryEntryMessage::Metadata { .. }));
+    }
+
+    #[test]
+    fn reconfigure() {
+        crate::tests::init_env_logging();
+
+        let (sender, receiver) = unbounded();
+        let jobs = Jobs::new(1, Arc::default());
+        let stat_pool = StatPool::start(
+            4,
+            16,
+            0,
+            jobs.clone(),
+            vec![sender],
+            64,
+            Duration::from_millis(10),
+            ThreadPriority::default(),
+            Watchdog::new(Duration::from_secs(60)),
+        )
+        .unwrap();
+
+        stat_pool.set_threads(1).unwrap();
+        assert_eq!(stat_pool.threads(), 1);
+        stat_pool.set_min_blockcount(metadata_types::blksize_t::MAX);
+
+        stat_pool.stat(
+            Arc::new(Dir::open(".").unwrap()),
+            OsStr::new("Cargo.toml"),
+            ObjectPath::new("."),
+        );
+        stat_pool.wait_idle();
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(jobs.small_files().0, 1);
+    }
+}
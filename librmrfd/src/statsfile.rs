@@ -0,0 +1,110 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::Statistics;
+use crate::pathdisplay::PathEscape;
+
+/// Periodically writes the statistics to a file, see RmrfdBuilder::with_stats_file(). The
+/// thread stops and removes the file when this is dropped.
+#[derive(Debug)]
+pub(crate) struct StatsFile {
+    _stop: Sender<()>,
+}
+
+impl StatsFile {
+    /// Starts a thread which writes 'snapshot' to 'path' every 'interval'.
+    pub(crate) fn start<F>(path: PathBuf, interval: Duration, snapshot: F) -> io::Result<StatsFile>
+    where
+        F: Fn() -> Statistics + Send + 'static,
+    {
+        let (stop, stopped) = bounded(0);
+        // fail early when the file can't be written at all
+        write_atomic(&path, &format_stats(&snapshot()))?;
+        thread::Builder::new()
+            .name(String::from("statsfile"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(err) = write_atomic(&path, &format_stats(&snapshot())) {
+                        warn!("writing stats file {:?}: {}", path.escaped(), err);
+                    }
+                }
+                let _ = fs::remove_file(&path);
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(StatsFile { _stop: stop })
+    }
+}
+
+/// Writes 'contents' to a temporary file next to 'path' and renames it over 'path'. Readers
+/// always see either the old or the new file complete.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Formats 'stats' as one 'name value' pair per line. Device counters are named
+/// 'device.<dev>.<counter>'.
+fn format_stats(stats: &Statistics) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "uptime {:.3}", stats.uptime.as_secs_f64());
+    let _ = writeln!(out, "delete_rate {:.3}", stats.delete_rate);
+    let _ = writeln!(out, "errors {}", stats.errors);
+    let _ = writeln!(out, "jobs {}", stats.jobs);
+    let _ = writeln!(out, "small_files {}", stats.small_files.0);
+    let _ = writeln!(out, "small_bytes {}", stats.small_files.1);
+    let _ = writeln!(out, "gather_queue {}", stats.gather_queue);
+    let _ = writeln!(out, "stat_queue {}", stats.stat_queue);
+    let _ = writeln!(out, "stat_in_flight {}", stats.stat_in_flight);
+    let mut devices: Vec<_> = stats.devices.iter().collect();
+    devices.sort_by_key(|(device, _)| **device);
+    for (device, device_stats) in devices {
+        let prefix = format!("device.{}", device);
+        let _ = writeln!(out, "{}.files_deleted {}", prefix, device_stats.files_deleted);
+        let _ = writeln!(out, "{}.bytes_freed {}", prefix, device_stats.bytes_freed);
+        let _ = writeln!(out, "{}.dirs_deleted {}", prefix, device_stats.dirs_deleted);
+        let _ = writeln!(out, "{}.files_per_sec {:.3}", prefix, device_stats.files_per_sec);
+        let _ = writeln!(out, "{}.bytes_per_sec {:.3}", prefix, device_stats.bytes_per_sec);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statistics::Stats;
+
+    #[test]
+    fn stats_file() {
+        crate::tests::init_env_logging();
+
+        let path = std::env::temp_dir().join(format!("rmrfd-stats-{}", std::process::id()));
+        let stats = Stats::new();
+        stats.deleted(7, 2, 1024);
+        let stats_file = {
+            let stats = stats.clone();
+            StatsFile::start(path.clone(), Duration::from_millis(5), move || {
+                stats.snapshot((0, 0))
+            })
+            .unwrap()
+        };
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.lines().any(|line| line == "device.7.files_deleted 2"));
+        assert!(contents.lines().any(|line| line == "device.7.bytes_freed 1024"));
+
+        drop(stats_file);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!path.exists());
+    }
+}
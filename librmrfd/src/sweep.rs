@@ -0,0 +1,684 @@
+use std::ffi::{CStr, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dirinventory::openat::{AsPath, Dir, Metadata};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::RmrfdError;
+use crate::anchors::{cstr, Anchors};
+use crate::events::{Event, EventLog};
+use crate::nfs;
+use crate::pause::{PauseReason, Pauses};
+use crate::platform::{blocks_to_bytes, list_dir, metadata_types, Mount};
+use crate::profile::{timed, Syscall};
+use crate::watchdog::{self, Item};
+use crate::pathdisplay::PathEscape;
+
+/// How often a directory which got new entries while it was swept is swept again before it
+/// is given up.
+const RESCANS: u32 = 3;
+
+/// How many directory handles along the current path a sweep keeps open. The ones further up
+/// are closed and opened again by path when the sweep returns to them.
+const OPEN_DIRS: usize = 16;
+
+/// What a sweep removed.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SweepTotals {
+    pub(crate) dev:    metadata_types::dev_t,
+    pub(crate) files:  u64,
+    pub(crate) bytes:  u64,
+    pub(crate) dirs:   u64,
+    pub(crate) errors: u64,
+}
+
+/// Removes everything below the directory 'path' in a plain depth first walk, and 'path'
+/// itself when 'remove_root' is set. This is the cheap final pass after the big files are
+/// deleted. 'path' is opened below one of the 'anchors', all further operations are relative
+/// to the handle of the parent directory and never follow symlinks. Every directory is checked
+/// to be on 'mount' after it is opened, filesystems and bind mounts which appeared since the
+/// job was submitted are reported as RmrfdError::Mountpoint and left alone. Directories which
+/// are not empty when they are removed are swept again. Handles which went stale on NFS are
+/// opened again by path. When the filesystem goes read-only or out of space its device is
+/// paused in 'pauses' and the sweep waits until it is resumed. Silly renamed NFS files are
+/// retried at the end, together with the directories they kept in place, the ones still open
+/// then are reported as RmrfdError::Lingering. Errors are logged, counted and
+/// written to 'events' and passed to 'on_error' together with the number of objects removed so
+/// far. The sweep continues with the next entry unless 'on_error' returns 'false'.
+/// 'on_unlink' is called with the device and the time every file unlink took.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sweep(
+    anchors: &Anchors,
+    pauses: &Pauses,
+    path: &Path,
+    mount: Mount,
+    remove_root: bool,
+    events: &EventLog,
+    on_unlink: &dyn Fn(metadata_types::dev_t, Duration),
+    on_error: &dyn Fn(&RmrfdError, u64) -> bool,
+) -> io::Result<SweepTotals> {
+    let mut sweeper = Sweeper {
+        anchors,
+        pauses,
+        mount,
+        events,
+        on_unlink,
+        on_error,
+        aborted: false,
+        path: path.to_path_buf(),
+        buf: Vec::new(),
+        deferred: Vec::new(),
+        frames: Vec::new(),
+        names: Vec::new(),
+        name: Vec::new(),
+        totals: SweepTotals {
+            dev: mount.dev,
+            ..SweepTotals::default()
+        },
+    };
+    let dir = timed(Syscall::Open, || anchors.open_dir(path, &mut sweeper.buf))?;
+    if !sweeper.same_mount(&dir) {
+        return Ok(sweeper.totals);
+    }
+    sweeper.sweep_dir(&dir);
+    sweeper.retry_deferred();
+    if remove_root && !sweeper.aborted {
+        match anchors.open_parent(path, &mut sweeper.buf) {
+            Ok((parent, name)) => {
+                let mut rescans = 0;
+                while sweeper.remove_dir(&parent, name, rescans) && !sweeper.aborted {
+                    rescans += 1;
+                    sweeper.sweep_dir(&dir);
+                }
+            }
+            Err(err) => sweeper.error(RmrfdError::delete(path.to_path_buf(), err)),
+        }
+    }
+    if !sweeper.deferred.is_empty() {
+        warn!(
+            "sweep: {} files below {:?} still open on NFS clients, left in place",
+            sweeper.deferred.len(),
+            path.escaped()
+        );
+    }
+    watchdog::idle();
+    Ok(sweeper.totals)
+}
+
+/// State of a running sweep.
+struct Sweeper<'a> {
+    anchors:   &'a Anchors,
+    pauses:    &'a Pauses,
+    mount:     Mount,
+    events:    &'a EventLog,
+    on_unlink: &'a dyn Fn(metadata_types::dev_t, Duration),
+    on_error:  &'a dyn Fn(&RmrfdError, u64) -> bool,
+    /// Set when 'on_error' asked to stop, nothing more is removed then.
+    aborted:   bool,
+    /// The path of the current entry, only used for reporting. Names are pushed and popped,
+    /// sweeping a file does not allocate.
+    path:      PathBuf,
+    /// Names passed to the kernel when opening by path.
+    buf:       Vec<u8>,
+    /// Silly renamed files, in the order they were found. Directories containing one are
+    /// not removed until they are retried.
+    deferred:  Vec<PathBuf>,
+    /// The directories from the root of the sweep down to the current path.
+    frames:    Vec<Frame>,
+    /// The nul terminated names of the entries not swept yet, of all 'frames' in order.
+    names:     Vec<u8>,
+    /// The name of the entry swept.
+    name:      Vec<u8>,
+    totals:    SweepTotals,
+}
+
+/// A directory on the current path of a sweep.
+struct Frame {
+    /// The handle, None while it is closed, see OPEN_DIRS.
+    dir:     Option<Dir>,
+    /// A directory opened again has to be the same one.
+    dev:     metadata_types::dev_t,
+    ino:     metadata_types::ino_t,
+    /// Where the names of its entries start on the name stack.
+    names:   usize,
+    /// How often it was listed again, see remove_dir().
+    rescans: u32,
+}
+
+impl Sweeper<'_> {
+    /// Empties 'dir' which is at the current path. The walk is a loop over 'frames', a deep
+    /// tree needs neither stack nor more than OPEN_DIRS directory handles.
+    fn sweep_dir(&mut self, dir: &Dir) {
+        let root = timed(Syscall::Open, || dir.try_clone()).and_then(|dir| {
+            let metadata = dir.self_metadata()?;
+            Ok((dir, metadata))
+        });
+        match root {
+            Ok((root, metadata)) => self.push_frame(root, &metadata),
+            Err(err) => return self.gather_error(err),
+        }
+        while let Some(top) = self.frames.len().checked_sub(1) {
+            if self.aborted {
+                for _ in 0..top {
+                    self.path.pop();
+                }
+                self.frames.clear();
+                self.names.clear();
+                return;
+            }
+            if self.names.len() == self.frames[top].names {
+                match top {
+                    0 => {
+                        self.frames.pop();
+                    }
+                    _ => self.leave_dir(),
+                }
+                continue;
+            }
+            let Some(dir) = self.take_dir(top) else {
+                // could not be opened again, its entries are left in place
+                self.pop_frame();
+                continue;
+            };
+            watchdog::progress();
+            let buf = self.next_name();
+            let name = CStr::from_bytes_with_nul(&buf).expect("names are nul terminated");
+            self.path.push(OsStr::from_bytes(name.to_bytes()));
+            let sub_dir = self.sweep_entry(&dir, name);
+            self.name = buf;
+            self.frames[top].dir = Some(dir);
+            match sub_dir {
+                Some((sub_dir, metadata)) => self.push_frame(sub_dir, &metadata),
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+
+    /// Removes the entry 'name' of 'dir', which is at the current path. Directories are
+    /// only opened, they are returned with their metadata to be swept next.
+    fn sweep_entry(&mut self, dir: &Dir, name: &CStr) -> Option<(Dir, Metadata)> {
+        match timed(Syscall::Stat, || dir.metadata(name)) {
+            Ok(metadata) if metadata.is_dir() => {
+                match timed(Syscall::Open, || dir.sub_dir(name)) {
+                    // checked on the open handle, a mount appearing between the stat and the
+                    // open is caught as well
+                    Ok(sub_dir) if self.same_mount(&sub_dir) => return Some((sub_dir, metadata)),
+                    Ok(_) => {}
+                    Err(err) => self.gather_error(err),
+                }
+            }
+            Ok(_) if nfs::is_silly_renamed(OsStr::from_bytes(name.to_bytes())) => {
+                debug!("sweep: silly renamed, deferred {:?}", self.path.escaped());
+                self.deferred.push(self.path.clone());
+            }
+            Ok(metadata) => {
+                let start = Instant::now();
+                let removed = self.remove_file(dir, name);
+                (self.on_unlink)(self.totals.dev, start.elapsed());
+                if removed {
+                    self.totals.files += 1;
+                    // the space is only freed when the last link is gone
+                    if metadata.nlink() == Some(1) {
+                        self.totals.bytes += blocks_to_bytes(metadata.blocks().unwrap_or(0));
+                    }
+                }
+            }
+            Err(err) => self.gather_error(err),
+        }
+        None
+    }
+
+    /// Enters 'dir' with 'metadata', the current path, and lists its entries. Closes the
+    /// handle of the directory which drops out of the OPEN_DIRS deepest ones.
+    fn push_frame(&mut self, dir: Dir, metadata: &Metadata) {
+        watchdog::busy(Item::Path(self.path.clone()));
+        let names = self.names.len();
+        self.list(&dir);
+        self.frames.push(Frame {
+            dir: Some(dir),
+            dev: metadata.dev().unwrap_or(0),
+            ino: metadata.ino().unwrap_or(0),
+            names,
+            rescans: 0,
+        });
+        if let Some(index) = self.frames.len().checked_sub(OPEN_DIRS + 1) {
+            self.frames[index].dir = None;
+        }
+    }
+
+    /// Leaves the current directory without removing it, its entries not swept yet are
+    /// dropped.
+    fn pop_frame(&mut self) {
+        let frame = self.frames.pop().expect("a directory is swept");
+        self.names.truncate(frame.names);
+        if !self.frames.is_empty() {
+            self.path.pop();
+        }
+    }
+
+    /// Removes the current directory once all its entries are swept, lists it again when a
+    /// concurrent writer added new ones, see remove_dir().
+    fn leave_dir(&mut self) {
+        let top = self.frames.len() - 1;
+        let Some(parent) = self.take_dir(top - 1) else {
+            return self.pop_frame();
+        };
+        let mut name = std::mem::take(&mut self.name);
+        let rescan = match cstr(&mut name, self.path.file_name().expect("below the root")) {
+            Ok(name) => self.remove_dir(&parent, name, self.frames[top].rescans),
+            Err(err) => {
+                self.error(RmrfdError::delete(self.path.clone(), err));
+                false
+            }
+        };
+        self.name = name;
+        self.frames[top - 1].dir = Some(parent);
+        if !rescan {
+            return self.pop_frame();
+        }
+        self.frames[top].rescans += 1;
+        match self.take_dir(top) {
+            Some(dir) => {
+                self.list(&dir);
+                self.frames[top].dir = Some(dir);
+            }
+            None => self.pop_frame(),
+        }
+    }
+
+    /// Returns the handle of the directory in 'frames' at 'index', the current one or one of
+    /// its parents. A closed handle is opened again by path below the anchors and has to be
+    /// the same directory still. Failures are reported, the directory is left then.
+    fn take_dir(&mut self, index: usize) -> Option<Dir> {
+        if let Some(dir) = self.frames[index].dir.take() {
+            return Some(dir);
+        }
+        let up = self.frames.len() - 1 - index;
+        let path = self.path.ancestors().nth(up).expect("one component per frame");
+        let reopened = timed(Syscall::Open, || self.anchors.open_dir(path, &mut self.buf))
+            .and_then(|dir| Ok((dir.self_metadata()?, dir)));
+        let frame = &self.frames[index];
+        match reopened {
+            Ok((metadata, dir))
+                if (metadata.dev().unwrap_or(0), metadata.ino().unwrap_or(0))
+                    == (frame.dev, frame.ino) =>
+            {
+                trace!("sweep: reopened {:?}", path.escaped());
+                Some(dir)
+            }
+            Ok(_) => {
+                self.error(RmrfdError::Replaced(path.to_path_buf()));
+                None
+            }
+            Err(err) => {
+                let path = path.to_path_buf();
+                self.error(RmrfdError::Gather { path, source: err });
+                None
+            }
+        }
+    }
+
+    /// Pushes the names of the entries of 'dir', which is at the current path, onto the name
+    /// stack.
+    fn list(&mut self, dir: &Dir) {
+        let mut entries = match timed(Syscall::Open, || list_dir(dir)) {
+            Ok(entries) => entries,
+            Err(err) => return self.gather_error(err),
+        };
+        while let Some(entry) = timed(Syscall::Getdents, || entries.next()) {
+            if self.aborted {
+                return;
+            }
+            watchdog::progress();
+            match entry {
+                Ok(entry) => {
+                    self.names.extend_from_slice(entry.file_name().as_bytes());
+                    self.names.push(0);
+                }
+                Err(err) => self.gather_error(err),
+            }
+        }
+    }
+
+    /// Pops the last name of the current directory from the name stack into the name buffer,
+    /// which is taken until the caller puts it back.
+    fn next_name(&mut self) -> Vec<u8> {
+        let start = self.frames.last().map_or(0, |frame| frame.names);
+        // each name ends with its nul, the last one starts after the nul before
+        let begin = self.names[start..self.names.len() - 1]
+            .iter()
+            .rposition(|&byte| byte == 0)
+            .map_or(start, |nul| start + nul + 1);
+        let mut name = std::mem::take(&mut self.name);
+        name.clear();
+        name.extend_from_slice(&self.names[begin..]);
+        self.names.truncate(begin);
+        name
+    }
+
+    /// Removes the file 'name' in 'dir', which is at the current path. Returns 'true' on
+    /// success.
+    fn remove_file<P: AsPath + Copy>(&mut self, dir: &Dir, name: P) -> bool {
+        match self.retry(dir, |dir| timed(Syscall::Unlink, || dir.remove_file(name))) {
+            Ok(()) => {
+                trace!("sweep: removed {:?}", self.path.escaped());
+                true
+            }
+            Err(err) => {
+                self.error(RmrfdError::delete_in(dir, name, self.path.clone(), err));
+                false
+            }
+        }
+    }
+
+    /// Removes the directory 'name' in 'parent', which is at the current path and was swept
+    /// again 'rescans' times. Returns 'true' when a concurrent writer added entries since and
+    /// it has to be swept again, after RESCANS times it is reported as RmrfdError::NotEmpty.
+    /// Directories holding a deferred silly renamed file are left for retry_deferred().
+    fn remove_dir<P: AsPath + Copy>(&mut self, parent: &Dir, name: P, rescans: u32) -> bool {
+        // depth first, when a deferred file is below the current path it is the last one
+        if self.deferred.last().is_some_and(|file| file.starts_with(&self.path)) {
+            return false;
+        }
+        match self.retry(parent, |parent| timed(Syscall::Unlink, || parent.remove_dir(name))) {
+            Ok(()) => {
+                trace!("sweep: removed {:?}", self.path.escaped());
+                self.totals.dirs += 1;
+                self.events.emit(Event::Dir { path: &self.path });
+                false
+            }
+            // POSIX allows EEXIST for a directory which is not empty
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOTEMPTY | libc::EEXIST)) => {
+                if rescans == RESCANS {
+                    self.error(RmrfdError::NotEmpty {
+                        path: self.path.clone(),
+                        rescans,
+                    });
+                    return false;
+                }
+                debug!("sweep: {:?} not empty, rescan {}", self.path.escaped(), rescans + 1);
+                true
+            }
+            Err(err) => {
+                self.error(RmrfdError::delete_in(parent, name, self.path.clone(), err));
+                false
+            }
+        }
+    }
+
+    /// Runs 'op' on 'dir', the parent directory of the current path, once the device is not
+    /// paused. When the handle went stale on NFS 'op' is retried up to STALE_RETRIES times,
+    /// when the filesystem went read-only or out of space the device is paused and 'op'
+    /// retried after it was resumed. Retries open the parent again by path.
+    fn retry<T>(&mut self, dir: &Dir, op: impl Fn(&Dir) -> io::Result<T>) -> io::Result<T> {
+        let dev = self.totals.dev;
+        self.pauses.wait(dev)?;
+        let mut result = op(dir);
+        let mut retries = 0;
+        loop {
+            match &result {
+                Err(err) if nfs::is_stale(err) && retries < nfs::STALE_RETRIES => {
+                    retries += 1;
+                    let path = self.path.escaped();
+                    debug!("sweep: stale file handle, retry {}: {:?}", retries, path);
+                }
+                Err(err) => match PauseReason::of(err) {
+                    Some(reason) => {
+                        self.pauses.pause(dev, reason, &self.path);
+                        self.pauses.wait(dev)?;
+                    }
+                    None => return result,
+                },
+                Ok(_) => return result,
+            }
+            let parent = self.path.parent().unwrap_or(&self.path);
+            result = timed(Syscall::Open, || self.anchors.open_dir(parent, &mut self.buf))
+                .and_then(|dir| op(&dir));
+        }
+    }
+
+    /// Retries the deferred silly renamed files after a delay, usually the NFS client removes
+    /// them itself once they are closed. Afterwards the directories which were kept for them
+    /// are removed, below the current path which is the root of the sweep. Files still there
+    /// after SILLY_RETRIES are reported as RmrfdError::Lingering and stay in 'deferred'.
+    fn retry_deferred(&mut self) {
+        let mut gone = Vec::new();
+        let mut failed = Vec::new();
+        let mut delay = nfs::SILLY_DELAY;
+        for _ in 0..nfs::SILLY_RETRIES {
+            if self.deferred.is_empty() || self.aborted {
+                break;
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            for file in std::mem::take(&mut self.deferred) {
+                match self.remove_path(&file, false) {
+                    Ok(()) => {
+                        self.totals.files += 1;
+                        gone.push(file);
+                    }
+                    // the client removed it when it was closed
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => gone.push(file),
+                    Err(err) if matches!(err.raw_os_error(), Some(libc::EBUSY | libc::ESTALE)) => {
+                        self.deferred.push(file)
+                    }
+                    Err(err) => {
+                        self.error(RmrfdError::delete(file.clone(), err));
+                        failed.push(file);
+                    }
+                }
+            }
+        }
+
+        // the directories between the root and the files which are gone now, deepest first
+        let mut dirs: Vec<PathBuf> = gone
+            .iter()
+            .flat_map(|file| file.ancestors().skip(1))
+            .filter(|dir| dir.starts_with(&self.path) && *dir != self.path)
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.sort_unstable_by(|a, b| b.cmp(a));
+        dirs.dedup();
+        for dir in dirs {
+            if self.aborted {
+                return;
+            }
+            if self.deferred.iter().chain(&failed).any(|file| file.starts_with(&dir)) {
+                continue;
+            }
+            match self.remove_path(&dir, true) {
+                Ok(()) => {
+                    self.totals.dirs += 1;
+                    self.events.emit(Event::Dir { path: &dir });
+                }
+                Err(err) => self.error(RmrfdError::delete(dir, err)),
+            }
+        }
+
+        for file in self.deferred.clone() {
+            self.error(RmrfdError::Lingering(file));
+        }
+    }
+
+    /// Removes the file or, with 'is_dir', the directory 'path', its parent is opened by path
+    /// below the anchors.
+    fn remove_path(&mut self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let (parent, name) = self.anchors.open_parent(path, &mut self.buf)?;
+        let name = cstr(&mut self.buf, name)?;
+        match is_dir {
+            true => timed(Syscall::Unlink, || parent.remove_dir(name)),
+            false => timed(Syscall::Unlink, || parent.remove_file(name)),
+        }
+    }
+
+    /// Returns 'true' when 'dir' which is at the current path is on the mount of the job,
+    /// reports it as a mountpoint otherwise.
+    fn same_mount(&mut self, dir: &Dir) -> bool {
+        match timed(Syscall::Stat, || Mount::of(dir)) {
+            Ok(mount) if mount == self.mount => true,
+            Ok(_) => {
+                self.error(RmrfdError::Mountpoint(self.path.clone()));
+                false
+            }
+            Err(err) => {
+                self.gather_error(err);
+                false
+            }
+        }
+    }
+
+    fn gather_error(&mut self, err: io::Error) {
+        self.error(RmrfdError::Gather {
+            path:   self.path.clone(),
+            source: err,
+        });
+    }
+
+    fn error(&mut self, error: RmrfdError) {
+        warn!("sweep: {}", error);
+        self.events.emit(Event::Error { error: &error });
+        self.totals.errors += 1;
+        if !(self.on_error)(&error, self.totals.files + self.totals.dirs) {
+            self.aborted = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn sweep_tree() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-sweep-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/file"), b"data").unwrap();
+        fs::write(root.join("a/b/file"), b"data").unwrap();
+        let outside = root.with_extension("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("file"), b"data").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("a/link")).unwrap();
+
+        let anchors = Anchors::new([std::env::temp_dir().as_path()].into_iter()).unwrap();
+        let pauses = Pauses::new(Arc::default());
+        let mount = Mount::of(&Dir::open(&root).unwrap()).unwrap();
+        // a directory on another mount is never entered
+        let other = Mount {
+            dev: mount.dev + 1,
+            ..mount
+        };
+        let events = EventLog::default();
+        let (no_unlink, no_error) = (|_, _| {}, |_: &RmrfdError, _| true);
+        let totals = sweep(&anchors, &pauses, &root, other, true, &events, &no_unlink, &no_error)
+            .unwrap();
+        assert_eq!(totals.errors, 1);
+        assert_eq!(totals.files, 0);
+        assert!(root.join("a/b/file").exists());
+
+        let unlinks = std::cell::Cell::new(0);
+        let on_unlink = |_, _| unlinks.set(unlinks.get() + 1);
+        let totals = sweep(&anchors, &pauses, &root, mount, false, &events, &on_unlink, &no_error)
+            .unwrap();
+        assert_eq!(totals.files, 3);
+        assert_eq!(totals.dirs, 2);
+        assert_eq!(totals.errors, 0);
+        assert_eq!(unlinks.get(), 3);
+        assert!(root.exists());
+        // symlinks are removed, never followed
+        assert!(outside.join("file").exists());
+        fs::remove_dir_all(&outside).unwrap();
+
+        let totals = sweep(&anchors, &pauses, &root, mount, true, &events, &no_unlink, &no_error)
+            .unwrap();
+        assert_eq!(totals.dirs, 1);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn deep_tree() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-deep-{}", std::process::id()));
+        // deeper than the handles kept open, the directories further up are opened again
+        let depth = OPEN_DIRS * 3;
+        let mut dir = root.clone();
+        for _ in 0..depth {
+            dir.push("d");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), b"data").unwrap();
+            fs::create_dir(dir.join("empty")).unwrap();
+        }
+
+        let anchors = Anchors::new([std::env::temp_dir().as_path()].into_iter()).unwrap();
+        let pauses = Pauses::new(Arc::default());
+        let mount = Mount::of(&Dir::open(&root).unwrap()).unwrap();
+        let (events, no_unlink, no_error) =
+            (EventLog::default(), |_, _| {}, |_: &RmrfdError, _| true);
+        let totals = sweep(&anchors, &pauses, &root, mount, true, &events, &no_unlink, &no_error)
+            .unwrap();
+        assert_eq!(totals.errors, 0);
+        assert_eq!(totals.files, depth as u64);
+        assert_eq!(totals.dirs, depth as u64 * 2 + 1);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn rescan() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-rescan-{}", std::process::id()));
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/file"), b"data").unwrap();
+
+        let anchors = Anchors::new([std::env::temp_dir().as_path()].into_iter()).unwrap();
+        let pauses = Pauses::new(Arc::default());
+        let mount = Mount::of(&Dir::open(&root).unwrap()).unwrap();
+        // a concurrent writer which adds a new file for the first two ones removed
+        let added = std::cell::Cell::new(0);
+        let on_unlink = |_, _| {
+            if added.get() < 2 {
+                added.set(added.get() + 1);
+                fs::write(root.join(format!("a/new{}", added.get())), b"data").unwrap();
+            }
+        };
+        let (events, no_error) = (EventLog::default(), |_: &RmrfdError, _| true);
+        let totals = sweep(&anchors, &pauses, &root, mount, true, &events, &on_unlink, &no_error)
+            .unwrap();
+        assert_eq!(totals.files, 3);
+        assert_eq!(totals.errors, 0);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn silly_renamed() {
+        crate::tests::init_env_logging();
+        let root = std::env::temp_dir().join(format!("rmrfd-silly-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/.nfs000000000012abcd00000001"), b"data").unwrap();
+        fs::write(root.join("a/file"), b"data").unwrap();
+
+        let anchors = Anchors::new([std::env::temp_dir().as_path()].into_iter()).unwrap();
+        let pauses = Pauses::new(Arc::default());
+        let mount = Mount::of(&Dir::open(&root).unwrap()).unwrap();
+        // only the order of removal differs, the deferred file is not open anywhere
+        let removed = std::cell::RefCell::new(Vec::new());
+        let on_unlink = |_, _| removed.borrow_mut().push(root.join("a/file").exists());
+        let (events, no_error) = (EventLog::default(), |_: &RmrfdError, _| true);
+        let totals = sweep(&anchors, &pauses, &root, mount, true, &events, &on_unlink, &no_error)
+            .unwrap();
+        assert_eq!(*removed.borrow(), [false]);
+        assert_eq!(totals.files, 2);
+        assert_eq!(totals.dirs, 3);
+        assert_eq!(totals.errors, 0);
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,108 @@
+use std::cell::Cell;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::platform::{set_ioprio, set_nice};
+
+/// The thread pools of the daemon, each can be given its own priorities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pool {
+    /// Threads listing directories.
+    Gather,
+    /// Threads fetching the metadata of directory entries.
+    Stat,
+    /// Threads maintaining the inventory and deleting files.
+    Inventory,
+}
+
+/// IO scheduling class, see ioprio_set(2). The levels range from 0 (highest) to 7 (lowest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// Realtime class with the given level, needs CAP_SYS_ADMIN.
+    RealTime(u8),
+    /// The default class with the given level.
+    BestEffort(u8),
+    /// Only gets disk time when no one else needs it.
+    Idle,
+}
+
+impl IoClass {
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    /// The value passed to ioprio_set(2).
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn ioprio(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoClass::RealTime(level) => (1, level),
+            IoClass::BestEffort(level) => (2, level),
+            IoClass::Idle => (3, 0),
+        };
+        class << Self::IOPRIO_CLASS_SHIFT | libc::c_int::from(level.min(7))
+    }
+}
+
+/// IO class and niceness applied to the threads of a pool.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ThreadPriority {
+    pub(crate) io_class: Option<IoClass>,
+    pub(crate) nice:     Option<libc::c_int>,
+}
+
+impl ThreadPriority {
+    /// Applies the priorities to the calling thread. Failures are only logged, running at
+    /// the wrong priority is better than not running at all.
+    pub(crate) fn apply(&self) {
+        if let Some(io_class) = self.io_class {
+            if let Err(err) = set_ioprio(io_class) {
+                warn!("setting io class {:?}: {}", io_class, err);
+            }
+        }
+        if let Some(nice) = self.nice {
+            if let Err(err) = set_nice(nice) {
+                warn!("setting nice {}: {}", nice, err);
+            }
+        }
+    }
+
+    /// Applies the priorities only on the first call in each thread. Used for threads which
+    /// are not spawned by us but call back into our code.
+    pub(crate) fn apply_once(&self) {
+        thread_local!(static APPLIED: Cell<bool> = const { Cell::new(false) });
+        APPLIED.with(|applied| {
+            if !applied.replace(true) {
+                self.apply();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn apply() {
+        crate::tests::init_env_logging();
+
+        std::thread::spawn(|| {
+            ThreadPriority {
+                io_class: Some(IoClass::BestEffort(7)),
+                nice:     Some(19),
+            }
+            .apply_once();
+
+            // SAFETY: plain syscalls querying the calling thread
+            unsafe {
+                assert_eq!(
+                    libc::syscall(libc::SYS_ioprio_get, 1, 0),
+                    IoClass::BestEffort(7).ioprio() as libc::c_long
+                );
+                assert_eq!(libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t), 19);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Structured traces with the 'tracing' crate, enabled by the 'tracing' feature. There is a
+//! span for every job, every inventory pass and every deletion phase. Without the feature
+//! the spans are zero sized stand-ins and compile to nothing.
+pub(crate) use imp::*;
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::path::Path;
+
+    pub(crate) use tracing::Span;
+
+    use crate::JobState;
+    use crate::platform::metadata_types;
+
+    /// Span covering a job from submission until it is done or cancelled.
+    pub(crate) fn job_span(path: &Path, uid: Option<libc::uid_t>) -> Span {
+        tracing::info_span!(parent: None, "job", path = ?path, uid = ?uid)
+    }
+
+    /// Emits an event for a job state change in the span of the job.
+    pub(crate) fn job_state(span: &Span, state: JobState) {
+        tracing::info!(parent: span, state = ?state, "job state");
+    }
+
+    /// Span covering one pass of an inventory thread, from its first entry until all
+    /// gatherer channels reported 'Done'. The number of 'entries' is recorded at the end.
+    pub(crate) fn pass_span(thread: usize) -> Span {
+        tracing::info_span!(
+            parent: None,
+            "pass",
+            thread,
+            entries = tracing::field::Empty
+        )
+    }
+
+    /// Span of a deletion phase ("fastrmrf" or "sweep") on 'device'. The 'files' and
+    /// 'bytes' deleted are recorded at the end.
+    pub(crate) fn phase_span(
+        parent: &Span,
+        phase: &'static str,
+        device: metadata_types::dev_t,
+        path: Option<&Path>,
+    ) -> Span {
+        tracing::info_span!(
+            parent: parent,
+            "phase",
+            phase,
+            device,
+            path = ?path,
+            files = tracing::field::Empty,
+            bytes = tracing::field::Empty
+        )
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use std::path::Path;
+
+    use crate::JobState;
+    use crate::platform::metadata_types;
+
+    /// Stand-in for 'tracing::Span'.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub(crate) fn record(&self, _field: &str, _value: u64) -> &Self {
+            self
+        }
+    }
+
+    pub(crate) fn job_span(_path: &Path, _uid: Option<libc::uid_t>) -> Span {
+        Span
+    }
+
+    pub(crate) fn job_state(_span: &Span, _state: JobState) {}
+
+    pub(crate) fn pass_span(_thread: usize) -> Span {
+        Span
+    }
+
+    pub(crate) fn phase_span(
+        _parent: &Span,
+        _phase: &'static str,
+        _device: metadata_types::dev_t,
+        _path: Option<&Path>,
+    ) -> Span {
+        Span
+    }
+}
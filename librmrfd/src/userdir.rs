@@ -0,0 +1,112 @@
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::pathdisplay::PathEscape;
+
+/// Mode of the per user directories, only the owner may move things in.
+const USER_DIR_MODE: u32 = 0o700;
+
+/// Returns the per user directory for 'uid' in the rmrf directory 'root', creates it when
+/// missing. An existing directory must be owned by 'uid' and not be accessible by others.
+pub(crate) fn user_dir(root: &Path, uid: libc::uid_t) -> io::Result<PathBuf> {
+    let path = root.join(uid.to_string());
+    match create_owned(&path, uid) {
+        Ok(()) => debug!("created user dir {:?}", path.escaped()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    check_user_dir(&path, uid)?;
+    Ok(path)
+}
+
+/// Creates a new directory with a unique name in 'parent' for 'uid' to move things into, see
+/// Rmrfd::reserve().
+pub(crate) fn temp_dir(parent: &Path, uid: libc::uid_t) -> io::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    loop {
+        let path = parent.join(format!(
+            "tmp.{:x}.{:x}",
+            nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match create_owned(&path, uid) {
+            Ok(()) => {
+                debug!("created temp dir {:?}", path.escaped());
+                return Ok(path);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Creates the directory 'path' with USER_DIR_MODE and hands it over to 'uid'.
+fn create_owned(path: &Path, uid: libc::uid_t) -> io::Result<()> {
+    fs::DirBuilder::new().mode(USER_DIR_MODE).create(path)?;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: 'cpath' is a valid C string, gid -1 leaves the group unchanged
+    if unsafe { libc::lchown(cpath.as_ptr(), uid, libc::gid_t::MAX) } != 0 {
+        let err = io::Error::last_os_error();
+        let _ = fs::remove_dir(path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Checks that 'path' is a directory (not a symlink) owned by 'uid' with the expected mode.
+pub(crate) fn check_user_dir(path: &Path, uid: libc::uid_t) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        warn!("user dir {:?} has wrong type, owner or mode", path.escaped());
+        return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+    }
+    Ok(())
+}
+
+/// Returns the uid a per user directory name stands for.
+pub(crate) fn user_dir_uid(name: &OsStr) -> Option<libc::uid_t> {
+    name.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn create_and_check() {
+        crate::tests::init_env_logging();
+
+        let root = std::env::temp_dir().join(format!("rmrfd-userdir-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        // SAFETY: getuid can't fail
+        let uid = unsafe { libc::getuid() };
+
+        let path = user_dir(&root, uid).unwrap();
+        assert_eq!(user_dir_uid(path.file_name().unwrap()), Some(uid));
+        assert_eq!(user_dir(&root, uid).unwrap(), path);
+        assert!(check_user_dir(&path, uid.wrapping_add(1)).is_err());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(user_dir(&root, uid).is_err());
+
+        let temp = temp_dir(&root, uid).unwrap();
+        assert_ne!(temp_dir(&root, uid).unwrap(), temp);
+        assert!(check_user_dir(&temp, uid).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
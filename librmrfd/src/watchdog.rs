@@ -0,0 +1,220 @@
+//! Detects worker threads which stopped making progress, for example on a hung NFS mount.
+//! Workers register themself and mark when they start and finish working on an item, a
+//! thread busy with the same item for longer than the timeout is considered stuck. A thread
+//! blocked in a syscall can't be interrupted, stuck threads are only reported.
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use dirinventory::ObjectPath;
+use parking_lot::Mutex;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use crate::pathdisplay::PathEscape;
+
+/// What a worker thread is working on.
+#[derive(Debug, Clone)]
+pub(crate) enum Item {
+    /// An object from the inventory, or the directory of an entry being stat()ed.
+    Object(Arc<ObjectPath>),
+    /// A path being swept or unlinked.
+    Path(PathBuf),
+}
+
+impl Item {
+    fn to_pathbuf(&self) -> PathBuf {
+        match self {
+            Item::Object(path) => path.to_pathbuf(),
+            Item::Path(path) => path.clone(),
+        }
+    }
+}
+
+/// A worker thread which is busy with one item for longer than the watchdog timeout, see
+/// Rmrfd::health().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StuckThread {
+    /// Name of the thread.
+    pub thread: String,
+    /// How long the thread is busy with its current item.
+    pub busy:   Duration,
+    /// The path the thread is working on, when known.
+    pub item:   Option<PathBuf>,
+}
+
+/// The state of a single worker thread.
+#[derive(Debug)]
+struct Heartbeat {
+    name:       String,
+    start:      Instant,
+    /// Milliseconds since 'start' plus one when the current item was started, zero when idle.
+    busy_since: AtomicU64,
+    item:       Mutex<Option<Item>>,
+}
+
+impl Heartbeat {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64 + 1
+    }
+}
+
+thread_local! {
+    static HEARTBEAT: RefCell<Option<Arc<Heartbeat>>> = const { RefCell::new(None) };
+}
+
+/// Marks the current thread busy with 'item'. Does nothing in unregistered threads.
+pub(crate) fn busy(item: Item) {
+    HEARTBEAT.with_borrow(|heartbeat| {
+        if let Some(heartbeat) = heartbeat {
+            *heartbeat.item.lock() = Some(item);
+            heartbeat.busy_since.store(heartbeat.now(), Ordering::Relaxed);
+        }
+    });
+}
+
+/// Marks that the current thread made progress on its item.
+pub(crate) fn progress() {
+    HEARTBEAT.with_borrow(|heartbeat| {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.busy_since.store(heartbeat.now(), Ordering::Relaxed);
+        }
+    });
+}
+
+/// Marks the current thread idle, waiting for work is never stuck.
+pub(crate) fn idle() {
+    HEARTBEAT.with_borrow(|heartbeat| {
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.busy_since.store(0, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Registry of the worker threads of a Rmrfd.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    start:   Instant,
+    timeout: Duration,
+    threads: Mutex<Vec<Weak<Heartbeat>>>,
+}
+
+impl Watchdog {
+    /// Creates a Watchdog which considers threads stuck after 'timeout'.
+    pub(crate) fn new(timeout: Duration) -> Arc<Watchdog> {
+        Arc::new(Watchdog {
+            start: Instant::now(),
+            timeout,
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Registers the current thread, must be called by every worker thread on start. The
+    /// thread is forgotten when it exits.
+    pub(crate) fn register(&self) {
+        let heartbeat = Arc::new(Heartbeat {
+            name:       thread::current().name().unwrap_or("unnamed").to_string(),
+            start:      self.start,
+            busy_since: AtomicU64::new(0),
+            item:       Mutex::new(None),
+        });
+        let mut threads = self.threads.lock();
+        threads.retain(|thread| thread.strong_count() > 0);
+        threads.push(Arc::downgrade(&heartbeat));
+        HEARTBEAT.set(Some(heartbeat));
+    }
+
+    /// Returns all threads busy with their current item for longer than the timeout.
+    pub(crate) fn stuck(&self) -> Vec<StuckThread> {
+        let now = self.start.elapsed().as_millis() as u64 + 1;
+        self.threads
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter_map(|heartbeat| {
+                let since = heartbeat.busy_since.load(Ordering::Relaxed);
+                let busy = Duration::from_millis(now.saturating_sub(since));
+                (since != 0 && busy >= self.timeout).then(|| StuckThread {
+                    thread: heartbeat.name.clone(),
+                    busy,
+                    item: heartbeat.item.lock().as_ref().map(Item::to_pathbuf),
+                })
+            })
+            .collect()
+    }
+
+    /// Starts a thread which logs the stuck threads twice per timeout. The thread stops when
+    /// the returned handle is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> io::Result<WatchdogThread> {
+        let (stop, stopped) = bounded(0);
+        let watchdog = self.clone();
+        thread::Builder::new()
+            .name(String::from("watchdog"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Err(RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(watchdog.timeout / 2)
+                {
+                    for stuck in watchdog.stuck() {
+                        warn!(
+                            "watchdog: thread {} stuck for {:?} at {:?}",
+                            stuck.thread,
+                            stuck.busy,
+                            stuck.item.as_deref().map(Path::escaped)
+                        );
+                    }
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(WatchdogThread { _stop: stop })
+    }
+}
+
+/// Handle of the thread started by Watchdog::start().
+#[derive(Debug)]
+pub(crate) struct WatchdogThread {
+    _stop: Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stuck() {
+        crate::tests::init_env_logging();
+
+        let watchdog = Watchdog::new(Duration::from_millis(10));
+        let (release, released) = bounded::<()>(0);
+        let worker = {
+            let watchdog = watchdog.clone();
+            thread::Builder::new()
+                .name(String::from("worker"))
+                .spawn(move || {
+                    watchdog.register();
+                    busy(Item::Path(PathBuf::from("/tmp/rmrf/hung")));
+                    let _ = released.recv();
+                    idle();
+                    let _ = released.recv();
+                })
+                .unwrap()
+        };
+
+        thread::sleep(Duration::from_millis(30));
+        let stuck = watchdog.stuck();
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].thread, "worker");
+        assert_eq!(stuck[0].item, Some(PathBuf::from("/tmp/rmrf/hung")));
+
+        release.send(()).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        assert!(watchdog.stuck().is_empty());
+        release.send(()).unwrap();
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! The rmrf command. Removes files and directory trees by handing them over to the rmrfd, see
+//! the README.
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: rmrf [--sync N] [--socket PATH] PATH...
+
+Moves every 'PATH' into a rmrf directory on the same filesystem, the rmrfd deletes it in
+background from there.
+
+  --sync N       -1 (default) returns at once, 0 when the size to be freed is known, 1 to 100
+                 when as many percent of it are freed, the 1k blocks are printed then
+  --socket PATH  the control socket of the rmrfd, RMRFD_SOCKET or /run/rmrfd.sock by default
+  --help         show this help";
+
+/// The settings from the command line.
+#[derive(Debug, PartialEq, Eq)]
+struct Config {
+    sync:   i8,
+    socket: Option<PathBuf>,
+    paths:  Vec<PathBuf>,
+    help:   bool,
+}
+
+impl Config {
+    /// Parses the command line 'args' without the program name.
+    fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Config, String> {
+        let mut config = Config {
+            sync:   -1,
+            socket: None,
+            paths:  Vec::new(),
+            help:   false,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--sync") => {
+                    config.sync = args
+                        .next()
+                        .and_then(|sync| sync.to_str()?.parse().ok())
+                        .filter(|sync| (-1..=100).contains(sync))
+                        .ok_or("--sync needs a number from -1 to 100")?;
+                }
+                Some("--socket") => {
+                    config.socket = Some(args.next().ok_or("--socket needs a path")?.into());
+                }
+                Some("--help") => config.help = true,
+                Some("--") => config.paths.extend(args.by_ref().map(PathBuf::from)),
+                Some(option) if option.starts_with("--") => {
+                    return Err(format!("unknown option {}", option));
+                }
+                _ => config.paths.push(arg.into()),
+            }
+        }
+        if config.paths.is_empty() && !config.help {
+            return Err(String::from("nothing to remove"));
+        }
+        Ok(config)
+    }
+}
+
+fn main() -> ExitCode {
+    let config = match Config::parse(env::args_os().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("rmrf: {}\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if config.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    let socket = config.socket.unwrap_or_else(librmrf::socket);
+    let mut status = ExitCode::SUCCESS;
+    for path in &config.paths {
+        match librmrf::rmrf_at(&socket, path, config.sync) {
+            Ok(blocks) if config.sync >= 0 => println!("{}\t{}", blocks, escaped(path)),
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("rmrf: {}: {}", escaped(path), err);
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
+
+/// Returns 'path' printable, with control characters escaped.
+fn escaped(path: &Path) -> String {
+    path.to_string_lossy().escape_debug().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Config::parse(args(&["--sync", "85", "a", "--", "--b"])).unwrap(),
+            Config {
+                sync:   85,
+                socket: None,
+                paths:  vec![PathBuf::from("a"), PathBuf::from("--b")],
+                help:   false,
+            }
+        );
+        assert_eq!(Config::parse(args(&["a"])).unwrap().sync, -1);
+        assert!(Config::parse(args(&["--sync", "101", "a"])).is_err());
+        assert!(Config::parse(args(&[])).is_err());
+    }
+}
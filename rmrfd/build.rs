@@ -0,0 +1,11 @@
+//! Generates the gRPC service from proto/rmrfd.proto when the 'grpc' feature is enabled. The
+//! protoc binary comes vendored, none needs to be installed.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/rmrfd.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::compile_protos("proto/rmrfd.proto").expect("compiling rmrfd.proto");
+    }
+}
@@ -0,0 +1,95 @@
+//! Authorization of privileged requests on the control socket. Root may do everything, other
+//! users only when polkit authorizes the requesting process for the action, see
+//! 'org.rmrfd.policy'. Without polkit enabled they are refused.
+use std::fs;
+use std::io;
+use std::process::{Command, Stdio};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::RmrfdError;
+
+/// The privileged requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Cancelling a job of another user.
+    CancelOthers,
+    /// Resuming the deletion on a paused device.
+    Resume,
+}
+
+impl Action {
+    /// The polkit action id.
+    fn id(self) -> &'static str {
+        match self {
+            Action::CancelOthers => "org.rmrfd.cancel-others",
+            Action::Resume => "org.rmrfd.resume",
+        }
+    }
+}
+
+/// The process on the other end of a session, from the peer credentials of the socket.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Peer {
+    pub(crate) pid: libc::pid_t,
+    pub(crate) uid: libc::uid_t,
+}
+
+/// Checks that 'peer' may do 'action', for users other than root by asking polkit when
+/// 'polkit' is enabled.
+pub(crate) fn authorize(peer: Peer, action: Action, polkit: bool) -> Result<(), RmrfdError> {
+    if peer.uid == 0 {
+        return Ok(());
+    }
+    if polkit {
+        match pkcheck(peer, action) {
+            Ok(true) => {
+                info!("uid {} authorized for {}", peer.uid, action.id());
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(err) => warn!("pkcheck: {}", err),
+        }
+    }
+    warn!("uid {} not authorized for {}", peer.uid, action.id());
+    Err(RmrfdError::NotAuthorized(String::from(action.id())))
+}
+
+/// Asks polkit whether 'peer' is authorized for 'action'. The process is identified together
+/// with its start time, a process which reused the pid meanwhile is not authorized.
+fn pkcheck(peer: Peer, action: Action) -> io::Result<bool> {
+    let process = format!("{},{},{}", peer.pid, start_time(peer.pid)?, peer.uid);
+    let status = Command::new("pkcheck")
+        .args(["--action-id", action.id(), "--process", &process])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// Returns the start time of the process 'pid' in clock ticks since boot.
+fn start_time(pid: libc::pid_t) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // field 22, counted after the command name which may contain spaces and parentheses
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(19)?.parse().ok())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_only() {
+        let root = Peer { pid: 1, uid: 0 };
+        let user = Peer { pid: 1, uid: 1000 };
+        assert!(authorize(root, Action::Resume, false).is_ok());
+        assert!(matches!(
+            authorize(user, Action::CancelOthers, false),
+            Err(RmrfdError::NotAuthorized(_))
+        ));
+        assert!(start_time(std::process::id() as libc::pid_t).unwrap() > 0);
+    }
+}
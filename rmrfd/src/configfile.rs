@@ -0,0 +1,189 @@
+//! The configuration file given with --config. It sets the RMRFD_* variables of
+//! RmrfdBuilder::from_vars() as 'NAME=VALUE' lines, '#' starts a comment line. Variables not
+//! set in the file are taken from the environment. On SIGHUP the file is read again, the
+//! settings in ReconfigRequest::VARS are applied to the running daemon, changes of all others
+//! are only logged, they need a restart. That includes RMRFD_SPOOL_DIRS, rmrf directories are
+//! not added or removed while running.
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::{PathEscape, ReconfigRequest, Rmrfd, RmrfdError};
+
+/// The variables set by a configuration file.
+type Vars = HashMap<String, OsString>;
+
+/// A loaded configuration file.
+#[derive(Debug)]
+pub(crate) struct ConfigFile {
+    path: PathBuf,
+    vars: Vars,
+}
+
+impl ConfigFile {
+    /// Reads the configuration file 'path'.
+    pub(crate) fn load(path: &Path) -> io::Result<ConfigFile> {
+        let vars = read(path)?;
+        info!("configuration {:?}", path.escaped());
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            vars,
+        })
+    }
+
+    /// Returns the value of the variable 'name', from the file or the environment.
+    pub(crate) fn var(&self, name: &str) -> Option<OsString> {
+        lookup(&self.vars, name)
+    }
+
+    /// Reads the file again and applies the changed settings which can be changed on
+    /// 'rmrfd'. Returns the names of the changed variables which need a restart. When the
+    /// file is unusable nothing is applied.
+    pub(crate) fn reload(&mut self, rmrfd: &Rmrfd) -> Result<Vec<String>, RmrfdError> {
+        let vars = read(&self.path)?;
+        let changed = changed(&self.vars, &vars);
+        let request = ReconfigRequest::from_vars(|name| {
+            changed
+                .iter()
+                .any(|changed| changed == name)
+                .then(|| lookup(&vars, name))
+                .flatten()
+        })?;
+        rmrfd.reconfigure(request)?;
+
+        let mut restart = Vec::new();
+        for name in changed {
+            if name == "RMRFD_SPOOL_DIRS" {
+                warn!("{} changed, rmrf directories are fixed until a restart", name);
+                restart.push(name);
+            } else if !ReconfigRequest::VARS.contains(&name.as_str()) {
+                warn!("{} changed, needs a restart", name);
+                restart.push(name);
+            } else if lookup(&vars, &name).is_none() {
+                warn!(
+                    "{} removed, the current value is kept until a restart",
+                    name
+                );
+                restart.push(name);
+            }
+        }
+        self.vars = vars;
+        info!("configuration {:?} reloaded", self.path.escaped());
+        Ok(restart)
+    }
+}
+
+/// Returns the value of 'name' in 'vars', from the environment when it is not set there.
+fn lookup(vars: &Vars, name: &str) -> Option<OsString> {
+    vars.get(name).cloned().or_else(|| env::var_os(name))
+}
+
+/// Returns the sorted names of the variables whose values differ between 'old' and 'new'.
+fn changed(old: &Vars, new: &Vars) -> Vec<String> {
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|name| lookup(old, name) != lookup(new, name))
+        .cloned()
+        .collect()
+}
+
+/// Reads the variables set in the file 'path'.
+fn read(path: &Path) -> io::Result<Vars> {
+    parse(&fs::read(path)?).map_err(|line| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?}: line {} is not NAME=VALUE", path.escaped(), line),
+        )
+    })
+}
+
+/// Parses the lines of a configuration file, returns the number of the first malformed line
+/// as error. Whitespace around names and values is ignored, values may be paths with any
+/// bytes.
+fn parse(text: &[u8]) -> Result<Vars, usize> {
+    let mut vars = Vars::new();
+    for (number, line) in text.split(|&byte| byte == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let equals = line
+            .iter()
+            .position(|&byte| byte == b'=')
+            .ok_or(number + 1)?;
+        let name = std::str::from_utf8(&line[..equals])
+            .ok()
+            .map(str::trim_ascii)
+            .filter(|name| !name.is_empty())
+            .ok_or(number + 1)?;
+        if !name.starts_with("RMRFD_") {
+            warn!("configuration: {} is not a RMRFD_* variable, ignored", name);
+            continue;
+        }
+        let value = OsStr::from_bytes(line[equals + 1..].trim_ascii()).to_os_string();
+        vars.insert(String::from(name), value);
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload() {
+        let vars = parse(
+            b"# rmrfd\n\
+              RMRFD_MIN_BLOCKS=128\n\
+              \n  RMRFD_SPOOL_DIRS = /var/spool/rmrf:/tmp/rmrf \n\
+              HOME=/root\n",
+        )
+        .unwrap();
+        assert_eq!(vars.len(), 2);
+        assert_eq!(vars["RMRFD_MIN_BLOCKS"], "128");
+        assert_eq!(vars["RMRFD_SPOOL_DIRS"], "/var/spool/rmrf:/tmp/rmrf");
+        assert_eq!(parse(b"RMRFD_MIN_BLOCKS=1\nRMRFD_ARM\n"), Err(2));
+
+        let root = std::env::temp_dir().join(format!("rmrfd-config-{}", std::process::id()));
+        fs::create_dir_all(root.join(".rmrf")).unwrap();
+        let path = root.join("rmrfd.conf");
+        fs::write(&path, "RMRFD_MIN_BLOCKS=128\nRMRFD_REPORT_SECS=60\n").unwrap();
+        let mut config = ConfigFile::load(&path).unwrap();
+        assert_eq!(config.var("RMRFD_MIN_BLOCKS").unwrap(), "128");
+        let rmrfd = librmrfd::RmrfdBuilder::from_vars(|name| config.var(name))
+            .unwrap()
+            .with_startup_scan(false)
+            .add_dir(root.join(".rmrf").as_os_str())
+            .unwrap()
+            .start()
+            .unwrap();
+
+        fs::write(
+            &path,
+            "RMRFD_MIN_BLOCKS=256\nRMRFD_STAT_THREADS=2\nRMRFD_THREADS=4\n\
+             RMRFD_SPOOL_DIRS=/tmp/rmrf\n",
+        )
+        .unwrap();
+        assert_eq!(config.reload(&rmrfd).unwrap(), [
+            "RMRFD_REPORT_SECS",
+            "RMRFD_SPOOL_DIRS",
+            "RMRFD_THREADS"
+        ]);
+        assert_eq!(config.var("RMRFD_STAT_THREADS").unwrap(), "2");
+
+        fs::write(&path, "RMRFD_STAT_THREADS=0\n").unwrap();
+        assert!(config.reload(&rmrfd).is_err());
+        assert_eq!(config.var("RMRFD_STAT_THREADS").unwrap(), "2");
+
+        drop(rmrfd);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+//! The control socket. Every connection is a session of the protocol in
+//! librmrfd::protocol, served by a thread of its own. Clients are identified by their peer
+//! credentials, the socket itself is open for every user. Privileged requests are checked
+//! with authorize().
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufReader};
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::protocol::{Request, Response};
+use librmrfd::{JobHandle, PathEscape, Rmrfd, RmrfdError};
+
+use crate::authorize::{authorize, Action, Peer};
+
+/// How often a SYNC waiting for a percentage of the space checks the progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle of the thread accepting connections. Dropping it stops accepting and removes the
+/// socket, sessions already running are served to their end.
+pub(crate) struct ControlSocket {
+    path:    PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ControlSocket {
+    /// Creates the socket at 'path' and starts accepting connections for 'rmrfd'. A socket
+    /// left over from a previous daemon is replaced, another daemon running on the same rmrf
+    /// directories was refused by their locks already. With 'polkit' users other than root
+    /// may be authorized for privileged requests by polkit.
+    pub(crate) fn start(
+        path: &Path,
+        rmrfd: Arc<Rmrfd>,
+        polkit: bool,
+    ) -> io::Result<ControlSocket> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+        info!("control socket {:?}", path);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopping = stopped.clone();
+        thread::Builder::new()
+            .name(String::from("control"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => spawn_session(stream, rmrfd.clone(), polkit),
+                        Err(err) => warn!("control socket: {}", err),
+                    }
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            stopped,
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wakes the thread blocked in accept()
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Serves the session on 'stream' in a new thread.
+fn spawn_session(stream: UnixStream, rmrfd: Arc<Rmrfd>, polkit: bool) {
+    let spawned = thread::Builder::new()
+        .name(String::from("session"))
+        .spawn(move || {
+            if let Err(err) = session(stream, &rmrfd, polkit) {
+                warn!("session: {}", err);
+            }
+        });
+    if let Err(err) = spawned {
+        error!("starting a session: {}", err);
+    }
+}
+
+/// Serves a client until it closes the session or a request failed. A directory reserved but
+/// not synced is deleted when the session ends, the client may have moved something into it.
+fn session(stream: UnixStream, rmrfd: &Rmrfd, polkit: bool) -> Result<(), RmrfdError> {
+    let peer = peer(&stream)?;
+    let mut reserved = None;
+    let result = serve(stream, rmrfd, peer, polkit, &mut reserved);
+    if let Some(dir) = reserved {
+        rmrfd.delete_dir_as(&dir, peer.uid)?;
+    }
+    result
+}
+
+/// Answers the requests of 'peer' on 'stream'.
+fn serve(
+    stream: UnixStream,
+    rmrfd: &Rmrfd,
+    peer: Peer,
+    polkit: bool,
+    reserved: &mut Option<PathBuf>,
+) -> Result<(), RmrfdError> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let answered = match Request::read(&mut reader) {
+            Ok(Some(request)) => answer(rmrfd, peer, polkit, reserved, request),
+            Ok(None) => return Ok(()),
+            Err(err) => Err(err),
+        };
+        match answered {
+            Ok(value) => Response::Ok(value).write(&mut writer)?,
+            Err(err) => {
+                Response::from(&err).write(&mut writer)?;
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Answers a single 'request' of 'peer'. 'reserved' is the directory reserved in the session
+/// and not synced yet.
+fn answer(
+    rmrfd: &Rmrfd,
+    peer: Peer,
+    polkit: bool,
+    reserved: &mut Option<PathBuf>,
+    request: Request,
+) -> Result<OsString, RmrfdError> {
+    let uid = peer.uid;
+    match request {
+        Request::Path(path) => {
+            if let Some(dir) = reserved.take() {
+                rmrfd.delete_dir_as(&dir, uid)?;
+            }
+            let dir = rmrfd.reserve(&path, uid)?;
+            let mut value = dir.clone().into_os_string();
+            value.push("/");
+            *reserved = Some(dir);
+            Ok(value)
+        }
+        Request::Sync(sync) => {
+            let dir = reserved
+                .take()
+                .ok_or_else(|| RmrfdError::Protocol(String::from("SYNC without PATH")))?;
+            let total = if sync >= 0 {
+                rmrfd.plan(&dir)?.bytes
+            } else {
+                0
+            };
+            let job = rmrfd.delete_dir_as(&dir, uid)?;
+            let bytes = match sync {
+                -1 | 0 => total,
+                percent => wait_freed(rmrfd, &job, total * percent as u64 / 100)?,
+            };
+            Ok(OsString::from((bytes / 1024).to_string()))
+        }
+        Request::Cancel(path) => {
+            let jobs: Vec<_> = rmrfd.jobs().into_iter().filter(|job| job.path() == path).collect();
+            if jobs.is_empty() {
+                return Err(RmrfdError::Io(io::Error::from_raw_os_error(libc::ESRCH)));
+            }
+            if jobs.iter().any(|job| job.uid() != Some(uid)) {
+                authorize(peer, Action::CancelOthers, polkit)?;
+            }
+            info!("cancel: {:?} by uid {}", path.escaped(), uid);
+            for job in &jobs {
+                job.cancel();
+            }
+            Ok(OsString::from(jobs.len().to_string()))
+        }
+        Request::Resume(dev) => {
+            authorize(peer, Action::Resume, polkit)?;
+            if !rmrfd.resume(dev) {
+                return Err(RmrfdError::Io(io::Error::from_raw_os_error(libc::ESRCH)));
+            }
+            Ok(OsString::from("0"))
+        }
+    }
+}
+
+/// Waits until 'job' freed 'bytes' or ended, returns the bytes freed below its directory.
+fn wait_freed(rmrfd: &Rmrfd, job: &JobHandle, bytes: u64) -> Result<u64, RmrfdError> {
+    let mut freed = 0;
+    for progress in rmrfd.progress(job, PROGRESS_INTERVAL)? {
+        freed = progress.deleted_bytes;
+        if freed >= bytes {
+            break;
+        }
+    }
+    Ok(freed)
+}
+
+/// Returns the process on the other end of 'stream'.
+#[cfg(target_os = "linux")]
+fn peer(stream: &UnixStream) -> io::Result<Peer> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: 'cred' and 'len' describe the buffer SO_PEERCRED fills in
+    if unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Peer {
+        pid: cred.pid,
+        uid: cred.uid,
+    })
+}
+
+/// Returns the process on the other end of 'stream'. Its pid is not known here, polkit won't
+/// authorize it.
+#[cfg(not(target_os = "linux"))]
+fn peer(stream: &UnixStream) -> io::Result<Peer> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: getpeereid only writes the two ids
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Peer { pid: 0, uid })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn request(stream: &mut BufReader<UnixStream>, request: Request) -> Response {
+        request.write(stream.get_mut()).unwrap();
+        Response::read(stream).unwrap()
+    }
+
+    #[test]
+    fn session() {
+        let root = std::env::temp_dir().join(format!("rmrfd-control-{}", std::process::id()));
+        fs::create_dir_all(root.join(".rmrf")).unwrap();
+        fs::create_dir_all(root.join("victim/sub")).unwrap();
+        fs::write(root.join("victim/sub/file"), vec![0; 8192]).unwrap();
+        let root = fs::canonicalize(root).unwrap();
+
+        let rmrfd = Rmrfd::build()
+            .add_dir(root.join(".rmrf").as_os_str())
+            .unwrap()
+            .start()
+            .unwrap();
+        let socket = ControlSocket::start(&root.join("sock"), Arc::new(rmrfd), false).unwrap();
+        let mut stream = BufReader::new(UnixStream::connect(root.join("sock")).unwrap());
+
+        assert_eq!(
+            request(&mut stream, Request::Sync(-1)),
+            Response::Err(libc::EPROTO)
+        );
+        let mut stream = BufReader::new(UnixStream::connect(root.join("sock")).unwrap());
+        stream.get_mut().write_all(b"DELETE /\0").unwrap();
+        assert_eq!(
+            Response::read(&mut stream).unwrap(),
+            Response::Err(libc::EPROTO)
+        );
+        let mut stream = BufReader::new(UnixStream::connect(root.join("sock")).unwrap());
+        let reserved = match request(&mut stream, Request::Path(root.join("victim"))) {
+            Response::Ok(reserved) => PathBuf::from(reserved),
+            response => panic!("unexpected {:?}", response),
+        };
+        assert!(reserved.starts_with(root.join(".rmrf")));
+        fs::rename(root.join("victim"), reserved.join("victim")).unwrap();
+        assert_eq!(
+            request(&mut stream, Request::Sync(0)),
+            Response::Ok(OsString::from("8"))
+        );
+        assert_eq!(
+            request(&mut stream, Request::Cancel(root.join("nothing"))),
+            Response::Err(libc::ESRCH)
+        );
+        let mut stream = BufReader::new(UnixStream::connect(root.join("sock")).unwrap());
+        assert_eq!(
+            request(&mut stream, Request::Resume(1)),
+            Response::Err(libc::ESRCH)
+        );
+
+        drop(socket);
+        assert!(!root.join("sock").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
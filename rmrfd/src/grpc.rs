@@ -0,0 +1,292 @@
+//! The gRPC interface, see proto/rmrfd.proto. It is served by a tokio runtime in threads of
+//! its own, the requests go to the same Rmrfd as those of the control socket. Clients are
+//! not authenticated, it must only be reachable by those who may act as root.
+use std::ffi::OsStr;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::{JobHandle, JobState, PathEscape, Rmrfd, RmrfdError};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("rmrfd");
+}
+use proto::rmrfd_server::RmrfdServer;
+use proto::{Job, Paused, ProgressReply, StatusReply, StatusRequest, SubmitRequest, WatchRequest};
+
+/// How often WatchProgress replies when the client did not ask for an interval.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle of the thread serving the gRPC interface. Dropping it shuts the server down.
+pub(crate) struct GrpcServer {
+    addr:     SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread:   Option<thread::JoinHandle<()>>,
+}
+
+impl GrpcServer {
+    /// Listens on 'addr' and starts serving the gRPC interface for 'rmrfd'.
+    pub(crate) fn start(addr: SocketAddr, rmrfd: Arc<Rmrfd>) -> io::Result<GrpcServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("grpc-worker")
+            .enable_all()
+            .build()?;
+
+        let (shutdown, stop) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("grpc"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                let served = runtime.block_on(async move {
+                    let incoming =
+                        TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+                    tonic::transport::Server::builder()
+                        .add_service(RmrfdServer::new(Service { rmrfd }))
+                        .serve_with_incoming_shutdown(incoming, async {
+                            let _ = stop.await;
+                        })
+                        .await
+                        .map_err(io::Error::other)
+                });
+                if let Err(err) = served {
+                    error!("grpc: {}", err);
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        Ok(GrpcServer {
+            addr,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the server listens on, with the port chosen when 0 was given.
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The implementation of the service.
+struct Service {
+    rmrfd: Arc<Rmrfd>,
+}
+
+#[tonic::async_trait]
+impl proto::rmrfd_server::Rmrfd for Service {
+    type WatchProgressStream = ReceiverStream<Result<ProgressReply, Status>>;
+
+    async fn submit(&self, request: Request<SubmitRequest>) -> Result<Response<Job>, Status> {
+        let request = request.into_inner();
+        let path = Path::new(OsStr::from_bytes(&request.path));
+        info!("grpc: submit {:?} uid {:?}", path.escaped(), request.uid);
+        let job = match request.uid {
+            Some(uid) => self.rmrfd.delete_dir_as(path, uid),
+            None => self.rmrfd.delete_dir(path),
+        }
+        .map_err(status)?;
+        Ok(Response::new(job_message(&job)))
+    }
+
+    // dev_t is not 64 bit everywhere
+    #[allow(clippy::unnecessary_cast)]
+    async fn status(
+        &self,
+        _request: Request<StatusRequest>,
+    ) -> Result<Response<StatusReply>, Status> {
+        let status = self.rmrfd.status();
+        Ok(Response::new(StatusReply {
+            jobs:            self.rmrfd.jobs().iter().map(job_message).collect(),
+            dirs_queue:      status.dirs_queue as u64,
+            stat_queue:      status.stat_queue as u64,
+            delete_queue:    status.delete_queue as u64,
+            stuck_threads:   status.stuck_threads as u64,
+            files_per_sec:   status.files_per_sec,
+            bytes_per_sec:   status.bytes_per_sec,
+            paused:          self
+                .rmrfd
+                .paused()
+                .into_iter()
+                .map(|(dev, reason)| Paused {
+                    dev:    dev as u64,
+                    reason: format!("{:?}", reason).to_lowercase(),
+                })
+                .collect(),
+            fd_soft_limit:   status.fd_soft_limit,
+            fd_hard_limit:   status.fd_hard_limit,
+            fd_budget:       status.fd_budget as u64,
+            fds_used:        status.fds_used as u64,
+            memory_used:     status.memory_used,
+            memory_pressure: format!("{:?}", status.memory_pressure).to_lowercase(),
+        }))
+    }
+
+    async fn watch_progress(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchProgressStream>, Status> {
+        let request = request.into_inner();
+        let path = Path::new(OsStr::from_bytes(&request.path));
+        let job = self
+            .rmrfd
+            .jobs()
+            .into_iter()
+            .find(|job| job.path() == path)
+            .ok_or_else(|| Status::not_found(format!("no job for {:?}", path.escaped())))?;
+        let interval = match request.interval_ms {
+            0 => WATCH_INTERVAL,
+            millis => Duration::from_millis(millis.into()),
+        };
+        let reports = self.rmrfd.progress(&job, interval).map_err(status)?;
+
+        let (sender, receiver) = mpsc::channel(4);
+        // the reports come from a blocking channel, a closed stream ends them
+        tokio::task::spawn_blocking(move || {
+            for progress in reports {
+                let reply = ProgressReply {
+                    scanned:         progress.scanned,
+                    deleted_files:   progress.deleted_files,
+                    deleted_bytes:   progress.deleted_bytes,
+                    small_files:     progress.small_files,
+                    small_bytes:     progress.small_bytes,
+                    estimated_total: progress.estimated_total,
+                    state:           job_state(job.state()) as i32,
+                };
+                if sender.blocking_send(Ok(reply)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Returns the message describing 'job'.
+fn job_message(job: &JobHandle) -> Job {
+    Job {
+        path:  job.path().as_os_str().as_bytes().to_vec(),
+        uid:   job.uid(),
+        state: job_state(job.state()) as i32,
+    }
+}
+
+/// Returns the message value of 'state'.
+fn job_state(state: JobState) -> proto::JobState {
+    match state {
+        JobState::Running => proto::JobState::Running,
+        JobState::Sweeping => proto::JobState::Sweeping,
+        JobState::Done => proto::JobState::Done,
+        JobState::Cancelled => proto::JobState::Cancelled,
+        JobState::Aborted => proto::JobState::Aborted,
+    }
+}
+
+/// Returns the gRPC status for 'err'.
+fn status(err: RmrfdError) -> Status {
+    let message = err.to_string();
+    match err {
+        RmrfdError::InvalidPath(_) | RmrfdError::NotBelowRmrfDir(_) => {
+            Status::invalid_argument(message)
+        }
+        RmrfdError::PermissionDenied(_) | RmrfdError::NotAuthorized(_) => {
+            Status::permission_denied(message)
+        }
+        RmrfdError::Mountpoint(_) | RmrfdError::OverlayLayer(_) => {
+            Status::failed_precondition(message)
+        }
+        RmrfdError::Io(err) if err.kind() == io::ErrorKind::NotFound => Status::not_found(message),
+        _ => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use proto::rmrfd_client::RmrfdClient;
+
+    use super::*;
+
+    #[test]
+    fn service() {
+        let root = std::env::temp_dir().join(format!("rmrfd-grpc-{}", std::process::id()));
+        fs::create_dir_all(root.join(".rmrf/victim/sub")).unwrap();
+        fs::write(root.join(".rmrf/victim/sub/file"), vec![0; 8192]).unwrap();
+        let root = fs::canonicalize(root).unwrap();
+
+        let rmrfd = Rmrfd::build()
+            .with_startup_scan(false)
+            .add_dir(root.join(".rmrf").as_os_str())
+            .unwrap()
+            .start()
+            .unwrap();
+        let server = GrpcServer::start("127.0.0.1:0".parse().unwrap(), Arc::new(rmrfd)).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut client = RmrfdClient::connect(format!("http://{}", server.addr()))
+                .await
+                .unwrap();
+            let request = |path: &Path| SubmitRequest {
+                path: path.as_os_str().as_bytes().to_vec(),
+                uid:  None,
+            };
+
+            let err = client.submit(request(&root)).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            let job = client
+                .submit(request(&root.join(".rmrf/victim")))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(job.path, root.join(".rmrf/victim").as_os_str().as_bytes());
+
+            // the job may be done before it is watched
+            let watch = WatchRequest {
+                path:        job.path.clone(),
+                interval_ms: 10,
+            };
+            match client.watch_progress(watch).await {
+                Ok(stream) => {
+                    let mut stream = stream.into_inner();
+                    let mut last = None;
+                    while let Some(reply) = stream.message().await.unwrap() {
+                        last = Some(reply);
+                    }
+                    assert_eq!(last.unwrap().state(), proto::JobState::Done);
+                }
+                Err(err) => assert_eq!(err.code(), tonic::Code::NotFound),
+            }
+            let status = client.status(StatusRequest {}).await.unwrap().into_inner();
+            assert!(status.paused.is_empty());
+            assert!(status.fd_budget > 0);
+            assert_eq!(status.memory_pressure, "normal");
+        });
+
+        drop(server);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
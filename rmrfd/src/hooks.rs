@@ -0,0 +1,322 @@
+//! Notifications of finished jobs for ticketing and alerting pipelines. The JobReport of
+//! every job which is done, cancelled or aborted is passed to the commands given with --hook
+//! and posted to the URLs given with --webhook. They run one after another in a thread of
+//! their own, slow receivers never hold up the deletion.
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::{JobReport, JobState, PathEscape};
+
+/// How long a hook may take before it is killed or the request is abandoned.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a running hook command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many notifications may wait for the hooks, more are dropped with a warning.
+const BACKLOG: usize = 256;
+
+/// What is passed to the hooks.
+struct Notification {
+    path:  PathBuf,
+    state: JobState,
+    json:  String,
+}
+
+/// What the thread running the hooks takes over when it is started.
+struct Pending {
+    receiver: Receiver<Option<Notification>>,
+    commands: Vec<OsString>,
+    webhooks: Vec<String>,
+}
+
+/// Handle of the thread running the hooks. Dropping it runs the hooks for the notifications
+/// still waiting and stops the thread.
+pub(crate) struct Hooks {
+    sender:  Sender<Option<Notification>>,
+    pending: Option<Pending>,
+    thread:  Option<thread::JoinHandle<()>>,
+}
+
+impl Hooks {
+    /// Creates the hooks running the shell 'commands' and posting to the 'webhooks' URLs for
+    /// every notification. Notifications wait until start() is called.
+    pub(crate) fn new(commands: Vec<OsString>, webhooks: Vec<String>) -> Hooks {
+        let (sender, receiver) = crossbeam_channel::bounded::<Option<Notification>>(BACKLOG);
+        Hooks {
+            sender,
+            pending: Some(Pending {
+                receiver,
+                commands,
+                webhooks,
+            }),
+            thread: None,
+        }
+    }
+
+    /// Starts the thread running the hooks. The thread inherits the capabilities of the
+    /// calling thread, start it after RmrfdBuilder::start() dropped them. Does nothing when
+    /// already started.
+    pub(crate) fn start(&mut self) -> io::Result<()> {
+        let Some(Pending {
+            receiver,
+            commands,
+            webhooks,
+        }) = self.pending.take()
+        else {
+            return Ok(());
+        };
+        let thread = thread::Builder::new()
+            .name(String::from("hooks"))
+            .spawn(move || {
+                debug!("thread started: {}", thread::current().name().unwrap());
+                while let Ok(Some(notification)) = receiver.recv() {
+                    for command in &commands {
+                        match run_command(command, &notification) {
+                            Ok(status) if status.success() => {}
+                            Ok(status) => warn!("hook {:?}: {}", command, status),
+                            Err(err) => warn!("hook {:?}: {}", command, err),
+                        }
+                    }
+                    for url in &webhooks {
+                        if let Err(err) = post(url, &notification.json) {
+                            warn!("webhook {}: {}", url, err);
+                        }
+                    }
+                    debug!("notified: {:?}", notification.path.escaped());
+                }
+                debug!("thread stopped: {}", thread::current().name().unwrap());
+            })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Returns the callback for RmrfdBuilder::with_report_callback() queueing the reports
+    /// for the hooks.
+    pub(crate) fn notifier(&self) -> impl Fn(&JobReport) + Send + Sync + 'static {
+        let sender = self.sender.clone();
+        move |report: &JobReport| {
+            let notification = Notification {
+                path:  report.path.clone(),
+                state: report.state,
+                json:  report.to_json(),
+            };
+            if let Err(TrySendError::Full(_)) = sender.try_send(Some(notification)) {
+                warn!(
+                    "hooks backlog full, not notified: {:?}",
+                    report.path.escaped()
+                );
+            }
+        }
+    }
+}
+
+impl Drop for Hooks {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.sender.send(None);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs the shell 'command' with the report of 'notification' on its stdin and the path and
+/// state of the job in RMRFD_JOB_PATH and RMRFD_JOB_STATE. It is killed after HOOK_TIMEOUT.
+fn run_command(command: &OsStr, notification: &Notification) -> io::Result<ExitStatus> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("RMRFD_JOB_PATH", &notification.path)
+        .env(
+            "RMRFD_JOB_STATE",
+            format!("{:?}", notification.state).to_lowercase(),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    thread::scope(|scope| {
+        // a command not reading the report must not block the thread
+        scope.spawn(move || {
+            let _ = stdin.write_all(notification.json.as_bytes());
+        });
+        let deadline = Instant::now() + HOOK_TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "killed, timed out"));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    })
+}
+
+/// Posts 'json' to 'url'. Answers other than 2xx are errors.
+#[cfg(feature = "webhook")]
+fn post(url: &str, json: &str) -> io::Result<()> {
+    let agent = ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .timeout_global(Some(HOOK_TIMEOUT))
+            .build(),
+    );
+    agent
+        .post(url)
+        .content_type("application/json")
+        .send(json)
+        .map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webhook"))]
+fn post(_url: &str, _json: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "rmrfd was built without the webhook feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use std::path::Path;
+
+    use librmrfd::{ErrorSummary, Rmrfd};
+
+    use super::*;
+
+    fn report() -> JobReport {
+        JobReport {
+            path:          PathBuf::from("/tmp/rmrf/job"),
+            uid:           Some(1000),
+            state:         JobState::Aborted,
+            duration:      Duration::from_secs(1),
+            deleted_files: 1,
+            deleted_bytes: 4096,
+            removed_dirs:  0,
+            small_files:   0,
+            small_bytes:   0,
+            space:         None,
+            errors:        ErrorSummary::default(),
+            skipped:       Vec::new(),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let dir = std::env::temp_dir().join(format!("rmrfd-hooks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut hooks = Hooks::new(
+            vec![
+                OsString::from(format!(
+                    "cat >{}/\"$RMRFD_JOB_STATE\"",
+                    dir.to_str().unwrap()
+                )),
+                OsString::from("exit 1"),
+            ],
+            Vec::new(),
+        );
+        hooks.notifier()(&report());
+        hooks.start().unwrap();
+        drop(hooks);
+
+        assert_eq!(
+            fs::read_to_string(dir.join("aborted")).unwrap(),
+            report().to_json()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "webhook")]
+    #[test]
+    fn webhook() {
+        use std::io::{BufRead, BufReader, Read};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/notify", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            String::from_utf8(body).unwrap()
+        });
+
+        let mut hooks = Hooks::new(Vec::new(), vec![url]);
+        hooks.start().unwrap();
+        hooks.notifier()(&report());
+        drop(hooks);
+        assert_eq!(server.join().unwrap(), report().to_json());
+    }
+
+    /// Returns the effective capabilities from the /proc 'status' file of a thread.
+    fn capabilities(status: &Path) -> Option<String> {
+        fs::read_to_string(status)
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .map(|capabilities| capabilities.trim().to_string())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn capabilities_dropped() {
+        let dir = std::env::temp_dir().join(format!("rmrfd-hooks-caps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // capabilities are per thread, don't drop them in the test runner's threads
+        let dropped = dir.clone();
+        thread::spawn(move || {
+            let mut hooks = Hooks::new(Vec::new(), Vec::new());
+            let _rmrfd = Rmrfd::build()
+                .with_drop_capabilities(true)
+                .with_report_callback(hooks.notifier())
+                .add_dir(dropped.as_os_str())
+                .unwrap()
+                .with_startup_scan(false)
+                .start()
+                .unwrap();
+            hooks.start().unwrap();
+
+            let held = capabilities(Path::new("/proc/thread-self/status")).unwrap();
+            // The thread names itself once it runs. Other tests may run hooks threads of
+            // their own.
+            assert!((0..100).any(|_| {
+                thread::sleep(Duration::from_millis(10));
+                fs::read_dir("/proc/self/task")
+                    .unwrap()
+                    .filter_map(|task| Some(task.ok()?.path()))
+                    .filter(|task| {
+                        fs::read_to_string(task.join("comm"))
+                            .is_ok_and(|comm| comm.trim() == "hooks")
+                    })
+                    .any(|task| capabilities(&task.join("status")).as_ref() == Some(&held))
+            }));
+        })
+        .join()
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+//! The rmrfd daemon. Deletes what is moved into its rmrf directories and serves the clients
+//! of its control socket, see the README.
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use librmrfd::protocol::DEFAULT_SOCKET;
+use librmrfd::{RmrfdBuilder, RmrfdError};
+
+mod authorize;
+
+mod configfile;
+use configfile::ConfigFile;
+
+mod control;
+use control::ControlSocket;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+
+mod hooks;
+use hooks::Hooks;
+
+mod signals;
+use signals::Signals;
+
+const USAGE: &str = "usage: rmrfd [OPTION]... [DIR...]
+
+Deletes everything moved into the rmrf directories 'DIR' and those in RMRFD_SPOOL_DIRS, see
+librmrfd's RmrfdBuilder::from_env() for the other RMRFD_* environment variables.
+
+  --socket PATH  the control socket, RMRFD_SOCKET or /run/rmrfd.sock by default
+  --config FILE  read RMRFD_* variables from 'FILE' as 'NAME=VALUE' lines, SIGHUP reloads
+                 it and applies what can be changed while running
+  --grpc ADDR    serve the gRPC interface on 'ADDR' like 127.0.0.1:7878, unauthenticated,
+                 only when built with the grpc feature
+  --hook CMD     run the shell command 'CMD' for every finished job, with the job report as
+                 JSON on stdin and RMRFD_JOB_PATH and RMRFD_JOB_STATE set
+  --webhook URL  post the job report of every finished job to 'URL', only when built with
+                 the webhook feature
+  --reap DIR AGE delete what is in the rmrf directory 'DIR' only once it was not modified
+                 for 'AGE', in seconds or with a suffix 's', 'm', 'h' or 'd'
+  --user-dirs    give every user a directory of their own in the rmrf directories
+  --polkit       let polkit authorize users other than root for privileged requests
+  --arm          really delete, otherwise only what would be deleted is logged
+  --help         show this help";
+
+/// How often the directories given with --reap are checked at most.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The settings from the command line.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Config {
+    socket:      Option<PathBuf>,
+    config_file: Option<PathBuf>,
+    grpc:        Option<SocketAddr>,
+    hooks:       Vec<OsString>,
+    webhooks:    Vec<String>,
+    dirs:        Vec<OsString>,
+    reap:        Vec<(OsString, Duration)>,
+    user_dirs:   bool,
+    polkit:      bool,
+    arm:         bool,
+    help:        bool,
+}
+
+impl Config {
+    /// Parses the command line 'args' without the program name.
+    fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--socket") => {
+                    config.socket = Some(args.next().ok_or("--socket needs a path")?.into())
+                }
+                Some("--config") => {
+                    config.config_file = Some(args.next().ok_or("--config needs a file")?.into())
+                }
+                Some("--grpc") => {
+                    config.grpc = Some(
+                        args.next()
+                            .and_then(|addr| addr.to_str()?.parse().ok())
+                            .ok_or("--grpc needs an address like 127.0.0.1:7878")?,
+                    )
+                }
+                Some("--hook") => config.hooks.push(args.next().ok_or("--hook needs a command")?),
+                Some("--webhook") => config.webhooks.push(
+                    args.next()
+                        .and_then(|url| url.into_string().ok())
+                        .ok_or("--webhook needs a URL")?,
+                ),
+                Some("--reap") => {
+                    let dir = args.next().ok_or("--reap needs a directory")?;
+                    let age = args
+                        .next()
+                        .and_then(|age| parse_age(age.to_str()?))
+                        .ok_or("--reap needs an age like 7d")?;
+                    config.reap.push((dir, age));
+                }
+                Some("--user-dirs") => config.user_dirs = true,
+                Some("--polkit") => config.polkit = true,
+                Some("--arm") => config.arm = true,
+                Some("--help") => config.help = true,
+                Some(option) if option.starts_with("--") => {
+                    return Err(format!("unknown option {}", option));
+                }
+                _ => config.dirs.push(arg),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Parses an age like '90', '90s', '15m', '12h' or '7d'.
+fn parse_age(age: &str) -> Option<Duration> {
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(suffix) => age.split_at(suffix),
+        None => (age, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.parse::<u64>().ok()?.checked_mul(unit)?))
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let config = match Config::parse(env::args_os().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("rmrfd: {}\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if config.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    match run(config) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Starts the daemon and serves the control socket until SIGINT or SIGTERM. The directories
+/// given with --reap are checked every REAP_INTERVAL or their shortest age when less.
+fn run(config: Config) -> Result<(), RmrfdError> {
+    if cfg!(not(feature = "grpc")) && config.grpc.is_some() {
+        return Err(io::Error::other("--grpc: rmrfd was built without the grpc feature").into());
+    }
+    if cfg!(not(feature = "webhook")) && !config.webhooks.is_empty() {
+        return Err(
+            io::Error::other("--webhook: rmrfd was built without the webhook feature").into(),
+        );
+    }
+    // before any thread is started, they inherit the blocked signals
+    let signals = Signals::block(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP])?;
+
+    let mut hooks = match config.hooks.is_empty() && config.webhooks.is_empty() {
+        true => None,
+        false => Some(Hooks::new(config.hooks, config.webhooks)),
+    };
+    let mut config_file = config.config_file.as_deref().map(ConfigFile::load).transpose()?;
+    let mut builder = match &config_file {
+        Some(file) => RmrfdBuilder::from_vars(|name| file.var(name))?,
+        None => RmrfdBuilder::from_env()?,
+    }
+    .with_user_dirs(config.user_dirs);
+    if let Some(hooks) = &hooks {
+        builder = builder.with_report_callback(hooks.notifier());
+    }
+    for dir in &config.dirs {
+        builder = builder.add_dir(dir)?;
+    }
+    for (dir, age) in &config.reap {
+        builder = builder.add_reap_dir(dir, *age)?;
+    }
+    let rmrfd = Arc::new(builder.arm(config.arm).start()?);
+    // after start() dropped the capabilities, the thread inherits what is left
+    if let Some(hooks) = &mut hooks {
+        hooks.start()?;
+    }
+
+    let socket = config
+        .socket
+        .or_else(|| {
+            match &config_file {
+                Some(file) => file.var("RMRFD_SOCKET"),
+                None => env::var_os("RMRFD_SOCKET"),
+            }
+            .map(PathBuf::from)
+        })
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SOCKET));
+    let _control = ControlSocket::start(&socket, rmrfd.clone(), config.polkit)?;
+    #[cfg(feature = "grpc")]
+    let _grpc = match config.grpc {
+        Some(addr) => {
+            let server = grpc::GrpcServer::start(addr, rmrfd.clone())?;
+            info!("grpc on {}", server.addr());
+            Some(server)
+        }
+        None => None,
+    };
+
+    let reap_interval = config
+        .reap
+        .iter()
+        .map(|(_, age)| *age)
+        .min()
+        .map(|age| age.clamp(Duration::from_secs(1), REAP_INTERVAL));
+
+    loop {
+        let signal = match reap_interval {
+            Some(interval) => signals.wait_timeout(interval)?,
+            None => Some(signals.wait()?),
+        };
+        match signal {
+            None => {
+                rmrfd.reap();
+            }
+            Some(libc::SIGHUP) => match &mut config_file {
+                Some(file) => {
+                    if let Err(err) = file.reload(&rmrfd) {
+                        error!("reloading the configuration: {}", err);
+                    }
+                }
+                None => info!("SIGHUP ignored, no configuration file given with --config"),
+            },
+            Some(signal) => {
+                info!("signal {}, shutting down", signal);
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Config::parse(args(&["--socket", "/tmp/sock", "--arm", "/a", "/b"])).unwrap(),
+            Config {
+                socket: Some(PathBuf::from("/tmp/sock")),
+                dirs: args(&["/a", "/b"]),
+                arm: true,
+                ..Config::default()
+            }
+        );
+        assert!(Config::parse(args(&["--socket"])).is_err());
+        assert_eq!(
+            Config::parse(args(&["--config", "/etc/rmrfd.conf"])).unwrap().config_file,
+            Some(PathBuf::from("/etc/rmrfd.conf"))
+        );
+        assert_eq!(
+            Config::parse(args(&["--grpc", "[::1]:7878"])).unwrap().grpc,
+            Some("[::1]:7878".parse().unwrap())
+        );
+        assert!(Config::parse(args(&["--grpc", "localhost"])).is_err());
+        let config =
+            Config::parse(args(&["--hook", "logger", "--webhook", "https://x/y"])).unwrap();
+        assert_eq!(config.hooks, args(&["logger"]));
+        assert_eq!(config.webhooks, ["https://x/y"]);
+        assert!(Config::parse(args(&["--webhook"])).is_err());
+        assert!(Config::parse(args(&["--frobnicate"])).is_err());
+        assert_eq!(
+            Config::parse(args(&["--reap", "/tmp", "7d"])).unwrap().reap,
+            [(OsString::from("/tmp"), Duration::from_secs(7 * 24 * 60 * 60))]
+        );
+        assert!(Config::parse(args(&["--reap", "/tmp", "7y"])).is_err());
+        assert_eq!(parse_age("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_age("15m"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_age("m"), None);
+    }
+}
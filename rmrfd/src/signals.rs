@@ -0,0 +1,61 @@
+//! Signal handling. The signals the daemon reacts on are blocked in all threads and taken by
+//! the main thread with sigwait(), nothing runs in signal handler context.
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::time::Duration;
+
+/// A set of blocked signals.
+pub(crate) struct Signals(libc::sigset_t);
+
+impl Signals {
+    /// Blocks 'signals' in the calling thread and in all threads it starts afterwards.
+    pub(crate) fn block(signals: &[libc::c_int]) -> io::Result<Signals> {
+        let mut set = MaybeUninit::uninit();
+        // SAFETY: sigemptyset initializes the set
+        let mut set = unsafe {
+            libc::sigemptyset(set.as_mut_ptr());
+            set.assume_init()
+        };
+        for &signal in signals {
+            // SAFETY: 'set' is initialized
+            if unsafe { libc::sigaddset(&mut set, signal) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // SAFETY: 'set' is initialized, the old mask is not asked for
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+            0 => Ok(Signals(set)),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// Waits until one of the signals is pending and returns it.
+    pub(crate) fn wait(&self) -> io::Result<libc::c_int> {
+        let mut signal = 0;
+        // SAFETY: 'self.0' is initialized
+        match unsafe { libc::sigwait(&self.0, &mut signal) } {
+            0 => Ok(signal),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// Waits until one of the signals is pending or 'timeout' elapsed. Returns the signal or
+    /// None on timeout.
+    pub(crate) fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<libc::c_int>> {
+        let timeout = libc::timespec {
+            tv_sec:  timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        // SAFETY: 'self.0' is initialized, the siginfo is not asked for
+        match unsafe { libc::sigtimedwait(&self.0, ptr::null_mut(), &timeout) } {
+            -1 => match io::Error::last_os_error() {
+                err if matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EINTR)) => {
+                    Ok(None)
+                }
+                err => Err(err),
+            },
+            signal => Ok(Some(signal)),
+        }
+    }
+}